@@ -21,6 +21,9 @@ pub fn confirmgui(title: String, information: String) -> bool {
     ui.set_information(information.into());
     let (sender, receiver) = mpsc::channel();
     init_slots(&ui, sender);
+    if ui.run().is_err() {
+        return false;
+    }
     receiver
         .recv_timeout(std::time::Duration::from_nanos(300))
         .unwrap_or_default()
@@ -44,3 +44,33 @@ pub fn selectgui(screen: Vec<ScreenInfo>) -> SlintSelection {
         SlintSelection::Canceled
     }
 }
+
+/// Show the screens as a list, returns the index of the picked one, `None` if the user
+/// cancels.
+pub fn selectoutput(screen: Vec<ScreenInfo>) -> Option<usize> {
+    select_from_list(screen, "Select a screen to share", "select a screen")
+}
+
+/// Show the windows, and the screens before them if any, as a list, returns the index of the
+/// picked one, `None` if the user cancels.
+pub fn selectwindow(windows: Vec<ScreenInfo>) -> Option<usize> {
+    select_from_list(windows, "Select a window to share", "select a window")
+}
+
+fn select_from_list(infos: Vec<ScreenInfo>, window_title: &str, heading: &str) -> Option<usize> {
+    let ui = OutputWindow::new().unwrap();
+    ui.set_window_title(window_title.into());
+    ui.set_heading(heading.into());
+    ui.set_infos(Rc::new(VecModel::from(infos)).into());
+    let (sender, receiver) = mpsc::channel();
+    let global = OutputSlots::get(&ui);
+    global.on_selectOutput(move |index| {
+        let _ = sender.send(index as usize);
+        let _ = slint::quit_event_loop();
+    });
+    global.on_cancel(|| {
+        let _ = slint::quit_event_loop();
+    });
+    ui.run().unwrap();
+    receiver.try_recv().ok()
+}
@@ -43,7 +43,12 @@ impl AccessBackend {
         _body: String,
         _options: AccessOption,
     ) -> fdo::Result<PortalResponse<HashMap<String, OwnedValue>>> {
-        if accessdialog::confirmgui(title, sub_title) {
+        // The dialog blocks until it is answered, so it keeps off the executor
+        let confirmed =
+            tokio::task::spawn_blocking(move || accessdialog::confirmgui(title, sub_title))
+                .await
+                .unwrap_or_default();
+        if confirmed {
             Ok(PortalResponse::Success(HashMap::new()))
         } else {
             Ok(PortalResponse::Cancelled)
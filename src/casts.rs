@@ -0,0 +1,235 @@
+use zbus::{interface, message::Header};
+
+use crate::pipewirethread::ScreencastThread;
+use crate::remotedesktop::REMOTE_SESSIONS;
+use crate::screencast::CAST_SESSIONS;
+use crate::session::{SESSIONS, close_session};
+
+/// The casts going on, and a way out of one the app does not stop, for a tool run by the
+/// user like `busctl`.
+pub struct CastsBackend;
+
+/// Check that the sender of `header` may act on the session at `session_handle`, which must be
+/// owned by `app_id`, see [`CastsBackend::stop`]. Returns whether there is such a session.
+///
+/// Only the client that created the session can, as its unique bus name tells. The app id is
+/// no proof, [`CastsBackend::list`] tells it to anyone.
+async fn check_owner(
+    header: &Header<'_>,
+    session_handle: &str,
+    app_id: &str,
+) -> zbus::fdo::Result<bool> {
+    let session = SESSIONS
+        .read()
+        .await
+        .iter()
+        .find(|session| session.handle_path.as_str() == session_handle)
+        .map(|session| (session.app_id.clone(), session.sender.clone()));
+    let Some((owner, creator)) = session else {
+        return Ok(false);
+    };
+    let sender = header.sender().map(|sender| sender.as_str());
+    if sender.is_none() || sender != creator.as_deref() {
+        return Err(zbus::fdo::Error::AccessDenied(format!(
+            "Only the client that created {session_handle} can control its casts"
+        )));
+    }
+    if owner != app_id {
+        return Err(zbus::fdo::Error::AccessDenied(format!(
+            "The session {session_handle} is not one of {app_id}"
+        )));
+    }
+    Ok(true)
+}
+
+/// Pause or resume every cast of the session at `session_handle`, see
+/// [`ScreencastThread::pause`].
+async fn set_paused(session_handle: &str, paused: bool) {
+    let toggle = |thread: &ScreencastThread| {
+        if paused {
+            thread.pause();
+        } else {
+            thread.resume();
+        }
+    };
+    CAST_SESSIONS
+        .lock()
+        .await
+        .iter()
+        .filter(|session| session.0 == session_handle)
+        .for_each(|session| toggle(&session.1));
+    REMOTE_SESSIONS
+        .lock()
+        .await
+        .iter()
+        .filter(|session| session.0 == session_handle)
+        .for_each(|session| toggle(&session.1));
+}
+
+#[interface(name = "org.freedesktop.impl.portal.desktop.luminous.Casts")]
+impl CastsBackend {
+    /// The sessions casting, as `(session_handle, app_id, stream_ids)`, whether screen cast
+    /// or remote desktop ones.
+    async fn list(&self) -> Vec<(String, String, Vec<String>)> {
+        let mut casts: Vec<(String, String, Vec<String>)> = Vec::new();
+        let streams: Vec<(String, String)> = {
+            let cast_sessions = CAST_SESSIONS.lock().await;
+            let remote_sessions = REMOTE_SESSIONS.lock().await;
+            cast_sessions
+                .iter()
+                .map(|session| (session.0.clone(), session.2.id.clone()))
+                .chain(
+                    remote_sessions
+                        .iter()
+                        .map(|session| (session.0.clone(), session.3.id.clone())),
+                )
+                .map(|(path, id)| (path, id.unwrap_or_default()))
+                .collect()
+        };
+        let sessions = SESSIONS.read().await;
+        for (path, id) in streams {
+            if let Some(cast) = casts.iter_mut().find(|cast| cast.0 == path) {
+                cast.2.push(id);
+                continue;
+            }
+            let app_id = sessions
+                .iter()
+                .find(|session| session.handle_path.as_str() == path)
+                .map(|session| session.app_id.clone())
+                .unwrap_or_default();
+            casts.push((path, app_id, vec![id]));
+        }
+        casts
+    }
+
+    /// Stop every cast of the session at `session_handle` and close it, as if the app did.
+    ///
+    /// `app_id` must be the one of the app that owns the session, as [`CastsBackend::list`]
+    /// tells it, and the caller must be the client that created the session, see
+    /// [`check_owner`]. Returns whether there was such a session.
+    async fn stop(
+        &self,
+        session_handle: String,
+        app_id: String,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<bool> {
+        if !check_owner(&header, &session_handle, &app_id).await? {
+            return Ok(false);
+        }
+        tracing::info!("the cast of {app_id} at {session_handle} is stopped over D-Bus");
+        close_session(&session_handle, "The cast was stopped by the user").await;
+        Ok(true)
+    }
+
+    /// Stop capturing the casts of the session at `session_handle`, like a muted share. Their
+    /// nodes stay, so the consumers stay connected, and pausing twice does nothing.
+    ///
+    /// The caller is checked like for [`CastsBackend::stop`]. Returns whether there was such
+    /// a session.
+    async fn pause(
+        &self,
+        session_handle: String,
+        app_id: String,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<bool> {
+        if !check_owner(&header, &session_handle, &app_id).await? {
+            return Ok(false);
+        }
+        tracing::info!("the cast of {app_id} at {session_handle} is paused over D-Bus");
+        set_paused(&session_handle, true).await;
+        Ok(true)
+    }
+
+    /// Capture the casts of the session at `session_handle` again, see
+    /// [`CastsBackend::pause`].
+    async fn resume(
+        &self,
+        session_handle: String,
+        app_id: String,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<bool> {
+        if !check_owner(&header, &session_handle, &app_id).await? {
+            return Ok(false);
+        }
+        tracing::info!("the cast of {app_id} at {session_handle} is resumed over D-Bus");
+        set_paused(&session_handle, false).await;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use futures::TryStreamExt;
+    use zbus::message::Type;
+    use zbus::zvariant::ObjectPath;
+
+    use super::*;
+    use crate::screencast::StreamProperties;
+    use crate::session::{Session, SessionType};
+    use crate::testing::{lock_globals, serve};
+
+    const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+    const SESSION_PATH: &str = "/org/freedesktop/portal/desktop/session/1_1/luminous_owned";
+    const APP_ID: &str = "org.luminous.Test";
+    /// The client that created the session.
+    const CREATOR: &str = ":1.7";
+
+    /// Call `method` of the casts as the client `sender`, which the bus would vouch for.
+    async fn call_as(client: &zbus::Connection, sender: &str, method: &str) -> zbus::Result<bool> {
+        let mut messages = zbus::MessageStream::from(client);
+        let call = zbus::Message::method_call(PORTAL_PATH, method)?
+            .interface("org.freedesktop.impl.portal.desktop.luminous.Casts")?
+            .sender(sender)?
+            .build(&(SESSION_PATH, APP_ID))?;
+        client.send(&call).await?;
+        let serial = call.primary_header().serial_num();
+        loop {
+            let reply = messages.try_next().await?.expect("no reply");
+            if reply.header().reply_serial() != Some(serial) {
+                continue;
+            }
+            if reply.message_type() == Type::Error {
+                return Err(reply.into());
+            }
+            return reply.body().deserialize();
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_creator_of_a_session_controls_its_casts() {
+        let _globals = lock_globals().await;
+        let mut session = Session::new(
+            ObjectPath::from_static_str_unchecked(SESSION_PATH),
+            "luminous_owned".to_string(),
+            SessionType::ScreenCast,
+            APP_ID.to_string(),
+        );
+        session.sender = Some(CREATOR.to_string());
+        SESSIONS.write().await.push(session);
+        let stopped = Arc::new(AtomicBool::new(false));
+        CAST_SESSIONS.lock().await.push((
+            SESSION_PATH.to_string(),
+            ScreencastThread::idle(0, stopped.clone()),
+            StreamProperties::default(),
+        ));
+        let (_server, client) = serve(PORTAL_PATH, CastsBackend).await;
+
+        // Knowing the app id, as the list tells it, is not enough
+        for method in ["Pause", "Resume", "Stop"] {
+            let Err(zbus::Error::MethodError(name, ..)) = call_as(&client, ":1.8", method).await
+            else {
+                panic!("{method} is allowed to another client");
+            };
+            assert_eq!(name.as_str(), "org.freedesktop.DBus.Error.AccessDenied");
+        }
+        assert!(!stopped.load(Ordering::Relaxed));
+
+        assert!(call_as(&client, CREATOR, "Pause").await.unwrap());
+        assert!(CAST_SESSIONS.lock().await[0].1.is_paused());
+        assert!(call_as(&client, CREATOR, "Resume").await.unwrap());
+        assert!(!CAST_SESSIONS.lock().await[0].1.is_paused());
+    }
+}
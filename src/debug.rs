@@ -0,0 +1,28 @@
+use zbus::interface;
+
+use crate::screencast::CAST_SESSIONS;
+
+/// Set to serve [`DebugBackend`], it is not there otherwise.
+pub const DEBUG_ENV: &str = "LUMINOUS_DEBUG";
+
+/// Introspection of the portal state, for `busctl`.
+pub struct DebugBackend;
+
+#[interface(name = "org.freedesktop.impl.portal.desktop.luminous.Debug")]
+impl DebugBackend {
+    /// The streams being cast, as `(session_handle, node_id, output_name)`.
+    #[zbus(property)]
+    async fn cast_sessions(&self) -> Vec<(String, u32, String)> {
+        let sessions = CAST_SESSIONS.lock().await;
+        sessions
+            .iter()
+            .map(|session| {
+                (
+                    session.0.clone(),
+                    session.1.node_id(),
+                    session.2.id.clone().unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+}
@@ -1,20 +1,30 @@
 mod access;
+mod casts;
+mod debug;
 mod remotedesktop;
 mod request;
+mod restore;
 mod screencast;
 mod screenshot;
 mod session;
 mod settings;
+#[cfg(test)]
+mod testing;
 mod utils;
+mod wallpaper;
 
 use access::AccessBackend;
-use remotedesktop::RemoteDesktopBackend;
-use screencast::ScreenCastBackend;
+use casts::CastsBackend;
+use debug::{DEBUG_ENV, DebugBackend};
+use remotedesktop::{REMOTE_SESSIONS, RemoteDesktopBackend};
+use restore::RestoreTokensBackend;
+use screencast::{CAST_SESSIONS, ScreenCastBackend, WaylandCasts};
 use screenshot::ScreenShotBackend;
+use session::{SESSIONS, close_idle_sessions, emit_closed};
 use settings::{AccentColor, SETTING_CONFIG, SettingsBackend, SettingsConfig};
+use wallpaper::WallpaperBackend;
 
 use std::collections::HashMap;
-use std::future::pending;
 use zbus::{Connection, connection, object_server::SignalEmitter, zvariant};
 
 use futures::{
@@ -23,6 +33,8 @@ use futures::{
 };
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use std::path::Path;
+use std::time::Duration;
+use tokio::signal::unix::SignalKind;
 
 mod pipewirethread;
 use std::sync::OnceLock;
@@ -31,6 +43,9 @@ const PORTAL_RESPONSE_SUCCESS: u32 = 0;
 const PORTAL_RESPONSE_CANCELLED: u32 = 1;
 const PORTAL_RESPONSE_OTHER: u32 = 2;
 
+/// How long the casts get to stop once the portal is told to exit
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
 static SESSION: OnceLock<zbus::Connection> = OnceLock::new();
 
 async fn get_connection() -> zbus::Connection {
@@ -51,6 +66,8 @@ enum PortalResponse<T: zvariant::Type + serde::Serialize> {
     Success(T),
     Cancelled,
     Other,
+    /// Like [`PortalResponse::Other`], with the cause under `error`, for frontends to show
+    Error(String),
 }
 
 impl<T: zvariant::Type + serde::Serialize> serde::Serialize for PortalResponse<T> {
@@ -67,6 +84,11 @@ impl<T: zvariant::Type + serde::Serialize> serde::Serialize for PortalResponse<T
                 HashMap::<String, zvariant::Value>::new(),
             )
                 .serialize(serializer),
+            Self::Error(message) => (
+                PORTAL_RESPONSE_OTHER,
+                HashMap::from([("error", zvariant::Value::from(message.as_str()))]),
+            )
+                .serialize(serializer),
         }
     }
 }
@@ -109,23 +131,29 @@ async fn async_watch<P: AsRef<Path>>(path: P) -> notify::Result<()> {
                 ..
             }) => {
                 let mut config = SETTING_CONFIG.lock().await;
-                *config = SettingsConfig::config_from_file();
-                let _ = SettingsBackend::setting_changed(
-                    &signal_context,
-                    "org.freedesktop.appearance".to_string(),
-                    "color-scheme".to_string(),
-                    config.get_color_scheme().into(),
-                )
-                .await;
-                let _ = SettingsBackend::setting_changed(
-                    &signal_context,
-                    "org.freedesktop.appearance".to_string(),
-                    "accent-color".to_string(),
-                    AccentColor::new(config.get_accent_color())
-                        .try_into()
-                        .unwrap(),
-                )
-                .await;
+                let old_config =
+                    std::mem::replace(&mut *config, SettingsConfig::config_from_file());
+                // Editors touch the file more than once per save, only real changes are told
+                if old_config.get_color_scheme() != config.get_color_scheme() {
+                    let _ = SettingsBackend::setting_changed(
+                        &signal_context,
+                        "org.freedesktop.appearance".to_string(),
+                        "color-scheme".to_string(),
+                        config.get_color_scheme().into(),
+                    )
+                    .await;
+                }
+                if old_config.get_accent_color() != config.get_accent_color() {
+                    let _ = SettingsBackend::setting_changed(
+                        &signal_context,
+                        "org.freedesktop.appearance".to_string(),
+                        "accent-color".to_string(),
+                        AccentColor::new(config.get_accent_color())
+                            .try_into()
+                            .unwrap(),
+                    )
+                    .await;
+                }
             }
             Err(e) => println!("watch error: {:?}", e),
             _ => {}
@@ -135,23 +163,68 @@ async fn async_watch<P: AsRef<Path>>(path: P) -> notify::Result<()> {
     Ok(())
 }
 
+/// Stop every cast, so no PipeWire node outlives the portal, and wait for their threads.
+async fn shutdown(connection: &zbus::Connection) {
+    let sessions = std::mem::take(&mut *SESSIONS.write().await);
+    for session in &sessions {
+        emit_closed(
+            connection,
+            session.handle_path.as_str(),
+            "The portal is shutting down",
+        )
+        .await;
+    }
+    let cast_sessions = std::mem::take(&mut *CAST_SESSIONS.lock().await);
+    let remote_sessions = std::mem::take(&mut *REMOTE_SESSIONS.lock().await);
+    for session in &cast_sessions {
+        session.1.stop();
+    }
+    for session in &remote_sessions {
+        session.1.stop();
+        session.2.stop();
+    }
+    while cast_sessions
+        .iter()
+        .map(|session| &session.1)
+        .chain(remote_sessions.iter().map(|session| &session.1))
+        .any(|thread| !thread.is_finished())
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tracing::info!(
+        "{} casts are stopped",
+        cast_sessions.len() + remote_sessions.len()
+    );
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     unsafe { std::env::set_var("RUST_LOG", "xdg-desktop-protal-luminous=info") }
     tracing_subscriber::fmt().init();
     tracing::info!("luminous Start");
+    std::sync::LazyLock::force(&restore::RESTORE_TOKENS);
 
-    let conn = connection::Builder::session()?
+    let mut builder = connection::Builder::session()?
         .name("org.freedesktop.impl.portal.desktop.luminous")?
         .serve_at("/org/freedesktop/portal/desktop", AccessBackend)?
         .serve_at("/org/freedesktop/portal/desktop", ScreenShotBackend)?
-        .serve_at("/org/freedesktop/portal/desktop", ScreenCastBackend)?
+        .serve_at(
+            "/org/freedesktop/portal/desktop",
+            ScreenCastBackend::new(WaylandCasts),
+        )?
         .serve_at("/org/freedesktop/portal/desktop", RemoteDesktopBackend)?
         .serve_at("/org/freedesktop/portal/desktop", SettingsBackend)?
-        .build()
-        .await?;
+        .serve_at("/org/freedesktop/portal/desktop", WallpaperBackend)?
+        .serve_at("/org/freedesktop/portal/desktop", RestoreTokensBackend)?
+        .serve_at("/org/freedesktop/portal/desktop", CastsBackend)?;
+    if std::env::var_os(DEBUG_ENV).is_some() {
+        tracing::info!("{DEBUG_ENV} is set, serve the debug interface");
+        builder = builder.serve_at("/org/freedesktop/portal/desktop", DebugBackend)?;
+    }
+    let conn = builder.build().await?;
 
     set_connection(conn).await;
+    tokio::spawn(close_idle_sessions());
     tokio::spawn(async {
         let Ok(home) = std::env::var("HOME") else {
             return;
@@ -164,7 +237,93 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    pending::<()>().await;
+    let mut terminate = tokio::signal::unix::signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    tracing::info!("luminous is shutting down");
+    let connection = get_connection().await;
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, shutdown(&connection))
+        .await
+        .is_err()
+    {
+        tracing::warn!("some casts did not stop in time, they are left behind");
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use futures::TryStreamExt;
+    use zbus::zvariant::ObjectPath;
+
+    use super::*;
+    use crate::pipewirethread::ScreencastThread;
+    use crate::screencast::StreamProperties;
+    use crate::session::{Session, SessionType};
+    use crate::testing::{lock_globals, serve};
+
+    #[tokio::test]
+    async fn shutdown_closes_the_sessions_and_stops_the_casts() {
+        let _globals = lock_globals().await;
+        let path = "/org/freedesktop/portal/desktop/session/1_1/luminous_shutdown";
+        let session = Session::new(
+            ObjectPath::from_static_str_unchecked(path),
+            "luminous_shutdown".to_string(),
+            SessionType::ScreenCast,
+            "org.luminous.Test".to_string(),
+        );
+        SESSIONS.write().await.push(session.clone());
+        let stopped: Vec<_> = (0..2).map(|_| Arc::new(AtomicBool::new(false))).collect();
+        CAST_SESSIONS
+            .lock()
+            .await
+            .extend(stopped.iter().enumerate().map(|(node_id, stopped)| {
+                (
+                    path.to_string(),
+                    ScreencastThread::idle(node_id as u32, stopped.clone()),
+                    StreamProperties::default(),
+                )
+            }));
+        let (server, client) = serve(path, session).await;
+        let mut messages = zbus::MessageStream::from(&client);
+
+        tokio::time::timeout(SHUTDOWN_TIMEOUT, shutdown(&server))
+            .await
+            .expect("the casts are not stopped in time");
+        assert!(SESSIONS.read().await.is_empty());
+        assert!(CAST_SESSIONS.lock().await.is_empty());
+        assert!(
+            stopped
+                .iter()
+                .all(|stopped| stopped.load(Ordering::Relaxed))
+        );
+
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let message = messages
+                    .try_next()
+                    .await
+                    .unwrap()
+                    .expect("no more messages");
+                if message
+                    .header()
+                    .member()
+                    .is_some_and(|member| member == "Closed")
+                {
+                    return message;
+                }
+            }
+        })
+        .await
+        .expect("Closed is not emitted");
+        assert_eq!(closed.header().path().unwrap().as_str(), path);
+        let reason: String = closed.body().deserialize().unwrap();
+        assert_eq!(reason, "The portal is shutting down");
+    }
+}
@@ -1,137 +1,1363 @@
+mod cursor;
+mod dmabuf;
+mod hide;
+mod output_watch;
+mod toplevel;
+
 use libwayshot::CaptureRegion;
 use libwayshot::{WayshotConnection, reexport::WlOutput};
 use pipewire::{
     spa::{
         self,
+        param::video::VideoFormat,
         pod::{self, deserialize::PodDeserializer, serialize::PodSerializer},
     },
     stream::StreamState,
 };
 use rustix::fd::BorrowedFd;
 
-use std::{cell::RefCell, io, os::fd::IntoRawFd, rc::Rc, slice};
+use std::{
+    cell::{Cell, RefCell},
+    hash::{DefaultHasher, Hash, Hasher},
+    io, mem,
+    ops::Range,
+    os::fd::{IntoRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    ptr,
+    rc::Rc,
+    slice,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use tokio::sync::oneshot;
+use wayland_client::protocol::{wl_output, wl_shm};
+
+use crate::session::CursorMode;
+use crate::settings::SETTING_CONFIG;
+use crate::utils::{output_layout, screenshot_area};
+
+pub use self::cursor::output_under_cursor;
+use self::cursor::{CursorCapture, CursorState};
+use self::dmabuf::{DmabufCapture, DmabufOffer};
+use self::hide::HiddenAreas;
+use self::output_watch::{OutputChange, OutputWatch};
+pub use self::output_watch::{refresh_rates, watch_outputs};
+use self::toplevel::ToplevelCapture;
+pub use self::toplevel::{ToplevelInfo, list_toplevels};
+
+/// The largest cursor bitmap we offer to send as metadata
+const CURSOR_BITMAP_MAX_SIZE: u32 = 256;
+
+/// Used when no framerate is asked for and the refresh rate of the output is unknown
+const DEFAULT_FRAMERATE: u32 = 60;
+pub const MAX_FRAMERATE: u32 = 240;
+
+/// How many times a cast tries to connect to PipeWire again once the daemon went away
+const RECONNECT_ATTEMPTS: u32 = 5;
+/// The wait before the first attempt, doubled after each failed one
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+/// How long PipeWire has to make the node of a new cast
+const STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+/// The average capture latency past which a warning is logged
+const LATENCY_WARNING: Duration = Duration::from_millis(50);
+/// How much of the average capture latency is kept for each new frame, as `1 - 1 / n`
+const LATENCY_SMOOTHING: u64 = 8;
+/// The stream property telling the render node DMA-BUFs are allocated on, when the stream
+/// may hand them out
+const RENDER_NODE_KEY: &str = "luminous.dmabuf.render-node";
+
+/// What the cast thread is asked to do from outside.
+enum CastMessage {
+    Stop,
+    Pause,
+    Resume,
+}
+
+/// How a stream ended, told by its listeners before they quit the loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamEnd {
+    /// The cast is over, the output is gone or the stream failed
+    Finished(u32),
+    /// The connection to PipeWire is lost, the cast may go on with a new stream
+    Disconnected,
+}
+
+/// What a cast copies its frames from.
+#[derive(Clone)]
+pub enum CastTarget {
+    /// One output, or a region of it in logical coordinates
+    Output {
+        output: WlOutput,
+        region: Option<CaptureRegion>,
+    },
+    /// All the outputs pieced together as they are laid out, in logical pixels since their
+    /// scales may differ. Gaps between them stay transparent.
+    Desktop,
+    /// One window, by its ext-foreign-toplevel identifier. Its last frame is sent again while
+    /// it is not redrawn, and the cast ends when it is closed.
+    Toplevel { identifier: String },
+}
+
+/// Counters kept by the cast thread, cheap enough to bump on every frame.
+#[derive(Debug, Default)]
+struct FrameCounters {
+    captured: AtomicU64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    /// The framerate agreed on with the consumer, 0 until the format is negotiated
+    framerate: AtomicU32,
+    /// Whether a consumer is connected and frames flow
+    streaming: AtomicBool,
+    /// The rolling average of the capture latency in microseconds, 0 until a frame is copied
+    latency_us: AtomicU64,
+}
+
+impl FrameCounters {
+    /// Fold the `latency` of one frame into the average, and return the new average.
+    fn add_latency(&self, latency: Duration) -> Duration {
+        let sample = latency.as_micros() as u64;
+        let average = match self.latency_us.load(Ordering::Relaxed) {
+            0 => sample,
+            average => average - average / LATENCY_SMOOTHING + sample / LATENCY_SMOOTHING,
+        };
+        self.latency_us.store(average.max(1), Ordering::Relaxed);
+        Duration::from_micros(average)
+    }
+}
+
+/// How a cast is doing, see [`ScreencastThread::stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames copied from the compositor
+    pub captured: u64,
+    /// Frames handed to the consumer
+    pub delivered: u64,
+    /// Frames asked for but not sent, because no buffer was free or the copy failed
+    pub dropped: u64,
+    /// The framerate agreed on with the consumer, `None` until it connects
+    pub framerate: Option<u32>,
+    /// The rolling average of the time from asking for a frame to having it in the buffer,
+    /// `None` until a frame is copied
+    pub latency: Option<Duration>,
+}
 
 pub struct ScreencastThread {
-    node_id: u32,
-    thread_stop_tx: pipewire::channel::Sender<()>,
+    /// Changes if the cast connects to PipeWire again
+    node_id: Arc<AtomicU32>,
+    size: (u32, u32),
+    max_framerate: u32,
+    counters: Arc<FrameCounters>,
+    paused: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    thread_tx: pipewire::channel::Sender<CastMessage>,
+    thread: std::thread::JoinHandle<()>,
 }
 
 impl ScreencastThread {
+    /// Start casting `target` at up to `framerate` frames per second, clamped to
+    /// `1..=MAX_FRAMERATE`. When `None`, the `framerate` of the config is used, or else the
+    /// refresh rate of the output, so each output of a session is cast at its own rate, or
+    /// [`DEFAULT_FRAMERATE`] if it is unknown.
+    ///
+    /// Frames larger than `max_size`, as `(max_width, max_height)`, are shrunk on the CPU
+    /// keeping their aspect ratio; they are never enlarged.
+    ///
+    /// If PipeWire restarts, the cast connects again up to [`RECONNECT_ATTEMPTS`] times with a
+    /// new stream, whose node id [`ScreencastThread::node_id`] tells from then on.
+    ///
+    /// If the output is unplugged, the stream fails or PipeWire does not come back, the cast
+    /// stops on its own, and `on_ended` is called from the cast thread with the node id.
+    #[allow(clippy::too_many_arguments)]
     pub async fn start_cast(
-        overlay_cursor: bool,
+        cursor_mode: CursorMode,
         width: u32,
         height: u32,
-        capture_region: Option<CaptureRegion>,
-        output: WlOutput,
+        framerate: Option<u32>,
+        max_size: (Option<u32>, Option<u32>),
+        target: CastTarget,
         connection: WayshotConnection,
+        on_ended: impl FnOnce(u32) + Send + 'static,
     ) -> anyhow::Result<Self> {
+        check_pipewire()?;
         let (tx, rx) = oneshot::channel();
-        let (thread_stop_tx, thread_stop_rx) = pipewire::channel::channel::<()>();
-        std::thread::spawn(move || {
-            match start_stream(
-                connection,
-                overlay_cursor,
-                width,
-                height,
-                capture_region,
-                output,
-            ) {
-                Ok((loop_, listener, context, node_id_rx)) => {
-                    tx.send(Ok(node_id_rx)).unwrap();
-                    let weak_loop = loop_.downgrade();
-                    let _receiver = thread_stop_rx.attach(loop_.loop_(), move |()| {
-                        weak_loop.upgrade().unwrap().quit();
-                    });
-                    loop_.run();
-                    // XXX fix segfault with opposite drop order
-                    drop(listener);
-                    drop(context);
+        let (thread_tx, thread_rx) = pipewire::channel::channel::<CastMessage>();
+        let (skip_unchanged, cursor_scale, hidden, config_framerate, preferred_format) = {
+            let config = SETTING_CONFIG.lock().await;
+            (
+                config.skip_unchanged_frames,
+                config.get_cursor_scale(),
+                HiddenAreas::new(
+                    &connection,
+                    &target,
+                    &config.hidden_regions,
+                    config.blurs_hidden_regions(),
+                ),
+                config.get_framerate(),
+                config.get_pixel_format().and_then(video_format_named),
+            )
+        };
+        // Asking for the refresh rate takes roundtrips, which must not hold the executor
+        let (connection, target, refresh) = match framerate.or(config_framerate) {
+            Some(_) => (connection, target, None),
+            None => {
+                tokio::task::spawn_blocking(move || {
+                    let refresh = output_refresh(&connection, &target);
+                    (connection, target, refresh)
+                })
+                .await?
+            }
+        };
+        let max_framerate = framerate
+            .or(config_framerate)
+            .or(refresh)
+            .unwrap_or(DEFAULT_FRAMERATE)
+            .clamp(1, MAX_FRAMERATE);
+        let counters = Arc::new(FrameCounters::default());
+        let thread_counters = counters.clone();
+        let node_id = Arc::new(AtomicU32::new(0));
+        let thread_node_id = node_id.clone();
+        let paused = Arc::new(AtomicBool::new(false));
+        let thread_paused = paused.clone();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        let thread = std::thread::spawn(move || {
+            let connection = Rc::new(connection);
+            let mut thread_rx = thread_rx;
+            let mut tx = Some(tx);
+            let mut attempt = 0;
+            let ended = loop {
+                let stream_end = Rc::new(Cell::new(None));
+                match start_stream(
+                    connection.clone(),
+                    cursor_mode,
+                    width,
+                    height,
+                    max_framerate,
+                    max_size,
+                    skip_unchanged,
+                    cursor_scale,
+                    preferred_format,
+                    hidden.clone(),
+                    thread_counters.clone(),
+                    target.clone(),
+                    thread_node_id.clone(),
+                    stream_end.clone(),
+                ) {
+                    Ok((
+                        loop_,
+                        listener,
+                        core_listener,
+                        context,
+                        stream_cell,
+                        node_id_rx,
+                        size,
+                    )) => {
+                        match tx.take() {
+                            Some(tx) => tx.send(Ok((node_id_rx, size))).unwrap(),
+                            None => {
+                                tracing::info!("connected to pipewire again");
+                                attempt = 0;
+                                if thread_paused.load(Ordering::Relaxed)
+                                    && let Some(stream) = stream_cell.borrow().as_ref()
+                                {
+                                    let _ = stream.set_active(false);
+                                }
+                            }
+                        }
+                        let weak_loop = loop_.downgrade();
+                        let receiver = thread_rx.attach(loop_.loop_(), move |message| {
+                            let active = match message {
+                                CastMessage::Stop => {
+                                    weak_loop.upgrade().unwrap().quit();
+                                    return;
+                                }
+                                CastMessage::Pause => false,
+                                CastMessage::Resume => true,
+                            };
+                            let stream = stream_cell.borrow();
+                            let Some(stream) = stream.as_ref() else {
+                                return;
+                            };
+                            if let Err(e) = stream.set_active(active) {
+                                tracing::warn!("cannot set the stream active to {active}: {e}");
+                            }
+                        });
+                        loop_.run();
+                        // XXX fix segfault with opposite drop order
+                        drop(listener);
+                        drop(core_listener);
+                        drop(context);
+                        thread_rx = receiver.deattach();
+                        match stream_end.get() {
+                            Some(StreamEnd::Finished(node_id)) => break Some(node_id),
+                            Some(StreamEnd::Disconnected) => {
+                                tracing::warn!("lost the connection to pipewire");
+                            }
+                            None => break None,
+                        }
+                    }
+                    Err(err) => {
+                        if let Some(tx) = tx.take() {
+                            let _ = tx.send(Err(err));
+                            return;
+                        }
+                        tracing::warn!("cannot connect to pipewire again: {err}");
+                    }
+                }
+                attempt += 1;
+                if attempt > RECONNECT_ATTEMPTS {
+                    tracing::error!("pipewire did not come back, stop casting");
+                    break Some(thread_node_id.load(Ordering::Relaxed));
+                }
+                let delay = RECONNECT_DELAY * 2u32.pow(attempt - 1);
+                tracing::info!(
+                    "connect to pipewire again in {delay:?}, attempt {attempt} of \
+                     {RECONNECT_ATTEMPTS}"
+                );
+                if !wait_unless_stopped(delay, &thread_stopped) {
+                    break None;
                 }
-                Err(err) => tx.send(Err(err)).unwrap(),
             };
+            if let Some(node_id) = ended {
+                on_ended(node_id);
+            }
         });
+        // The node id is stored before it is told
+        let (node_id_rx, size) = rx.await??;
+        // A daemon that hangs would leave the frontend waiting for good
+        match tokio::time::timeout(STREAM_TIMEOUT, node_id_rx).await {
+            Ok(node_id) => {
+                node_id??;
+            }
+            Err(_) => {
+                stopped.store(true, Ordering::Relaxed);
+                let _ = thread_tx.send(CastMessage::Stop);
+                anyhow::bail!("PipeWire not available, it did not make the stream in time");
+            }
+        }
         Ok(Self {
-            node_id: rx.await??.await??,
-            thread_stop_tx,
+            node_id,
+            size,
+            max_framerate,
+            counters,
+            paused,
+            stopped,
+            thread_tx,
+            thread,
         })
     }
-
     pub fn node_id(&self) -> u32 {
-        self.node_id
+        self.node_id.load(Ordering::Relaxed)
+    }
+
+    /// The size of the frames when the cast started, in physical pixels.
+    pub fn size(&self) -> (u32, u32) {
+        self.size
     }
 
+    /// The framerate agreed on with the consumer, `None` until it connects.
+    pub fn framerate(&self) -> Option<u32> {
+        Some(self.counters.framerate.load(Ordering::Relaxed)).filter(|framerate| *framerate != 0)
+    }
+
+    /// The framerate agreed on, or the highest one offered until a consumer connects.
+    pub fn settled_framerate(&self) -> u32 {
+        self.framerate().unwrap_or(self.max_framerate)
+    }
+
+    /// Whether no consumer is connected. A paused cast is not idle, its consumer may still be.
+    pub fn is_idle(&self) -> bool {
+        !self.counters.streaming.load(Ordering::Relaxed) && !self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            captured: self.counters.captured.load(Ordering::Relaxed),
+            delivered: self.counters.delivered.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            framerate: self.framerate(),
+            latency: Some(self.counters.latency_us.load(Ordering::Relaxed))
+                .filter(|latency| *latency != 0)
+                .map(Duration::from_micros),
+        }
+    }
+
+    /// Stop the cast and its thread. Stopping a stopped cast does nothing.
     pub fn stop(&self) {
-        let _ = self.thread_stop_tx.send(());
+        if !self.stopped.swap(true, Ordering::Relaxed) {
+            let _ = self.thread_tx.send(CastMessage::Stop);
+        }
+    }
+
+    /// Whether the cast thread is done, and so its node gone.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Stop capturing frames, the node stays around so the consumer stays connected.
+    ///
+    /// Pausing a paused cast does nothing.
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::Relaxed) {
+            let _ = self.thread_tx.send(CastMessage::Pause);
+        }
+    }
+
+    /// Capture frames again after [`ScreencastThread::pause`].
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::Relaxed) {
+            let _ = self.thread_tx.send(CastMessage::Resume);
+        }
+    }
+
+    /// Whether the cast is paused, see [`ScreencastThread::pause`].
+    #[cfg(test)]
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// A cast of nothing with the node `node_id`, whose thread only waits for `stopped`, for
+    /// the tests that keep and stop casts without a compositor or PipeWire.
+    #[cfg(test)]
+    pub fn idle(node_id: u32, stopped: Arc<AtomicBool>) -> Self {
+        let (thread_tx, _) = pipewire::channel::channel::<CastMessage>();
+        let thread = std::thread::spawn({
+            let stopped = stopped.clone();
+            move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        });
+        Self {
+            node_id: Arc::new(AtomicU32::new(node_id)),
+            size: (0, 0),
+            max_framerate: DEFAULT_FRAMERATE,
+            counters: Arc::default(),
+            paused: Arc::default(),
+            stopped,
+            thread_tx,
+            thread,
+        }
+    }
+}
+
+impl Drop for ScreencastThread {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Fail plainly when no PipeWire daemon can be reached, before any cast thread is started.
+///
+/// The socket is looked for where libpipewire would connect to it.
+fn check_pipewire() -> anyhow::Result<()> {
+    let remote = std::env::var("PIPEWIRE_REMOTE").unwrap_or_else(|_| "pipewire-0".to_string());
+    let socket = if Path::new(&remote).is_absolute() {
+        PathBuf::from(remote)
+    } else {
+        let Some(runtime_dir) = std::env::var_os("PIPEWIRE_RUNTIME_DIR")
+            .or_else(|| std::env::var_os("XDG_RUNTIME_DIR"))
+        else {
+            anyhow::bail!("PipeWire not available, XDG_RUNTIME_DIR is not set");
+        };
+        Path::new(&runtime_dir).join(remote)
+    };
+    if !socket.exists() {
+        anyhow::bail!(
+            "PipeWire not available, there is no {}. Is pipewire running?",
+            socket.display()
+        );
+    }
+    Ok(())
+}
+
+/// Sleep for `delay`, or less if the cast is stopped meanwhile. Tell whether it goes on.
+fn wait_unless_stopped(delay: Duration, stopped: &AtomicBool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < delay {
+        if stopped.load(Ordering::Relaxed) {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    !stopped.load(Ordering::Relaxed)
+}
+
+/// The SPA video format matching the memory layout of a wl_shm `format`.
+///
+/// wl_shm formats are little endian, so the channel order is reversed.
+fn shm_video_format(format: wl_shm::Format) -> Option<VideoFormat> {
+    match format {
+        wl_shm::Format::Argb8888 => Some(VideoFormat::BGRA),
+        wl_shm::Format::Xrgb8888 => Some(VideoFormat::BGRx),
+        wl_shm::Format::Abgr8888 => Some(VideoFormat::RGBA),
+        wl_shm::Format::Xbgr8888 => Some(VideoFormat::RGBx),
+        // What compositors may copy HDR and wide gamut outputs as
+        wl_shm::Format::Argb2101010 => Some(VideoFormat::ARGB_210LE),
+        wl_shm::Format::Xrgb2101010 => Some(VideoFormat::xRGB_210LE),
+        wl_shm::Format::Abgr2101010 => Some(VideoFormat::ABGR_210LE),
+        wl_shm::Format::Xbgr2101010 => Some(VideoFormat::xBGR_210LE),
+        _ => None,
+    }
+}
+
+/// Whether `format` packs 10 bits per channel in 32 bits.
+fn is_10bit(format: VideoFormat) -> bool {
+    matches!(
+        format,
+        VideoFormat::ARGB_210LE
+            | VideoFormat::xRGB_210LE
+            | VideoFormat::ABGR_210LE
+            | VideoFormat::xBGR_210LE
+    )
+}
+
+/// Drop the 2 lowest bits of each channel of the 10 bit `pixels`, which are laid out as
+/// `format`, leaving them as [`VideoFormat::RGBA`].
+fn narrow_10bit(pixels: &mut [u8], format: VideoFormat) {
+    let (red_shift, blue_shift) = match format {
+        VideoFormat::ABGR_210LE | VideoFormat::xBGR_210LE => (0, 20),
+        _ => (20, 0),
+    };
+    let has_alpha = matches!(format, VideoFormat::ARGB_210LE | VideoFormat::ABGR_210LE);
+    for pixel in pixels.chunks_exact_mut(4) {
+        let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+        let channel = |shift: u32| (((value >> shift) & 0x3ff) >> 2) as u8;
+        let alpha = if has_alpha {
+            (value >> 30) as u8 * 0x55
+        } else {
+            0xff
+        };
+        pixel.copy_from_slice(&[channel(red_shift), channel(10), channel(blue_shift), alpha]);
+    }
+}
+
+/// Turn `pixels` laid out as `layout` into the `target` the consumer picked, one of the
+/// [`offered_formats`].
+fn convert_layout(pixels: &mut [u8], mut layout: VideoFormat, target: VideoFormat) {
+    if layout == target {
+        return;
+    }
+    if is_10bit(layout) && !is_10bit(target) {
+        narrow_10bit(pixels, layout);
+        layout = VideoFormat::RGBA;
+    }
+    if is_swapped(target, layout) {
+        for pixel in pixels.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+}
+
+/// Whether `format` is `other` with the red and blue channels swapped.
+fn is_swapped(format: VideoFormat, other: VideoFormat) -> bool {
+    fn is_bgr(format: VideoFormat) -> bool {
+        format == VideoFormat::BGRA || format == VideoFormat::BGRx
+    }
+    is_bgr(format) != is_bgr(other)
+}
+
+/// The formats we can offer for frames copied as `native`, the native one first.
+///
+/// The channels can be swapped while copying, and a padding byte can stand for an alpha
+/// channel, but not the other way around, as it holds no real alpha.
+///
+/// 10 bit frames are offered as they are only with `deep_color`, and are narrowed to 8 bits
+/// for the consumers that do not take them. Only whole channels can be blended when frames
+/// are shrunk, so shrunk frames are never offered in 10 bits.
+fn offered_formats(native: VideoFormat, deep_color: bool) -> Vec<VideoFormat> {
+    if is_10bit(native) {
+        let has_alpha = matches!(native, VideoFormat::ARGB_210LE | VideoFormat::ABGR_210LE);
+        let mut formats = offered_formats(
+            if has_alpha {
+                VideoFormat::RGBA
+            } else {
+                VideoFormat::RGBx
+            },
+            false,
+        );
+        if deep_color {
+            formats.insert(0, native);
+        }
+        return formats;
+    }
+    match native {
+        VideoFormat::BGRA => vec![
+            VideoFormat::BGRA,
+            VideoFormat::BGRx,
+            VideoFormat::RGBA,
+            VideoFormat::RGBx,
+        ],
+        VideoFormat::RGBA => vec![
+            VideoFormat::RGBA,
+            VideoFormat::RGBx,
+            VideoFormat::BGRA,
+            VideoFormat::BGRx,
+        ],
+        VideoFormat::BGRx => vec![VideoFormat::BGRx, VideoFormat::RGBx],
+        _ => vec![VideoFormat::RGBx, VideoFormat::BGRx],
+    }
+}
+
+/// The format called `name` in the config, see `SettingsConfig::pixel_format`.
+fn video_format_named(name: &str) -> Option<VideoFormat> {
+    match name {
+        "BGRx" => Some(VideoFormat::BGRx),
+        "BGRA" => Some(VideoFormat::BGRA),
+        "RGBx" => Some(VideoFormat::RGBx),
+        "RGBA" => Some(VideoFormat::RGBA),
+        _ => None,
+    }
+}
+
+/// Put the `preferred` format first in `formats`, the one consumers get unless they pick
+/// another, when it is one of them.
+fn prefer_format(
+    mut formats: Vec<VideoFormat>,
+    preferred: Option<VideoFormat>,
+) -> Vec<VideoFormat> {
+    if let Some(index) = formats.iter().position(|format| Some(*format) == preferred) {
+        let format = formats.remove(index);
+        formats.insert(0, format);
+    }
+    formats
+}
+
+/// Copy one frame to learn the layout and the size the compositor hands frames out with.
+///
+/// On a fractionally scaled output, the compositor rounds a region to physical pixels its own
+/// way, so the size it gives wins over the expected `width` by `height`.
+fn probe_video_format(
+    connection: &WayshotConnection,
+    output: &WlOutput,
+    width: u32,
+    height: u32,
+    capture_region: Option<CaptureRegion>,
+) -> anyhow::Result<(VideoFormat, u32, u32)> {
+    let fd = rustix::fs::memfd_create(c"pipewire-probe", rustix::fs::MemfdFlags::CLOEXEC)?;
+    // Room for the rounding of the compositor
+    rustix::fs::ftruncate(&fd, ((width + 2) * (height + 2) * 4) as _)?;
+    let frame_format = connection.capture_output_frame_shm_fd(0, output, &fd, capture_region)?;
+    let video_format = shm_video_format(frame_format.format).ok_or_else(|| {
+        anyhow::anyhow!(
+            "the compositor copies frames as {:?}, which cannot be cast",
+            frame_format.format
+        )
+    })?;
+    Ok((video_format, frame_format.width, frame_format.height))
+}
+
+/// A digest of a frame, to tell when the screen has not changed. The cursor metadata is sent
+/// even then, so it is left out.
+///
+/// libwayshot copies whole frames and does not pass on the damage of the compositor, so the
+/// frames are compared instead.
+fn frame_hash(pixels: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    pixels.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Piece the outputs together into a `width` by `height` rgba frame.
+fn capture_desktop(
+    connection: &WayshotConnection,
+    show_cursor: bool,
+    (width, height): (u32, u32),
+) -> anyhow::Result<image::RgbaImage> {
+    let (x, y, _, _) = output_layout(connection);
+    screenshot_area(
+        connection,
+        CaptureRegion {
+            x_coordinate: x,
+            y_coordinate: y,
+            width: width as i32,
+            height: height as i32,
+        },
+        show_cursor,
+    )
+}
+
+/// The largest size within `max_width` by `max_height` with the aspect ratio of `native`,
+/// which it never exceeds.
+fn fit_size(
+    (width, height): (u32, u32),
+    (max_width, max_height): (Option<u32>, Option<u32>),
+) -> (u32, u32) {
+    if width == 0 || height == 0 {
+        return (width, height);
+    }
+    let scale = [
+        max_width.map(|max_width| max_width as f64 / width as f64),
+        max_height.map(|max_height| max_height as f64 / height as f64),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(1., f64::min);
+    if scale >= 1. {
+        return (width, height);
+    }
+    (
+        ((width as f64 * scale).round() as u32).max(1),
+        ((height as f64 * scale).round() as u32).max(1),
+    )
+}
+
+/// Spaces the frames sent evenly at up to a framerate, however often `process` is called:
+/// the graph may run at the refresh rate of the screen while the consumer wants far less.
+struct FrameLimiter {
+    framerate: u32,
+    /// When the next frame should go
+    next: Option<Instant>,
+    last_call: Option<Instant>,
+}
+
+impl FrameLimiter {
+    fn new() -> Self {
+        Self {
+            framerate: 0,
+            next: None,
+            last_call: None,
+        }
+    }
+
+    /// Whether a frame is due `now` at `framerate`, which may change from call to call.
+    fn is_due(&mut self, framerate: u32, now: Instant) -> bool {
+        let cycle = self
+            .last_call
+            .map(|last| now.saturating_duration_since(last))
+            .unwrap_or_default();
+        self.last_call = Some(now);
+        let interval = Duration::from_secs(1) / framerate.max(1);
+        if self.framerate != framerate {
+            self.framerate = framerate;
+            self.next = None;
+        }
+        let Some(next) = self.next else {
+            self.next = Some(now + interval);
+            return true;
+        };
+        // Up to half a cycle early, so the frame goes on the cycle closest to its time
+        if now + cycle / 2 < next {
+            return false;
+        }
+        // Counted from when the frame was due, not when it went, to keep the spacing even; a
+        // frame late by more than a whole interval does not make the next ones burst out
+        self.next = Some(if next + interval < now {
+            now + interval
+        } else {
+            next + interval
+        });
+        true
+    }
+}
+
+/// Where frames that are shrunk before being sent are captured at their native size.
+struct Scratch {
+    fd: OwnedFd,
+    pixels: Vec<u8>,
+}
+
+impl Scratch {
+    fn new() -> anyhow::Result<Self> {
+        let fd = rustix::fs::memfd_create(c"pipewire-scratch", rustix::fs::MemfdFlags::CLOEXEC)?;
+        Ok(Self {
+            fd,
+            pixels: Vec::new(),
+        })
+    }
+
+    /// Capture a `width` by `height` frame of `output`, whose pixels are returned.
+    fn capture(
+        &mut self,
+        connection: &WayshotConnection,
+        overlay_cursor: bool,
+        output: &WlOutput,
+        region: Option<CaptureRegion>,
+        (width, height): (u32, u32),
+    ) -> anyhow::Result<&[u8]> {
+        let len = (width * height * 4) as usize;
+        if self.pixels.len() != len {
+            rustix::fs::ftruncate(&self.fd, len as u64)?;
+            self.pixels.resize(len, 0);
+        }
+        connection.capture_output_frame_shm_fd(overlay_cursor as i32, output, &self.fd, region)?;
+        if rustix::io::pread(&self.fd, self.pixels.as_mut_slice(), 0)? < len {
+            anyhow::bail!("the frame is cut short");
+        }
+        Ok(&self.pixels)
+    }
+}
+
+/// The memory frames are narrowed and shrunk in before they are sent, kept from frame to frame
+/// so that a cast only allocates when the size of its frames changes, not at every frame.
+///
+/// libwayshot allocates the frames of the desktop itself, which cannot be pooled here.
+#[derive(Default)]
+struct FramePool {
+    narrowed: Vec<u8>,
+    shrinker: Shrinker,
+}
+
+/// Shrinks frames with a triangle filter, across then down, like `image` does.
+#[derive(Default)]
+struct Shrinker {
+    across: Filter,
+    down: Filter,
+    /// The frame shrunk across, still at its native height
+    shrunk_across: Vec<f32>,
+}
+
+impl Shrinker {
+    /// Shrink the `from` sized frame `pixels` into `target`, at the `to` size. Only the
+    /// channel values are blended, so their order does not matter.
+    fn shrink(
+        &mut self,
+        pixels: &[u8],
+        (from_width, from_height): (u32, u32),
+        target: &mut [u8],
+        (to_width, to_height): (u32, u32),
+    ) -> anyhow::Result<()> {
+        let (from_width, from_height) = (from_width as usize, from_height as usize);
+        let (to_width, to_height) = (to_width as usize, to_height as usize);
+        if pixels.len() < from_width * from_height * 4 {
+            anyhow::bail!("the frame is cut short");
+        }
+        if target.len() < to_width * to_height * 4 {
+            anyhow::bail!("the buffer is too small for the frame");
+        }
+        self.across.fit(from_width, to_width);
+        self.down.fit(from_height, to_height);
+        self.shrunk_across.resize(to_width * from_height * 4, 0.);
+        let rows = pixels.chunks_exact(from_width * 4);
+        for (row, line) in rows.zip(self.shrunk_across.chunks_exact_mut(to_width * 4)) {
+            for (pixel, (first, weights)) in line.chunks_exact_mut(4).zip(self.across.taps()) {
+                let mut sum = [0.; 4];
+                for (source, weight) in row[first * 4..].chunks_exact(4).zip(weights) {
+                    for (sum, &channel) in sum.iter_mut().zip(source) {
+                        *sum += channel as f32 * weight;
+                    }
+                }
+                pixel.copy_from_slice(&sum);
+            }
+        }
+        let stride = to_width * 4;
+        for (line, (first, weights)) in target.chunks_exact_mut(stride).zip(self.down.taps()) {
+            for (x, channel) in line.iter_mut().enumerate() {
+                let sum: f32 = weights
+                    .iter()
+                    .enumerate()
+                    .map(|(row, weight)| self.shrunk_across[(first + row) * stride + x] * weight)
+                    .sum();
+                *channel = sum.round().clamp(0., 255.) as u8;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The weights a line of pixels is shrunk with, made again only when its length changes.
+#[derive(Default)]
+struct Filter {
+    from: usize,
+    to: usize,
+    /// The first pixel each shrunk one is blended from, and where its weights are
+    taps: Vec<(usize, Range<usize>)>,
+    weights: Vec<f32>,
+}
+
+impl Filter {
+    /// Have the weights shrink a line of `from` pixels to `to`.
+    fn fit(&mut self, from: usize, to: usize) {
+        if (self.from, self.to) == (from, to) {
+            return;
+        }
+        self.from = from;
+        self.to = to;
+        self.taps.clear();
+        self.weights.clear();
+        let scale = from as f32 / to as f32;
+        let support = scale.max(1.);
+        for pixel in 0..to {
+            let center = (pixel as f32 + 0.5) * scale;
+            let first = ((center - support).floor().max(0.) as usize).min(from - 1);
+            let end = ((center + support).ceil() as usize).clamp(first + 1, from);
+            let start = self.weights.len();
+            self.weights.extend(
+                (first..end)
+                    .map(|source| (1. - ((source as f32 + 0.5 - center) / support).abs()).max(0.)),
+            );
+            let sum: f32 = self.weights[start..].iter().sum();
+            for weight in &mut self.weights[start..] {
+                *weight /= sum;
+            }
+            self.taps.push((first, start..self.weights.len()));
+        }
     }
+
+    /// The first pixel each shrunk one is blended from, and the weights of that one and the
+    /// next ones.
+    fn taps(&self) -> impl Iterator<Item = (usize, &[f32])> {
+        self.taps
+            .iter()
+            .map(|(first, weights)| (*first, &self.weights[weights.clone()]))
+    }
+}
+
+/// Copy the `native` sized frame `pixels`, laid out as `format`, into `data`, shrunk to
+/// `width` by `height` if it is larger, through the memory of `pool`.
+///
+/// Returns how the copy is laid out, 10 bit frames are narrowed before they are shrunk.
+///
+/// # Safety
+///
+/// `data` must belong to a buffer dequeued from the stream.
+unsafe fn write_frame(
+    pool: &mut FramePool,
+    pixels: &[u8],
+    format: VideoFormat,
+    native: (u32, u32),
+    data: &libspa_sys::spa_data,
+    width: u32,
+    height: u32,
+) -> anyhow::Result<VideoFormat> {
+    if data.data.is_null() {
+        anyhow::bail!("the buffer is not mapped");
+    }
+    let target = unsafe { slice::from_raw_parts_mut(data.data as *mut u8, data.maxsize as usize) };
+    if native == (width, height) {
+        let len = pixels.len().min(target.len());
+        target[..len].copy_from_slice(&pixels[..len]);
+        return Ok(format);
+    }
+    let (pixels, layout) = if is_10bit(format) {
+        pool.narrowed.clear();
+        pool.narrowed.extend_from_slice(pixels);
+        narrow_10bit(&mut pool.narrowed, format);
+        (pool.narrowed.as_slice(), VideoFormat::RGBA)
+    } else {
+        (pixels, format)
+    };
+    pool.shrinker
+        .shrink(pixels, native, target, (width, height))?;
+    Ok(layout)
 }
 
 type PipewireStreamResult = (
     pipewire::main_loop::MainLoop,
     pipewire::stream::StreamListener<()>,
+    pipewire::core::Listener,
     pipewire::context::Context,
+    Rc<RefCell<Option<pipewire::stream::Stream>>>,
     oneshot::Receiver<anyhow::Result<u32>>,
+    (u32, u32),
 );
 
+/// The refresh rate of the output cast by `target`, rounded to whole frames per second.
+fn output_refresh(connection: &WayshotConnection, target: &CastTarget) -> Option<u32> {
+    let CastTarget::Output { output, .. } = target else {
+        return None;
+    };
+    let name = connection
+        .get_all_outputs()
+        .iter()
+        .find(|info| info.wl_output == *output)?
+        .name
+        .clone();
+    let rates = match refresh_rates(&connection.conn) {
+        Ok(rates) => rates,
+        Err(e) => {
+            tracing::warn!("cannot get the refresh rate of {name}: {e}");
+            return None;
+        }
+    };
+    let (_, refresh) = rates
+        .into_iter()
+        .find(|(rate_name, _)| *rate_name == name)?;
+    let framerate = ((refresh + 500) / 1000).max(0) as u32;
+    tracing::info!("{name} refreshes at {framerate}Hz, cast it at up to that rate");
+    Some(framerate).filter(|framerate| *framerate != 0)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn start_stream(
-    connection: WayshotConnection,
-    overlay_cursor: bool,
+    connection: Rc<WayshotConnection>,
+    cursor_mode: CursorMode,
     width: u32,
     height: u32,
-    capture_region: Option<CaptureRegion>,
-    output: WlOutput,
-) -> Result<PipewireStreamResult, pipewire::Error> {
-    let loop_ = pipewire::main_loop::MainLoop::new(None).unwrap();
-    let context = pipewire::context::Context::new(&loop_).unwrap();
-    let core = context.connect(None).unwrap();
+    max_framerate: u32,
+    max_size: (Option<u32>, Option<u32>),
+    skip_unchanged: bool,
+    cursor_scale: f64,
+    preferred_format: Option<VideoFormat>,
+    hidden: HiddenAreas,
+    counters: Arc<FrameCounters>,
+    target: CastTarget,
+    node_id: Arc<AtomicU32>,
+    stream_end: Rc<Cell<Option<StreamEnd>>>,
+) -> anyhow::Result<PipewireStreamResult> {
+    let capture_region = match &target {
+        CastTarget::Output { region, .. } => *region,
+        CastTarget::Desktop | CastTarget::Toplevel { .. } => None,
+    };
+    // The window is followed by its own capture session, kept for the whole stream
+    let mut toplevel_capture = match &target {
+        CastTarget::Toplevel { identifier } => Some(ToplevelCapture::new(
+            &connection,
+            identifier,
+            cursor_mode != CursorMode::Hidden,
+        )?),
+        _ => None,
+    };
+    let (native_format, frame_width, frame_height) = match (&target, &toplevel_capture) {
+        (CastTarget::Output { output, region }, _) => {
+            probe_video_format(&connection, output, width, height, *region)?
+        }
+        // libwayshot pieces the outputs together as rgba
+        (CastTarget::Desktop, _) => (VideoFormat::RGBA, width, height),
+        (CastTarget::Toplevel { identifier }, Some(toplevel)) => {
+            let format = shm_video_format(toplevel.format()).ok_or_else(|| {
+                anyhow::anyhow!("the window {identifier} is copied in an unsupported format")
+            })?;
+            let (frame_width, frame_height) = toplevel.buffer_size();
+            (format, frame_width, frame_height)
+        }
+        (CastTarget::Toplevel { .. }, None) => unreachable!("the window capture is made above"),
+    };
+    if (frame_width, frame_height) != (width, height) {
+        tracing::info!(
+            "frames are {frame_width}x{frame_height} instead of {width}x{height}, use their size"
+        );
+    }
+    // Frames are captured at their native size, and maybe shrunk to the size of the stream
+    let mut native_size = (frame_width, frame_height);
+    let (width, height) = fit_size(native_size, max_size);
+    let downscaled = (width, height) != native_size;
+    if downscaled {
+        tracing::info!("frames are shrunk from {frame_width}x{frame_height} to {width}x{height}");
+    }
+    let mut scratch = Scratch::new()?;
+    let mut pool = FramePool::default();
+    // Numbers the frames sent, for the header metadata
+    let mut sequence = 0;
+    let mut slow_capture = false;
+    let mut limiter = FrameLimiter::new();
+    let (output_name, transform) = match &target {
+        CastTarget::Output { output, .. } => connection
+            .get_all_outputs()
+            .iter()
+            .find(|info| info.wl_output == *output)
+            .map(|info| (info.name.clone(), video_transform(info.transform)))
+            .unwrap_or_default(),
+        // Each output is turned upright while they are pieced together
+        CastTarget::Desktop => (
+            "the desktop".to_string(),
+            libspa_sys::SPA_META_TRANSFORMATION_None,
+        ),
+        // The compositor copies windows upright
+        CastTarget::Toplevel { identifier } => (
+            format!("the window {identifier}"),
+            libspa_sys::SPA_META_TRANSFORMATION_None,
+        ),
+    };
+    if transform != libspa_sys::SPA_META_TRANSFORMATION_None {
+        tracing::info!("{output_name} is rotated, the consumer is told to turn its frames");
+    }
+    let mut output_watch = match &target {
+        CastTarget::Output { .. } => match OutputWatch::new(&connection.conn, &output_name) {
+            Ok(output_watch) => Some(output_watch),
+            Err(e) => {
+                tracing::warn!("cannot tell when {output_name} is unplugged: {e}");
+                None
+            }
+        },
+        CastTarget::Desktop | CastTarget::Toplevel { .. } => None,
+    };
+    // The format the consumer picked, known before the buffers are added
+    let video_format = Rc::new(Cell::new(native_format));
+    let video_format_param = video_format.clone();
+    let video_format_process = video_format;
+
+    // Screencopy tells nothing of the colors of the output, so 10 bit frames are not tagged
+    // with primaries or a transfer function, and consumers take them as sRGB like 8 bit ones
+    let deep_color = max_size == (None, None);
+    if is_10bit(native_format) && deep_color {
+        tracing::info!("{output_name} is copied in 10 bits, offer them along with 8");
+    } else if is_10bit(native_format) {
+        tracing::info!("{output_name} is copied in 10 bits, narrow them to 8 to shrink them");
+    }
+    let formats = prefer_format(offered_formats(native_format, deep_color), preferred_format);
+    // Whole frames of an output can be copied by the compositor straight into DMA-BUFs, the
+    // frames that are shrunk, pieced together or painted over are written by the CPU
+    let dmabuf = match &target {
+        CastTarget::Output { output, region } if !downscaled && hidden.is_empty() => {
+            match DmabufCapture::new(&connection, output, *region) {
+                Ok(capture) if formats.contains(&capture.video_format()) => {
+                    Some(Rc::new(RefCell::new(capture)))
+                }
+                Ok(capture) => {
+                    tracing::info!(
+                        "{output_name} is copied into DMA-BUFs of {:?}, which is not offered, use shared memory",
+                        capture.video_format()
+                    );
+                    None
+                }
+                Err(e) => {
+                    tracing::info!(
+                        "cannot copy {output_name} into DMA-BUFs, use shared memory: {e}"
+                    );
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let loop_ = pipewire::main_loop::MainLoop::new(None)?;
+    let context = pipewire::context::Context::new(&loop_)?;
+    let core = context
+        .connect(None)
+        .map_err(|e| anyhow::anyhow!("PipeWire not available, cannot connect to it: {e}"))?;
+
+    // Ends the stream on its own, only the first reason is kept
+    let end_stream: Rc<dyn Fn(StreamEnd)> = {
+        let weak_loop = loop_.downgrade();
+        Rc::new(move |end| {
+            if stream_end.get().is_none() {
+                stream_end.set(Some(end));
+            }
+            if let Some(loop_) = weak_loop.upgrade() {
+                loop_.quit();
+            }
+        })
+    };
+    let end_cast: Rc<dyn Fn(u32)> = {
+        let end_stream = end_stream.clone();
+        Rc::new(move |node_id| end_stream(StreamEnd::Finished(node_id)))
+    };
 
     let name = "wayshot-screenshot"; // XXX randomize?
 
-    let stream = pipewire::stream::Stream::new(
-        &core,
-        name,
-        pipewire::properties::properties! {
-            "media.class" => "Video/Source",
-            "node.name" => "wayshot-screenshot", // XXX
-        },
-    )?;
+    let mut properties = pipewire::properties::properties! {
+        "media.class" => "Video/Source",
+        "node.name" => "wayshot-screenshot", // XXX
+    };
+    // For a consumer to tell whether it is on the same GPU, and can import the buffers as
+    // they are
+    if let Some(dmabuf) = &dmabuf {
+        let render_node = dmabuf.borrow().render_node().to_string_lossy().into_owned();
+        properties.insert(RENDER_NODE_KEY, render_node);
+    }
+    let stream = pipewire::stream::Stream::new(&core, name, properties)?;
+
+    // An embedded cursor is painted in by the compositor along with the frame, placed by its
+    // hotspot and in its current shape, so there is nothing to draw here.
+    // The cursor is not followed across outputs, so the desktop gets it painted in instead, as
+    // do shrunk frames, whose cursor position would not match
+    let follow_cursor = !downscaled && matches!(target, CastTarget::Output { .. });
+    let overlay_cursor =
+        cursor_mode.show_cursor() || (cursor_mode == CursorMode::Metadata && !follow_cursor);
+    let mut cursor_capture = match &target {
+        CastTarget::Output { output, .. }
+            if cursor_mode == CursorMode::Metadata && follow_cursor =>
+        {
+            match CursorCapture::new(&connection, output, cursor_scale) {
+                Ok(cursor_capture) => Some(cursor_capture),
+                Err(e) => {
+                    tracing::warn!("cannot follow the cursor, no cursor metadata is sent: {e}");
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let cursor_metadata = cursor_capture.is_some();
+    let dmabuf_param = dmabuf.clone();
+    let dmabuf_add = dmabuf.clone();
+    let dmabuf_remove = dmabuf.clone();
+    let dmabuf_process = dmabuf.clone();
+    let param_formats = formats.clone();
+    let add_formats = formats.clone();
+    // The size of whole output casts follows the output mode
+    let size = Rc::new(Cell::new((width, height)));
+    let buffer_size = size.clone();
+    let param_size = size.clone();
+    // The cursor is reported relative to the whole output, in buffer pixels
+    let cursor_offset = capture_region
+        .map(|region| {
+            let scale = width as f64 / region.width as f64;
+            (
+                (region.x_coordinate as f64 * scale).round() as i32,
+                (region.y_coordinate as f64 * scale).round() as i32,
+            )
+        })
+        .unwrap_or_default();
 
+    let param_counters = counters.clone();
+    let state_counters = counters.clone();
     let (node_id_tx, node_id_rx) = oneshot::channel();
     let mut node_id_tx = Some(node_id_tx);
     let stream_cell: Rc<RefCell<Option<pipewire::stream::Stream>>> = Rc::new(RefCell::new(None));
     let stream_cell_clone = stream_cell.clone();
+    let stream_end_cast = end_cast.clone();
+    // What the consumer saw last, `None` so a consumer that connects gets a whole frame
+    let last_frame = Rc::new(Cell::new(None));
+    let state_last_frame = last_frame.clone();
+
+    let core_listener = core
+        .add_listener_local()
+        .error(move |id, seq, res, message| {
+            tracing::error!("pipewire error on {id}, seq {seq}, res {res}: {message}");
+            // Errors of the core itself, like a lost connection, end this stream
+            if id != pipewire::core::PW_ID_CORE {
+                return;
+            }
+            end_stream(StreamEnd::Disconnected);
+        })
+        .register();
 
     let listener = stream
         .add_local_listener_with_user_data(())
-        .state_changed(move |_, _, old, new| {
+        .state_changed(move |stream, _, old, new| {
             tracing::info!("state-changed '{:?}' -> '{:?}'", old, new);
+            let streaming = matches!(new, StreamState::Streaming);
+            let was_streaming = state_counters.streaming.swap(streaming, Ordering::Relaxed);
+            if streaming {
+                state_last_frame.set(None);
+            }
+            match (was_streaming, streaming) {
+                (false, true) => tracing::info!("a consumer is connected, capture"),
+                (true, false) => tracing::info!("no consumer is connected, stop capturing"),
+                _ => {}
+            }
             match new {
                 StreamState::Paused => {
                     let stream = stream_cell_clone.borrow_mut();
                     let stream = stream.as_ref().unwrap();
+                    node_id.store(stream.node_id(), Ordering::Relaxed);
                     if let Some(node_id_tx) = node_id_tx.take() {
-                        node_id_tx.send(Ok(stream.node_id())).unwrap();
+                        let _ = node_id_tx.send(Ok(stream.node_id()));
                     }
                 }
                 StreamState::Error(e) => {
-                    tracing::error!("Error! : {e}");
+                    tracing::error!("the stream failed, stop casting: {e}");
+                    stream_end_cast(stream.node_id());
                 }
                 _ => {}
             }
         })
-        .param_changed(|_, _, id, pod| {
+        .param_changed(move |stream, _, id, pod| {
             if id != libspa_sys::SPA_PARAM_Format {
                 return;
             }
-            if let Some(pod) = pod {
-                let value = PodDeserializer::deserialize_from::<pod::Value>(pod.as_bytes());
-                tracing::info!("param-changed: {} {:?}", id, value);
+            let Some(pod) = pod else {
+                return;
+            };
+            let value = PodDeserializer::deserialize_from::<pod::Value>(pod.as_bytes());
+            tracing::info!("param-changed: {} {:?}", id, value);
+            let mut info = spa::param::video::VideoInfoRaw::new();
+            if info.parse(pod).is_ok() {
+                tracing::info!("negotiated format: {:?}", info.format());
+                video_format_param.set(info.format());
+                let spa::utils::Fraction { num, denom } = info.framerate();
+                let framerate = if denom == 0 { 0 } else { num / denom };
+                tracing::info!("negotiated framerate: {framerate}");
+                param_counters.framerate.store(framerate, Ordering::Relaxed);
+            }
+            // Without DMA-BUFs the buffers are the shared memory ones offered from the start
+            let Some(dmabuf) = dmabuf_param.as_ref() else {
+                return;
+            };
+            let (width, height) = param_size.get();
+            let shm_formats = || enum_formats(width, height, max_framerate, &param_formats, None);
+            match negotiated_modifiers(pod.as_bytes()) {
+                NegotiatedModifiers::None => update_params(
+                    stream,
+                    &[buffers(width, height, libspa_sys::SPA_DATA_MemFd, 1)],
+                ),
+                // The modifier picked is offered first, for the consumer to agree on it
+                NegotiatedModifiers::ToFixate(modifiers) => {
+                    let fixated = dmabuf.borrow_mut().fixate(&modifiers);
+                    match fixated {
+                        Ok(modifier) => {
+                            let video_format = dmabuf.borrow().video_format();
+                            let offer = dmabuf.borrow().offer((width, height));
+                            let mut params = vec![format(
+                                width,
+                                height,
+                                max_framerate,
+                                &[video_format],
+                                Some(&[modifier]),
+                            )];
+                            params.extend(enum_formats(
+                                width,
+                                height,
+                                max_framerate,
+                                &param_formats,
+                                offer.as_ref(),
+                            ));
+                            update_params(stream, &params);
+                        }
+                        Err(e) => {
+                            tracing::warn!(
+                                "cannot allocate a DMA-BUF the consumer takes, use shared memory: {e}"
+                            );
+                            dmabuf.borrow_mut().fail();
+                            update_params(stream, &shm_formats());
+                        }
+                    }
+                }
+                NegotiatedModifiers::Fixated(modifier) => {
+                    let agreed = dmabuf.borrow_mut().agree(modifier);
+                    match agreed {
+                        Ok(planes) => update_params(
+                            stream,
+                            &[buffers(
+                                width,
+                                height,
+                                libspa_sys::SPA_DATA_DmaBuf,
+                                planes as i32,
+                            )],
+                        ),
+                        Err(e) => {
+                            tracing::warn!(
+                                "cannot allocate a DMA-BUF of the modifier agreed on, use shared memory: {e}"
+                            );
+                            dmabuf.borrow_mut().fail();
+                            update_params(stream, &shm_formats());
+                        }
+                    }
+                }
             }
         })
-        .add_buffer(move |_, _, buffer| {
+        .add_buffer(move |stream, _, buffer| {
+            let (width, height) = buffer_size.get();
             let buf = unsafe { &mut *(*buffer).buffer };
             let datas = unsafe { slice::from_raw_parts_mut(buf.datas, buf.n_datas as usize) };
+            // The type of the data holds the types the buffer may be of, until it is added
+            if let Some(dmabuf) = dmabuf_add.as_ref()
+                && datas
+                    .first()
+                    .is_some_and(|data| data.type_ & (1 << libspa_sys::SPA_DATA_DmaBuf) != 0)
+            {
+                let added = dmabuf.borrow_mut().add_buffer(datas);
+                match added {
+                    Ok(()) => return,
+                    Err(e) => {
+                        // This buffer is in shared memory, until the consumer agrees on it
+                        tracing::warn!("cannot allocate a DMA-BUF, use shared memory: {e}");
+                        dmabuf.borrow_mut().fail();
+                        update_params(
+                            stream,
+                            &enum_formats(width, height, max_framerate, &add_formats, None),
+                        );
+                    }
+                }
+            }
             for data in datas {
                 let name = c"pipewire-screencopy";
                 let fd = rustix::fs::memfd_create(name, rustix::fs::MemfdFlags::CLOEXEC).unwrap();
@@ -139,9 +1365,20 @@ fn start_stream(
 
                 data.type_ = libspa_sys::SPA_DATA_MemFd;
                 data.flags = 0;
-                data.fd = fd.into_raw_fd().into();
 
-                data.data = std::ptr::null_mut();
+                // Mapped here, so frames can be swizzled in place if the consumer wants it
+                data.data = unsafe {
+                    rustix::mm::mmap(
+                        ptr::null_mut(),
+                        (width * height * 4) as usize,
+                        rustix::mm::ProtFlags::READ | rustix::mm::ProtFlags::WRITE,
+                        rustix::mm::MapFlags::SHARED,
+                        &fd,
+                        0,
+                    )
+                }
+                .unwrap_or(ptr::null_mut());
+                data.fd = fd.into_raw_fd().into();
                 data.maxsize = width * height * 4;
                 data.mapoffset = 0;
                 let chunk = unsafe { &mut *data.chunk };
@@ -150,41 +1387,337 @@ fn start_stream(
                 chunk.stride = 4 * width as i32;
             }
         })
-        .remove_buffer(|_, _, buffer| {
+        .remove_buffer(move |_, _, buffer| {
             let buf = unsafe { &mut *(*buffer).buffer };
             let datas = unsafe { slice::from_raw_parts_mut(buf.datas, buf.n_datas as usize) };
+            if let Some(dmabuf) = dmabuf_remove.as_ref()
+                && datas
+                    .first()
+                    .is_some_and(|data| data.type_ == libspa_sys::SPA_DATA_DmaBuf)
+            {
+                dmabuf.borrow_mut().remove_buffer(datas[0].fd);
+            }
 
             for data in datas {
+                if !data.data.is_null() {
+                    let _ = unsafe { rustix::mm::munmap(data.data, data.maxsize as usize) };
+                    data.data = ptr::null_mut();
+                }
                 unsafe { rustix::io::close(data.fd as _) };
                 data.fd = -1;
             }
         })
         .process(move |stream, ()| {
-            if let Some(mut buffer) = stream.dequeue_buffer() {
-                let datas = buffer.datas_mut();
-                let fd = unsafe { BorrowedFd::borrow_raw(datas[0].as_raw().fd as _) };
-                // TODO error
-                connection
-                    .capture_output_frame_shm_fd(overlay_cursor as i32, &output, fd, capture_region)
-                    .unwrap();
+            let buffer = unsafe { stream.dequeue_raw_buffer() };
+            if buffer.is_null() {
+                counters.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            let buf = unsafe { &mut *(*buffer).buffer };
+            let datas = unsafe { slice::from_raw_parts_mut(buf.datas, buf.n_datas as usize) };
+            let chunk = unsafe { &mut *datas[0].chunk };
+            // Frames are only ever captured here, for a consumer asking for them, so a cast
+            // nobody watches costs nothing. A buffer left over once it is gone is not filled
+            if !counters.streaming.load(Ordering::Relaxed) {
+                chunk.size = 0;
+                unsafe { stream.queue_raw_buffer(buffer) };
+                return;
+            }
+            let resized = match output_watch.as_mut().and_then(OutputWatch::poll) {
+                Some(OutputChange::Gone) => {
+                    tracing::warn!("{output_name} is unplugged, stop casting it");
+                    chunk.size = 0;
+                    unsafe { stream.queue_raw_buffer(buffer) };
+                    end_cast(stream.node_id());
+                    return;
+                }
+                Some(OutputChange::Resized { width, height }) if capture_region.is_none() => {
+                    Some((width, height))
+                }
+                _ => None,
+            };
+            let resized = match toplevel_capture.as_mut() {
+                Some(toplevel) => {
+                    if !toplevel.update() {
+                        tracing::warn!("{output_name} is closed, stop casting it");
+                        chunk.size = 0;
+                        unsafe { stream.queue_raw_buffer(buffer) };
+                        end_cast(stream.node_id());
+                        return;
+                    }
+                    Some(toplevel.buffer_size())
+                }
+                None => resized,
+            };
+            match resized {
+                Some((width, height))
+                    if native_size != (width, height) && width != 0 && height != 0 =>
+                {
+                    tracing::info!("{output_name} is now {width}x{height}, renegotiate the size");
+                    native_size = (width, height);
+                    let (width, height) = fit_size(native_size, max_size);
+                    size.set((width, height));
+                    // The compositor may copy the new size into DMA-BUFs of other modifiers
+                    let offer = match (dmabuf_process.as_ref(), &target) {
+                        (Some(dmabuf), CastTarget::Output { output, region }) => {
+                            let mut dmabuf = dmabuf.borrow_mut();
+                            if let Err(e) = dmabuf.probe(output, *region) {
+                                tracing::warn!(
+                                    "cannot copy {output_name} into DMA-BUFs anymore, use shared memory: {e}"
+                                );
+                            }
+                            dmabuf.offer((width, height))
+                        }
+                        _ => None,
+                    };
+                    let params = stream_params(
+                        width,
+                        height,
+                        max_framerate,
+                        &formats,
+                        offer.as_ref(),
+                        cursor_metadata,
+                        transform,
+                    );
+                    update_params(stream, &params);
+                }
+                _ => {}
+            }
+            let (width, height) = size.get();
+            if (datas[0].maxsize as usize) < (width * height * 4) as usize {
+                // The buffers of the former size stay until the new ones are added
+                chunk.size = 0;
+                unsafe { stream.queue_raw_buffer(buffer) };
+                return;
+            }
+            // The framerate agreed on, or the one asked for until then
+            let framerate = match counters.framerate.load(Ordering::Relaxed) {
+                0 => max_framerate,
+                framerate => framerate.min(max_framerate),
+            };
+            // With cursor metadata, buffers without a frame still carry the cursor, which thus
+            // moves as often as the consumer is driven. An empty chunk tells it apart from a
+            // frame, like mutter does
+            if !limiter.is_due(framerate, Instant::now()) {
+                chunk.size = 0;
+                if let Some(cursor) = cursor_capture.as_mut().map(CursorCapture::update) {
+                    unsafe { write_cursor_meta(buf, cursor, cursor_offset) };
+                }
+                unsafe { stream.queue_raw_buffer(buffer) };
+                return;
+            }
+            let fd = unsafe { BorrowedFd::borrow_raw(datas[0].fd as _) };
+            let capture_start = Instant::now();
+            let captured = match &target {
+                CastTarget::Output { output, region }
+                    if datas[0].type_ == libspa_sys::SPA_DATA_DmaBuf =>
+                {
+                    match dmabuf_process.as_ref() {
+                        Some(dmabuf) => dmabuf
+                            .borrow_mut()
+                            .capture(output, *region, overlay_cursor, datas[0].fd)
+                            .map(|()| video_format_process.get()),
+                        None => Err(anyhow::anyhow!("the buffer is not one of the DMA-BUFs")),
+                    }
+                }
+                CastTarget::Output { output, region } if native_size == (width, height) => {
+                    connection
+                        .capture_output_frame_shm_fd(overlay_cursor as i32, output, fd, *region)
+                        .map(|_| native_format)
+                        .map_err(anyhow::Error::from)
+                }
+                CastTarget::Output { output, region } => scratch
+                    .capture(&connection, overlay_cursor, output, *region, native_size)
+                    .and_then(|pixels| unsafe {
+                        write_frame(
+                            &mut pool,
+                            pixels,
+                            native_format,
+                            native_size,
+                            &datas[0],
+                            width,
+                            height,
+                        )
+                    }),
+                CastTarget::Desktop => capture_desktop(&connection, overlay_cursor, native_size)
+                    .and_then(|image| unsafe {
+                        write_frame(
+                            &mut pool,
+                            image.as_raw(),
+                            native_format,
+                            native_size,
+                            &datas[0],
+                            width,
+                            height,
+                        )
+                    }),
+                // A frame of the former size is fitted until one of the new size is copied
+                CastTarget::Toplevel { .. } => match toplevel_capture.as_ref() {
+                    Some(toplevel) if !toplevel.frame().is_empty() => unsafe {
+                        write_frame(
+                            &mut pool,
+                            toplevel.frame(),
+                            native_format,
+                            toplevel.frame_size(),
+                            &datas[0],
+                            width,
+                            height,
+                        )
+                    },
+                    _ => Err(anyhow::anyhow!("no frame of the window is copied yet")),
+                },
+            };
+            let layout = match captured {
+                Ok(layout) => layout,
+                Err(e) => {
+                    // An empty chunk tells the consumer there is no frame this time
+                    tracing::warn!("cannot capture {output_name}: {e}");
+                    counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    chunk.size = 0;
+                    unsafe { stream.queue_raw_buffer(buffer) };
+                    return;
+                }
+            };
+            counters.captured.fetch_add(1, Ordering::Relaxed);
+            let latency = counters.add_latency(capture_start.elapsed());
+            // Warned about once each time the average goes past the threshold
+            let slow = latency > LATENCY_WARNING;
+            if slow && !slow_capture {
+                tracing::warn!("capturing {output_name} takes {latency:?} on average");
+            }
+            slow_capture = slow;
+            // DMA-BUFs are as large as their planes, which may be padded
+            chunk.size = match datas[0].type_ {
+                libspa_sys::SPA_DATA_DmaBuf => datas[0].maxsize,
+                _ => width * height * 4,
+            };
+            if layout != video_format_process.get() && !datas[0].data.is_null() {
+                let pixels = unsafe {
+                    slice::from_raw_parts_mut(datas[0].data as *mut u8, datas[0].maxsize as usize)
+                };
+                convert_layout(pixels, layout, video_format_process.get());
             }
+            if !hidden.is_empty() && !datas[0].data.is_null() {
+                let pixels = unsafe {
+                    slice::from_raw_parts_mut(datas[0].data as *mut u8, chunk.size as usize)
+                };
+                hidden.apply(pixels, width, height, video_format_process.get());
+            }
+            let cursor = cursor_capture.as_mut().map(CursorCapture::update);
+            if skip_unchanged && !datas[0].data.is_null() {
+                let pixels = unsafe {
+                    slice::from_raw_parts(datas[0].data as *const u8, chunk.size as usize)
+                };
+                let frame = frame_hash(pixels);
+                if last_frame.replace(Some(frame)) == Some(frame) {
+                    chunk.size = 0;
+                    if let Some(cursor) = cursor {
+                        unsafe { write_cursor_meta(buf, cursor, cursor_offset) };
+                    }
+                    unsafe { stream.queue_raw_buffer(buffer) };
+                    return;
+                }
+            }
+            if let Some(cursor) = cursor {
+                unsafe { write_cursor_meta(buf, cursor, cursor_offset) };
+            }
+            unsafe { write_transform_meta(buf, transform) };
+            unsafe { write_header_meta(buf, sequence) };
+            sequence += 1;
+            unsafe { stream.queue_raw_buffer(buffer) };
+            counters.delivered.fetch_add(1, Ordering::Relaxed);
         })
         .register()?;
 
-    let format = format(width, height);
-    let buffers = buffers(width, height);
-
-    let params = &mut [
-        pod::Pod::from_bytes(&format).unwrap(),
-        pod::Pod::from_bytes(&buffers).unwrap(),
-    ];
+    let params = stream_params(
+        width,
+        height,
+        max_framerate,
+        &prefer_format(offered_formats(native_format, deep_color), preferred_format),
+        dmabuf
+            .as_ref()
+            .and_then(|dmabuf| dmabuf.borrow().offer((width, height)))
+            .as_ref(),
+        cursor_metadata,
+        transform,
+    );
+    let mut params: Vec<&pod::Pod> = params
+        .iter()
+        .map(|param| pod::Pod::from_bytes(param).unwrap())
+        .collect();
 
     let flags = pipewire::stream::StreamFlags::ALLOC_BUFFERS;
-    stream.connect(pipewire::spa::utils::Direction::Output, None, flags, params)?;
+    stream.connect(
+        pipewire::spa::utils::Direction::Output,
+        None,
+        flags,
+        &mut params,
+    )?;
 
     *stream_cell.borrow_mut() = Some(stream);
 
-    Ok((loop_, listener, context, node_id_rx))
+    Ok((
+        loop_,
+        listener,
+        core_listener,
+        context,
+        stream_cell,
+        node_id_rx,
+        transformed_size(width, height, transform),
+    ))
+}
+
+/// The params of a stream of `width` by `height` frames, serialized.
+///
+/// The buffers are in shared memory until the consumer agrees on a DMA-BUF format.
+#[allow(clippy::too_many_arguments)]
+fn stream_params(
+    width: u32,
+    height: u32,
+    max_framerate: u32,
+    formats: &[VideoFormat],
+    dmabuf: Option<&DmabufOffer>,
+    cursor_metadata: bool,
+    transform: u32,
+) -> Vec<Vec<u8>> {
+    let mut params = enum_formats(width, height, max_framerate, formats, dmabuf);
+    params.push(buffers(width, height, libspa_sys::SPA_DATA_MemFd, 1));
+    params.push(header_meta());
+    if cursor_metadata {
+        params.push(cursor_meta());
+    }
+    if transform != libspa_sys::SPA_META_TRANSFORMATION_None {
+        params.push(transform_meta());
+    }
+    params
+}
+
+/// The `SPA_META_TRANSFORMATION_*` that turns frames of an output with `transform` upright.
+///
+/// Frames are copied the way the output scans them out, so a rotated output gives sideways
+/// frames. Both enums follow the same counter-clockwise order.
+fn video_transform(transform: wl_output::Transform) -> u32 {
+    match transform {
+        wl_output::Transform::_90 => libspa_sys::SPA_META_TRANSFORMATION_90,
+        wl_output::Transform::_180 => libspa_sys::SPA_META_TRANSFORMATION_180,
+        wl_output::Transform::_270 => libspa_sys::SPA_META_TRANSFORMATION_270,
+        wl_output::Transform::Flipped => libspa_sys::SPA_META_TRANSFORMATION_Flipped,
+        wl_output::Transform::Flipped90 => libspa_sys::SPA_META_TRANSFORMATION_Flipped90,
+        wl_output::Transform::Flipped180 => libspa_sys::SPA_META_TRANSFORMATION_Flipped180,
+        wl_output::Transform::Flipped270 => libspa_sys::SPA_META_TRANSFORMATION_Flipped270,
+        _ => libspa_sys::SPA_META_TRANSFORMATION_None,
+    }
+}
+
+/// The size of `width` by `height` frames once turned by `transform`.
+fn transformed_size(width: u32, height: u32, transform: u32) -> (u32, u32) {
+    match transform {
+        libspa_sys::SPA_META_TRANSFORMATION_90
+        | libspa_sys::SPA_META_TRANSFORMATION_270
+        | libspa_sys::SPA_META_TRANSFORMATION_Flipped90
+        | libspa_sys::SPA_META_TRANSFORMATION_Flipped270 => (height, width),
+        _ => (width, height),
+    }
 }
 
 fn value_to_bytes(value: pod::Value) -> Vec<u8> {
@@ -194,24 +1727,24 @@ fn value_to_bytes(value: pod::Value) -> Vec<u8> {
     bytes
 }
 
-fn buffers(width: u32, height: u32) -> Vec<u8> {
+/// The buffers of `width` by `height` frames, of the `data_type` and in as many `blocks` as
+/// the format takes planes.
+fn buffers(width: u32, height: u32, data_type: u32, blocks: i32) -> Vec<u8> {
     value_to_bytes(pod::Value::Object(pod::Object {
         type_: libspa_sys::SPA_TYPE_OBJECT_ParamBuffers,
         id: libspa_sys::SPA_PARAM_Buffers,
         properties: vec![
-            /*
             pod::Property {
-                key: spa_sys::SPA_PARAM_BUFFERS_dataType,
+                key: libspa_sys::SPA_PARAM_BUFFERS_dataType,
                 flags: pod::PropertyFlags::empty(),
                 value: pod::Value::Choice(pod::ChoiceValue::Int(spa::utils::Choice(
                     spa::utils::ChoiceFlags::empty(),
                     spa::utils::ChoiceEnum::Flags {
-                        default: 1 << spa_sys::SPA_DATA_MemFd,
+                        default: 1 << data_type,
                         flags: vec![],
                     },
                 ))),
             },
-            */
             pod::Property {
                 key: libspa_sys::SPA_PARAM_BUFFERS_size,
                 flags: pod::PropertyFlags::empty(),
@@ -230,7 +1763,7 @@ fn buffers(width: u32, height: u32) -> Vec<u8> {
             pod::Property {
                 key: libspa_sys::SPA_PARAM_BUFFERS_blocks,
                 flags: pod::PropertyFlags::empty(),
-                value: pod::Value::Int(1),
+                value: pod::Value::Int(blocks),
             },
             pod::Property {
                 key: libspa_sys::SPA_PARAM_BUFFERS_buffers,
@@ -248,6 +1781,181 @@ fn buffers(width: u32, height: u32) -> Vec<u8> {
     }))
 }
 
+fn cursor_meta_size(width: u32, height: u32) -> i32 {
+    (mem::size_of::<libspa_sys::spa_meta_cursor>()
+        + mem::size_of::<libspa_sys::spa_meta_bitmap>()
+        + (width * height * 4) as usize) as i32
+}
+
+fn cursor_meta() -> Vec<u8> {
+    value_to_bytes(pod::Value::Object(pod::Object {
+        type_: libspa_sys::SPA_TYPE_OBJECT_ParamMeta,
+        id: libspa_sys::SPA_PARAM_Meta,
+        properties: vec![
+            pod::Property {
+                key: libspa_sys::SPA_PARAM_META_type,
+                flags: pod::PropertyFlags::empty(),
+                value: pod::Value::Id(spa::utils::Id(libspa_sys::SPA_META_Cursor)),
+            },
+            pod::Property {
+                key: libspa_sys::SPA_PARAM_META_size,
+                flags: pod::PropertyFlags::empty(),
+                value: pod::Value::Choice(pod::ChoiceValue::Int(spa::utils::Choice(
+                    spa::utils::ChoiceFlags::empty(),
+                    spa::utils::ChoiceEnum::Range {
+                        default: cursor_meta_size(64, 64),
+                        min: cursor_meta_size(1, 1),
+                        max: cursor_meta_size(CURSOR_BITMAP_MAX_SIZE, CURSOR_BITMAP_MAX_SIZE),
+                    },
+                ))),
+            },
+        ],
+    }))
+}
+
+/// Fill the cursor metadata of `buf`, if the consumer agreed to receive it.
+///
+/// # Safety
+///
+/// `buf` must be a buffer dequeued from the stream, with valid metas.
+unsafe fn write_cursor_meta(
+    buf: &mut libspa_sys::spa_buffer,
+    cursor: &CursorState,
+    (offset_x, offset_y): (i32, i32),
+) {
+    let metas = unsafe { slice::from_raw_parts_mut(buf.metas, buf.n_metas as usize) };
+    let Some(meta) = metas.iter_mut().find(|meta| {
+        meta.type_ == libspa_sys::SPA_META_Cursor
+            && meta.size as usize >= mem::size_of::<libspa_sys::spa_meta_cursor>()
+    }) else {
+        return;
+    };
+    let meta_cursor = unsafe { &mut *(meta.data as *mut libspa_sys::spa_meta_cursor) };
+    let Some((x, y)) = cursor.position else {
+        // An id of 0 tells the consumer there is no cursor to draw
+        meta_cursor.id = 0;
+        return;
+    };
+    meta_cursor.id = 1;
+    meta_cursor.flags = 0;
+    // The position is the one of the hotspot, consumers draw the bitmap at position - hotspot
+    meta_cursor.position = libspa_sys::spa_point {
+        x: x - offset_x,
+        y: y - offset_y,
+    };
+    meta_cursor.hotspot = libspa_sys::spa_point {
+        x: cursor.hotspot.0,
+        y: cursor.hotspot.1,
+    };
+    meta_cursor.bitmap_offset = 0;
+
+    let Some(bitmap) = cursor.bitmap.as_ref() else {
+        return;
+    };
+    let bitmap_offset = mem::size_of::<libspa_sys::spa_meta_cursor>();
+    let pixels_offset = mem::size_of::<libspa_sys::spa_meta_bitmap>();
+    if (meta.size as usize) < bitmap_offset + pixels_offset + bitmap.pixels.len() {
+        return;
+    }
+    meta_cursor.bitmap_offset = bitmap_offset as u32;
+    let bitmap_ptr = unsafe { (meta.data as *mut u8).add(bitmap_offset) };
+    let meta_bitmap = unsafe { &mut *(bitmap_ptr as *mut libspa_sys::spa_meta_bitmap) };
+    meta_bitmap.format = bitmap.format;
+    meta_bitmap.size = libspa_sys::spa_rectangle {
+        width: bitmap.width,
+        height: bitmap.height,
+    };
+    meta_bitmap.stride = (bitmap.width * 4) as i32;
+    meta_bitmap.offset = pixels_offset as u32;
+    unsafe {
+        ptr::copy_nonoverlapping(
+            bitmap.pixels.as_ptr(),
+            bitmap_ptr.add(pixels_offset),
+            bitmap.pixels.len(),
+        )
+    };
+}
+
+fn transform_meta() -> Vec<u8> {
+    value_to_bytes(pod::Value::Object(pod::Object {
+        type_: libspa_sys::SPA_TYPE_OBJECT_ParamMeta,
+        id: libspa_sys::SPA_PARAM_Meta,
+        properties: vec![
+            pod::Property {
+                key: libspa_sys::SPA_PARAM_META_type,
+                flags: pod::PropertyFlags::empty(),
+                value: pod::Value::Id(spa::utils::Id(libspa_sys::SPA_META_VideoTransform)),
+            },
+            pod::Property {
+                key: libspa_sys::SPA_PARAM_META_size,
+                flags: pod::PropertyFlags::empty(),
+                value: pod::Value::Int(mem::size_of::<libspa_sys::spa_meta_videotransform>() as i32),
+            },
+        ],
+    }))
+}
+
+/// Tell how the frame in `buf` is to be turned, if the consumer agreed to receive it.
+///
+/// # Safety
+///
+/// `buf` must be a buffer dequeued from the stream, with valid metas.
+unsafe fn write_transform_meta(buf: &mut libspa_sys::spa_buffer, transform: u32) {
+    let metas = unsafe { slice::from_raw_parts_mut(buf.metas, buf.n_metas as usize) };
+    let Some(meta) = metas.iter_mut().find(|meta| {
+        meta.type_ == libspa_sys::SPA_META_VideoTransform
+            && meta.size as usize >= mem::size_of::<libspa_sys::spa_meta_videotransform>()
+    }) else {
+        return;
+    };
+    let meta_transform = unsafe { &mut *(meta.data as *mut libspa_sys::spa_meta_videotransform) };
+    meta_transform.transform = transform;
+}
+
+fn header_meta() -> Vec<u8> {
+    value_to_bytes(pod::Value::Object(pod::Object {
+        type_: libspa_sys::SPA_TYPE_OBJECT_ParamMeta,
+        id: libspa_sys::SPA_PARAM_Meta,
+        properties: vec![
+            pod::Property {
+                key: libspa_sys::SPA_PARAM_META_type,
+                flags: pod::PropertyFlags::empty(),
+                value: pod::Value::Id(spa::utils::Id(libspa_sys::SPA_META_Header)),
+            },
+            pod::Property {
+                key: libspa_sys::SPA_PARAM_META_size,
+                flags: pod::PropertyFlags::empty(),
+                value: pod::Value::Int(mem::size_of::<libspa_sys::spa_meta_header>() as i32),
+            },
+        ],
+    }))
+}
+
+/// Stamp the frame in `buf` with the time it is sent and its `sequence` number.
+///
+/// The time is taken from the monotonic clock, like the one of audio streams, so it never
+/// goes back, even over a pause.
+///
+/// # Safety
+///
+/// `buf` must be a buffer dequeued from the stream, with valid metas.
+unsafe fn write_header_meta(buf: &mut libspa_sys::spa_buffer, sequence: u64) {
+    let metas = unsafe { slice::from_raw_parts_mut(buf.metas, buf.n_metas as usize) };
+    let Some(meta) = metas.iter_mut().find(|meta| {
+        meta.type_ == libspa_sys::SPA_META_Header
+            && meta.size as usize >= mem::size_of::<libspa_sys::spa_meta_header>()
+    }) else {
+        return;
+    };
+    let now = rustix::time::clock_gettime(rustix::time::ClockId::Monotonic);
+    let header = unsafe { &mut *(meta.data as *mut libspa_sys::spa_meta_header) };
+    header.flags = 0;
+    header.offset = 0;
+    header.pts = now.tv_sec * 1_000_000_000 + now.tv_nsec;
+    header.dts_offset = 0;
+    header.seq = sequence;
+}
+
 #[allow(unused)]
 fn buffers2(width: u32, height: u32) -> Vec<u8> {
     value_to_bytes(pod::Value::Object(spa::pod::object!(
@@ -256,8 +1964,41 @@ fn buffers2(width: u32, height: u32) -> Vec<u8> {
     )))
 }
 
-fn format(width: u32, height: u32) -> Vec<u8> {
-    value_to_bytes(pod::Value::Object(spa::pod::object!(
+/// The EnumFormat params of `width` by `height` frames: the DMA-BUFs of `dmabuf` first,
+/// then shared memory in any of `formats`, for the consumers that cannot take them.
+fn enum_formats(
+    width: u32,
+    height: u32,
+    max_framerate: u32,
+    formats: &[VideoFormat],
+    dmabuf: Option<&DmabufOffer>,
+) -> Vec<Vec<u8>> {
+    let mut params = Vec::new();
+    if let Some(dmabuf) = dmabuf {
+        params.push(format(
+            width,
+            height,
+            max_framerate,
+            &[dmabuf.format],
+            Some(&dmabuf.modifiers),
+        ));
+    }
+    params.push(format(width, height, max_framerate, formats, None));
+    params
+}
+
+/// An EnumFormat param, of DMA-BUFs with one of `modifiers` when there are some.
+///
+/// A single modifier is the one picked, more are left for the consumer to narrow down and for
+/// us to pick from then, see [`negotiated_modifiers`].
+fn format(
+    width: u32,
+    height: u32,
+    max_framerate: u32,
+    formats: &[VideoFormat],
+    modifiers: Option<&[u64]>,
+) -> Vec<u8> {
+    let mut object = spa::pod::object!(
         spa::utils::SpaTypes::ObjectParamFormat,
         spa::param::ParamType::EnumFormat,
         spa::pod::property!(
@@ -270,15 +2011,20 @@ fn format(width: u32, height: u32) -> Vec<u8> {
             Id,
             spa::param::format::MediaSubtype::Raw
         ),
-        spa::pod::property!(
-            spa::param::format::FormatProperties::VideoFormat,
-            Choice,
-            Enum,
-            Id,
-            spa::param::video::VideoFormat::RGBA,
-            spa::param::video::VideoFormat::RGBA,
-        ),
-        // XXX modifiers
+        pod::Property {
+            key: spa::param::format::FormatProperties::VideoFormat.as_raw(),
+            flags: pod::PropertyFlags::empty(),
+            value: pod::Value::Choice(pod::ChoiceValue::Id(spa::utils::Choice(
+                spa::utils::ChoiceFlags::empty(),
+                spa::utils::ChoiceEnum::Enum {
+                    default: spa::utils::Id(formats[0].as_raw()),
+                    alternatives: formats
+                        .iter()
+                        .map(|format| spa::utils::Id(format.as_raw()))
+                        .collect(),
+                },
+            ))),
+        },
         spa::pod::property!(
             spa::param::format::FormatProperties::VideoSize,
             Choice,
@@ -293,10 +2039,470 @@ fn format(width: u32, height: u32) -> Vec<u8> {
             Choice,
             Range,
             Fraction,
-            spa::utils::Fraction { num: 60, denom: 1 },
-            spa::utils::Fraction { num: 60, denom: 1 },
-            spa::utils::Fraction { num: 60, denom: 1 }
+            spa::utils::Fraction {
+                num: max_framerate,
+                denom: 1
+            },
+            spa::utils::Fraction { num: 1, denom: 1 },
+            spa::utils::Fraction {
+                num: max_framerate,
+                denom: 1
+            }
         ),
         // TODO max framerate
-    )))
+    );
+    match modifiers {
+        Some(&[modifier]) => object.properties.push(pod::Property {
+            key: libspa_sys::SPA_FORMAT_VIDEO_modifier,
+            flags: pod::PropertyFlags::MANDATORY,
+            value: pod::Value::Long(modifier as i64),
+        }),
+        Some(modifiers) => object.properties.push(pod::Property {
+            key: libspa_sys::SPA_FORMAT_VIDEO_modifier,
+            flags: pod::PropertyFlags::MANDATORY | pod::PropertyFlags::DONT_FIXATE,
+            value: pod::Value::Choice(pod::ChoiceValue::Long(spa::utils::Choice(
+                spa::utils::ChoiceFlags::empty(),
+                spa::utils::ChoiceEnum::Enum {
+                    default: modifiers[0] as i64,
+                    alternatives: modifiers.iter().map(|&modifier| modifier as i64).collect(),
+                },
+            ))),
+        }),
+        None => {}
+    }
+    value_to_bytes(pod::Value::Object(object))
+}
+
+/// What the format a consumer agreed on tells of DMA-BUFs.
+#[derive(Debug, PartialEq, Eq)]
+enum NegotiatedModifiers {
+    /// The format is not of DMA-BUFs, the frames go in shared memory
+    None,
+    /// The consumer takes any of these modifiers, for us to pick one
+    ToFixate(Vec<u64>),
+    /// The modifier is picked
+    Fixated(u64),
+}
+
+/// The modifiers of the negotiated `format` pod.
+fn negotiated_modifiers(format: &[u8]) -> NegotiatedModifiers {
+    let Ok((_, pod::Value::Object(object))) =
+        PodDeserializer::deserialize_from::<pod::Value>(format)
+    else {
+        return NegotiatedModifiers::None;
+    };
+    let Some(property) = object
+        .properties
+        .into_iter()
+        .find(|property| property.key == libspa_sys::SPA_FORMAT_VIDEO_modifier)
+    else {
+        return NegotiatedModifiers::None;
+    };
+    let to_fixate = property.flags.contains(pod::PropertyFlags::DONT_FIXATE);
+    match property.value {
+        pod::Value::Long(modifier) => NegotiatedModifiers::Fixated(modifier as u64),
+        pod::Value::Choice(pod::ChoiceValue::Long(spa::utils::Choice(_, choice))) => {
+            let modifiers = match choice {
+                spa::utils::ChoiceEnum::None(modifier) => vec![modifier],
+                spa::utils::ChoiceEnum::Enum {
+                    default,
+                    alternatives,
+                } => [default].into_iter().chain(alternatives).collect(),
+                _ => return NegotiatedModifiers::None,
+            };
+            let mut modifiers: Vec<u64> = modifiers
+                .into_iter()
+                .map(|modifier| modifier as u64)
+                .collect();
+            // The default is listed among the alternatives again
+            modifiers.sort_unstable();
+            modifiers.dedup();
+            match modifiers[..] {
+                [modifier] if !to_fixate => NegotiatedModifiers::Fixated(modifier),
+                _ => NegotiatedModifiers::ToFixate(modifiers),
+            }
+        }
+        _ => NegotiatedModifiers::None,
+    }
+}
+
+/// Replace the params of `stream` with the serialized `params` of the same ids.
+fn update_params(stream: &pipewire::stream::StreamRef, params: &[Vec<u8>]) {
+    let mut params: Vec<&pod::Pod> = params
+        .iter()
+        .map(|param| pod::Pod::from_bytes(param).unwrap())
+        .collect();
+    if let Err(e) = stream.update_params(&mut params) {
+        tracing::warn!("cannot update the params of the stream: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use libwayshot::WayshotConnection;
+    use libwayshot::output::OutputInfo;
+    use pipewire::spa::param::video::VideoFormat;
+
+    use wayland_client::protocol::wl_shm;
+
+    use super::dmabuf::{DmabufOffer, fourcc_video_format, shareable_modifiers};
+    use super::{
+        FrameLimiter, FramePool, NegotiatedModifiers, convert_layout, enum_formats, fit_size,
+        format, is_10bit, narrow_10bit, negotiated_modifiers, shm_video_format, write_frame,
+    };
+    use crate::testing::allocations;
+    use crate::utils::connect_wayshot;
+
+    /// A pixel of 10 bit red, green and blue channels, laid out as `format`, with an alpha of
+    /// 2 out of 3 when it has one.
+    fn pixel_10bit(format: VideoFormat, (red, green, blue): (u32, u32, u32)) -> [u8; 4] {
+        let value = match format {
+            VideoFormat::ABGR_210LE | VideoFormat::xBGR_210LE => (blue << 20) | (green << 10) | red,
+            _ => (red << 20) | (green << 10) | blue,
+        };
+        let alpha = match format {
+            VideoFormat::ARGB_210LE | VideoFormat::ABGR_210LE => 2 << 30,
+            _ => 0,
+        };
+        (value | alpha).to_le_bytes()
+    }
+
+    /// The channels each pixel of the frames below has, in 10 and 8 bits
+    const CHANNELS_10BIT: (u32, u32, u32) = (0x3ff, 0x200, 0x004);
+    const CHANNELS: [u8; 3] = [0xff, 0x80, 0x01];
+
+    /// A buffer of the stream, at `buffer`.
+    fn spa_data(buffer: &mut [u8]) -> libspa_sys::spa_data {
+        let mut data: libspa_sys::spa_data = unsafe { std::mem::zeroed() };
+        data.data = buffer.as_mut_ptr().cast();
+        data.maxsize = buffer.len() as u32;
+        data
+    }
+
+    /// Copy `pixels`, `native` sized and laid out as `format`, into a `width` by `height`
+    /// buffer, in the `target` layout, the way a frame is sent.
+    fn send_frame(
+        pixels: &[u8],
+        format: VideoFormat,
+        native: (u32, u32),
+        (width, height): (u32, u32),
+        target: VideoFormat,
+    ) -> Vec<u8> {
+        let mut buffer = vec![0; (width * height * 4) as usize];
+        let data = spa_data(&mut buffer);
+        let mut pool = FramePool::default();
+        let layout =
+            unsafe { write_frame(&mut pool, pixels, format, native, &data, width, height) }
+                .expect("cannot write the frame");
+        convert_layout(&mut buffer, layout, target);
+        buffer
+    }
+
+    #[test]
+    fn frames_come_out_in_the_layout_picked() {
+        // Four pixels told apart by their channels, sent at their size
+        let frame = [
+            [0x10, 0x20, 0x30, 0x40],
+            [0x50, 0x60, 0x70, 0x80],
+            [0x90, 0xa0, 0xb0, 0xc0],
+            [0xd0, 0xe0, 0xf0, 0xff],
+        ]
+        .concat();
+        let golden = [
+            [0x30, 0x20, 0x10, 0x40],
+            [0x70, 0x60, 0x50, 0x80],
+            [0xb0, 0xa0, 0x90, 0xc0],
+            [0xf0, 0xe0, 0xd0, 0xff],
+        ]
+        .concat();
+        let sent = send_frame(&frame, VideoFormat::BGRA, (2, 2), (2, 2), VideoFormat::RGBA);
+        assert_eq!(sent, golden);
+        let sent = send_frame(&frame, VideoFormat::BGRA, (2, 2), (2, 2), VideoFormat::BGRA);
+        assert_eq!(sent, frame);
+
+        // A 10 bit frame, shrunk, which narrows it first
+        let frame = pixel_10bit(VideoFormat::xRGB_210LE, CHANNELS_10BIT).repeat(4 * 4);
+        let [red, green, blue] = CHANNELS;
+        let golden = [blue, green, red, 0xff].repeat(2 * 2);
+        let sent = send_frame(
+            &frame,
+            VideoFormat::xRGB_210LE,
+            (4, 4),
+            (2, 2),
+            VideoFormat::BGRx,
+        );
+        assert_eq!(sent, golden);
+    }
+
+    #[test]
+    fn frames_are_fitted_in_the_largest_size_asked_for() {
+        assert_eq!(fit_size((3840, 2160), (Some(1920), None)), (1920, 1080));
+        assert_eq!(fit_size((3840, 2160), (None, Some(1080))), (1920, 1080));
+        assert_eq!(fit_size((1280, 720), (Some(640), Some(640))), (640, 360));
+        assert_eq!(fit_size((1280, 720), (Some(1280), Some(180))), (320, 180));
+        // Never grown, and never shrunk to nothing
+        assert_eq!(fit_size((1280, 720), (None, None)), (1280, 720));
+        assert_eq!(fit_size((1280, 720), (Some(3840), Some(2160))), (1280, 720));
+        assert_eq!(fit_size((1000, 1), (Some(10), None)), (10, 1));
+        assert_eq!(fit_size((0, 0), (Some(10), Some(10))), (0, 0));
+    }
+
+    #[test]
+    fn frames_of_10_bits_are_narrowed_to_8() {
+        let formats = [
+            VideoFormat::ARGB_210LE,
+            VideoFormat::xRGB_210LE,
+            VideoFormat::ABGR_210LE,
+            VideoFormat::xBGR_210LE,
+        ];
+        assert!(formats.into_iter().all(is_10bit));
+        assert!(!is_10bit(VideoFormat::BGRx));
+        assert!(!is_10bit(VideoFormat::RGBA));
+
+        let [red, green, blue] = CHANNELS;
+        for format in formats {
+            let has_alpha = matches!(format, VideoFormat::ARGB_210LE | VideoFormat::ABGR_210LE);
+            let alpha = if has_alpha { 0xaa } else { 0xff };
+            let mut pixel = pixel_10bit(format, CHANNELS_10BIT);
+            narrow_10bit(&mut pixel, format);
+            assert_eq!(pixel, [red, green, blue, alpha], "{format:?}");
+
+            let mut pixel = pixel_10bit(format, CHANNELS_10BIT);
+            convert_layout(&mut pixel, format, VideoFormat::BGRA);
+            assert_eq!(pixel, [blue, green, red, alpha], "{format:?}");
+
+            // Kept as they are for the consumers that take them
+            let mut pixel = pixel_10bit(format, CHANNELS_10BIT);
+            convert_layout(&mut pixel, format, format);
+            assert_eq!(pixel, pixel_10bit(format, CHANNELS_10BIT), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn frames_are_spaced_at_the_framerate() {
+        let start = Instant::now();
+        // Called at 60 Hz, for 30 frames a second
+        let cycle = Duration::from_secs(1) / 60;
+        let at = |cycles: u32| start + cycle * cycles;
+        let mut limiter = FrameLimiter::new();
+        let due: Vec<bool> = (0..8).map(|n| limiter.is_due(30, at(n))).collect();
+        assert_eq!(due, [true, false, true, false, true, false, true, false]);
+
+        // A new framerate starts over with a frame right away
+        assert!(limiter.is_due(60, at(8)));
+        assert!(limiter.is_due(60, at(9)));
+
+        // A frame late by a while is not followed by a burst
+        assert!(limiter.is_due(30, at(10)));
+        assert!(limiter.is_due(30, at(30)));
+        assert!(!limiter.is_due(30, at(31)));
+        assert!(limiter.is_due(30, at(32)));
+    }
+
+    #[test]
+    fn frames_are_shrunk_like_the_image_crate_does() {
+        // Channels all over the place, at a ratio that does not divide evenly
+        let native = (37, 23);
+        let frame: Vec<u8> = (0..native.0 * native.1 * 4)
+            .map(|channel| (channel * 7 % 251) as u8)
+            .collect();
+        let (width, height) = (16, 9);
+        let image =
+            image::ImageBuffer::<image::Rgba<u8>, &[u8]>::from_raw(native.0, native.1, &frame)
+                .unwrap();
+        let golden =
+            image::imageops::resize(&image, width, height, image::imageops::FilterType::Triangle);
+        let sent = send_frame(
+            &frame,
+            VideoFormat::RGBA,
+            native,
+            (width, height),
+            VideoFormat::RGBA,
+        );
+        assert_eq!(sent.len(), golden.as_raw().len());
+        // Only rounding sets them apart
+        for (channel, (sent, golden)) in sent.iter().zip(golden.as_raw()).enumerate() {
+            assert!(
+                sent.abs_diff(*golden) <= 1,
+                "channel {channel} is {sent} instead of {golden}"
+            );
+        }
+    }
+
+    /// How large the frames the benchmark below shrinks are, and to what
+    const POOL_NATIVE: (u32, u32) = (640, 360);
+    const POOL_SIZE: (u32, u32) = (320, 180);
+    /// How many frames it counts the allocations of
+    const POOL_FRAMES: u32 = 30;
+
+    /// How many allocations a second it takes to narrow and shrink `POOL_FRAMES` 10 bit
+    /// frames, through a pool made for each frame as it was before frames were pooled, or
+    /// through one kept from frame to frame. Both make a frame first, which is not counted.
+    fn shrink_allocations_per_second(keep_pool: bool) -> f64 {
+        let format = VideoFormat::xRGB_210LE;
+        let frame =
+            pixel_10bit(format, CHANNELS_10BIT).repeat((POOL_NATIVE.0 * POOL_NATIVE.1) as usize);
+        let (width, height) = POOL_SIZE;
+        let mut buffer = vec![0; (width * height * 4) as usize];
+        let data = spa_data(&mut buffer);
+        let mut pool = FramePool::default();
+        let send = |pool: &mut FramePool| {
+            unsafe { write_frame(pool, &frame, format, POOL_NATIVE, &data, width, height) }
+                .expect("cannot write the frame");
+        };
+        send(&mut pool);
+        let start = Instant::now();
+        let allocated = allocations();
+        for _ in 0..POOL_FRAMES {
+            if !keep_pool {
+                pool = FramePool::default();
+            }
+            send(&mut pool);
+        }
+        (allocations() - allocated) as f64 / start.elapsed().as_secs_f64()
+    }
+
+    #[test]
+    fn frames_are_shrunk_without_allocating() {
+        let before = shrink_allocations_per_second(false);
+        let after = shrink_allocations_per_second(true);
+        assert!(before > 0., "no allocation is counted without a pool");
+        assert_eq!(
+            after, 0.,
+            "{after:.0} allocations a second with the pool, {before:.0} without it"
+        );
+    }
+
+    #[test]
+    fn dmabufs_are_cast_in_the_formats_of_shared_memory() {
+        let formats = [
+            (wl_shm::Format::Argb8888, gbm::Format::Argb8888),
+            (wl_shm::Format::Xrgb8888, gbm::Format::Xrgb8888),
+            (wl_shm::Format::Abgr8888, gbm::Format::Abgr8888),
+            (wl_shm::Format::Xbgr8888, gbm::Format::Xbgr8888),
+            (wl_shm::Format::Argb2101010, gbm::Format::Argb2101010),
+            (wl_shm::Format::Xrgb2101010, gbm::Format::Xrgb2101010),
+            (wl_shm::Format::Abgr2101010, gbm::Format::Abgr2101010),
+            (wl_shm::Format::Xbgr2101010, gbm::Format::Xbgr2101010),
+        ];
+        for (shm, fourcc) in formats {
+            assert!(
+                fourcc_video_format(fourcc).is_some(),
+                "{fourcc} is not cast"
+            );
+            assert_eq!(
+                fourcc_video_format(fourcc),
+                shm_video_format(shm),
+                "{fourcc}"
+            );
+        }
+        assert_eq!(fourcc_video_format(gbm::Format::Nv12), None);
+    }
+
+    #[test]
+    fn dmabufs_are_linear_when_the_consumer_may_be_on_another_gpu() {
+        let linear = u64::from(gbm::Modifier::Linear);
+        let tiled = u64::from(gbm::Modifier::I915_x_tiled);
+        assert_eq!(
+            shareable_modifiers(vec![linear, tiled], false),
+            [linear, tiled]
+        );
+        assert_eq!(shareable_modifiers(vec![linear, tiled], true), [linear]);
+        // Such a compositor casts in shared memory then
+        assert!(shareable_modifiers(vec![tiled], true).is_empty());
+    }
+
+    #[test]
+    fn dmabufs_are_offered_before_shared_memory() {
+        let formats = [VideoFormat::BGRx, VideoFormat::RGBx];
+        let offer = DmabufOffer {
+            format: VideoFormat::BGRx,
+            modifiers: vec![0],
+        };
+        let params = enum_formats(64, 32, 60, &formats, Some(&offer));
+        let modifiers: Vec<_> = params
+            .iter()
+            .map(|param| negotiated_modifiers(param))
+            .collect();
+        assert_eq!(
+            modifiers,
+            [NegotiatedModifiers::Fixated(0), NegotiatedModifiers::None]
+        );
+        assert_eq!(enum_formats(64, 32, 60, &formats, None).len(), 1);
+    }
+
+    #[test]
+    fn dmabuf_modifiers_are_fixated_once_one_is_picked() {
+        let formats = [VideoFormat::BGRx];
+        let offered = format(64, 32, 60, &formats, Some(&[2, 0, 1]));
+        assert_eq!(
+            negotiated_modifiers(&offered),
+            NegotiatedModifiers::ToFixate(vec![0, 1, 2])
+        );
+        let picked = format(64, 32, 60, &formats, Some(&[1]));
+        assert_eq!(
+            negotiated_modifiers(&picked),
+            NegotiatedModifiers::Fixated(1)
+        );
+        let shm = format(64, 32, 60, &formats, None);
+        assert_eq!(negotiated_modifiers(&shm), NegotiatedModifiers::None);
+    }
+
+    /// How many frames of each output a run copies
+    const BENCH_FRAMES: u32 = 120;
+
+    /// Copy `BENCH_FRAMES` frames of `output` into shm, like a cast does.
+    fn copy_frames(connection: &WayshotConnection, output: &OutputInfo) {
+        let fd =
+            rustix::fs::memfd_create(c"pipewire-bench", rustix::fs::MemfdFlags::CLOEXEC).unwrap();
+        let (width, height) = (output.mode.width.max(1), output.mode.height.max(1));
+        rustix::fs::ftruncate(&fd, (width * height * 4) as _).unwrap();
+        for _ in 0..BENCH_FRAMES {
+            connection
+                .capture_output_frame_shm_fd(0, &output.wl_output, &fd, None)
+                .unwrap();
+        }
+    }
+
+    /// Compares copying the frames of every output one after another, on one thread, with
+    /// copying each on a thread of its own as the casts do. Run it on a compositor with two 4K
+    /// outputs with `cargo test --release -- --ignored capture_side_by_side`.
+    #[test]
+    #[ignore = "needs a wlroots compositor with several outputs"]
+    fn capture_side_by_side() {
+        let connection = connect_wayshot().unwrap();
+        let outputs = connection.get_all_outputs().clone();
+        assert!(outputs.len() >= 2, "it takes several outputs to compare");
+
+        let start = Instant::now();
+        for output in &outputs {
+            copy_frames(&connection, output);
+        }
+        let one_thread = start.elapsed();
+
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for output in &outputs {
+                scope.spawn(move || {
+                    let connection = connect_wayshot().unwrap();
+                    let output = connection
+                        .get_all_outputs()
+                        .iter()
+                        .find(|info| info.name == output.name)
+                        .unwrap();
+                    copy_frames(&connection, output);
+                });
+            }
+        });
+        let side_by_side = start.elapsed();
+
+        assert!(
+            side_by_side <= one_thread,
+            "copying {} frames on a thread each takes {side_by_side:?}, \
+             against {one_thread:?} on one thread",
+            BENCH_FRAMES as usize * outputs.len()
+        );
+    }
 }
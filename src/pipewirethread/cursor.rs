@@ -0,0 +1,457 @@
+use std::{fs::File, os::fd::AsFd, os::unix::fs::FileExt};
+
+use libwayshot::{WayshotConnection, reexport::WlOutput};
+use wayland_client::{
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum, delegate_noop,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_pointer::WlPointer,
+        wl_seat::WlSeat,
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
+    },
+};
+use wayland_protocols::ext::{
+    image_capture_source::v1::client::{
+        ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+        ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+    },
+    image_copy_capture::v1::client::{
+        ext_image_copy_capture_cursor_session_v1::{self, ExtImageCopyCaptureCursorSessionV1},
+        ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1},
+        ext_image_copy_capture_manager_v1::ExtImageCopyCaptureManagerV1,
+        ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+    },
+};
+
+use super::CURSOR_BITMAP_MAX_SIZE;
+
+/// The cursor image, as tightly packed pixels
+#[derive(Debug, Clone)]
+pub struct CursorBitmap {
+    pub format: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct CursorState {
+    /// Hotspot position in buffer pixels of the captured output, `None` when the cursor is
+    /// not over it
+    pub position: Option<(i32, i32)>,
+    pub hotspot: (i32, i32),
+    pub bitmap: Option<CursorBitmap>,
+
+    pending_hotspot: (i32, i32),
+    buffer_size: (u32, u32),
+    shm_formats: Vec<wl_shm::Format>,
+    constraints_done: bool,
+    frame_ready: Option<bool>,
+    stopped: bool,
+}
+
+struct CursorFrame {
+    frame: ExtImageCopyCaptureFrameV1,
+    buffer: WlBuffer,
+    pool: WlShmPool,
+    file: File,
+    format: wl_shm::Format,
+    size: (u32, u32),
+}
+
+/// Follows the cursor over one output through an ext-image-copy-capture cursor session,
+/// without ever painting it into the captured frames.
+///
+/// The compositor hands out the cursor at the size it draws it, which a `scale` enlarges or
+/// shrinks for the consumer, up to [`CURSOR_BITMAP_MAX_SIZE`]. That is also the case of the
+/// shapes clients name with cursor-shape-v1, which only the compositor ever sees by name.
+/// When it cannot hand out the image, the arrow of the cursor theme is sent instead, see
+/// [`fallback_arrow`].
+pub struct CursorCapture {
+    event_queue: EventQueue<CursorState>,
+    qh: QueueHandle<CursorState>,
+    state: CursorState,
+    shm: WlShm,
+    session: ExtImageCopyCaptureCursorSessionV1,
+    capture_session: ExtImageCopyCaptureSessionV1,
+    source: ExtImageCaptureSourceV1,
+    pointer: WlPointer,
+    frame: Option<CursorFrame>,
+    scale: f64,
+}
+
+impl CursorCapture {
+    pub fn new(
+        connection: &WayshotConnection,
+        output: &WlOutput,
+        scale: f64,
+    ) -> anyhow::Result<Self> {
+        let conn: &Connection = &connection.conn;
+        let mut event_queue = conn.new_event_queue::<CursorState>();
+        let qh = event_queue.handle();
+
+        let source_manager = connection
+            .globals
+            .bind::<ExtOutputImageCaptureSourceManagerV1, _, _>(&qh, 1..=1, ())?;
+        let capture_manager = connection
+            .globals
+            .bind::<ExtImageCopyCaptureManagerV1, _, _>(&qh, 1..=1, ())?;
+        let seat = connection.globals.bind::<WlSeat, _, _>(&qh, 1..=5, ())?;
+        let shm = connection.globals.bind::<WlShm, _, _>(&qh, 1..=1, ())?;
+
+        let source = source_manager.create_source(output, &qh, ());
+        let pointer = seat.get_pointer(&qh, ());
+        let session = capture_manager.create_pointer_cursor_session(&source, &pointer, &qh, ());
+        let capture_session = session.get_capture_session(&qh, ());
+        source_manager.destroy();
+        capture_manager.destroy();
+
+        let mut state = CursorState::default();
+        event_queue.roundtrip(&mut state)?;
+
+        let mut capture = Self {
+            event_queue,
+            qh,
+            state,
+            shm,
+            session,
+            capture_session,
+            source,
+            pointer,
+            frame: None,
+            scale,
+        };
+        capture.request_frame()?;
+        Ok(capture)
+    }
+
+    /// Handle whatever the compositor has sent meanwhile, without blocking.
+    pub fn update(&mut self) -> &CursorState {
+        let _ = self.event_queue.flush();
+        if let Some(guard) = self.event_queue.prepare_read() {
+            let _ = guard.read();
+        }
+        if let Err(e) = self.event_queue.dispatch_pending(&mut self.state) {
+            tracing::warn!("cursor session dispatch failed: {e}");
+        }
+        if let Some(ready) = self.state.frame_ready.take() {
+            self.finish_frame(ready);
+            if let Err(e) = self.request_frame() {
+                tracing::warn!("cannot capture the cursor image: {e}");
+            }
+        }
+        &self.state
+    }
+
+    /// Ask for the next cursor image. The compositor only answers once the image changes.
+    fn request_frame(&mut self) -> anyhow::Result<()> {
+        if self.state.stopped || !self.state.constraints_done || self.frame.is_some() {
+            return Ok(());
+        }
+        let (width, height) = self.state.buffer_size;
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+        let format = [wl_shm::Format::Argb8888, wl_shm::Format::Abgr8888]
+            .into_iter()
+            .find(|format| self.state.shm_formats.contains(format))
+            .ok_or_else(|| anyhow::anyhow!("no supported shm format for the cursor"))?;
+
+        let len = width * height * 4;
+        let fd = rustix::fs::memfd_create(c"luminous-cursor", rustix::fs::MemfdFlags::CLOEXEC)?;
+        let file = File::from(fd);
+        file.set_len(len as u64)?;
+        let pool = self.shm.create_pool(file.as_fd(), len as i32, &self.qh, ());
+        let buffer = pool.create_buffer(
+            0,
+            width as i32,
+            height as i32,
+            width as i32 * 4,
+            format,
+            &self.qh,
+            (),
+        );
+        let frame = self.capture_session.create_frame(&self.qh, ());
+        frame.attach_buffer(&buffer);
+        frame.damage_buffer(0, 0, width as i32, height as i32);
+        frame.capture();
+        self.frame = Some(CursorFrame {
+            frame,
+            buffer,
+            pool,
+            file,
+            format,
+            size: (width, height),
+        });
+        Ok(())
+    }
+
+    fn finish_frame(&mut self, ready: bool) {
+        let Some(frame) = self.frame.take() else {
+            return;
+        };
+        if !ready && self.state.bitmap.is_none() {
+            tracing::warn!("cannot capture the cursor image, send the arrow instead");
+            let (bitmap, hotspot) = fallback_arrow(self.scale);
+            self.state.bitmap = Some(bitmap);
+            self.state.hotspot = hotspot;
+        }
+        if ready {
+            let (width, height) = frame.size;
+            let mut pixels = vec![0; (width * height * 4) as usize];
+            if frame.file.read_exact_at(&mut pixels, 0).is_ok() {
+                // wl_shm formats are little endian, so the byte order is reversed
+                let format = match frame.format {
+                    wl_shm::Format::Abgr8888 => libspa_sys::SPA_VIDEO_FORMAT_RGBA,
+                    _ => libspa_sys::SPA_VIDEO_FORMAT_BGRA,
+                };
+                let (bitmap, hotspot) = scale_bitmap(
+                    CursorBitmap {
+                        format,
+                        width,
+                        height,
+                        pixels,
+                    },
+                    self.state.pending_hotspot,
+                    self.scale,
+                );
+                self.state.bitmap = Some(bitmap);
+                self.state.hotspot = hotspot;
+            }
+        }
+        frame.frame.destroy();
+        frame.buffer.destroy();
+        frame.pool.destroy();
+    }
+}
+
+/// The connector name of the output the pointer is over, `None` when it is over none, as
+/// when it is hidden.
+///
+/// Wayland tells no client where the pointer is outside of its own surfaces, but a cursor
+/// session of each output tells whether it is over that output.
+pub fn output_under_cursor(connection: &WayshotConnection) -> anyhow::Result<Option<String>> {
+    for output in connection.get_all_outputs() {
+        let mut capture = CursorCapture::new(connection, &output.wl_output, 1.)?;
+        // The position comes right after the session is made, once the pointer is over it
+        capture.event_queue.roundtrip(&mut capture.state)?;
+        if capture.state.position.is_some() {
+            return Ok(Some(output.name.clone()));
+        }
+    }
+    Ok(None)
+}
+
+/// The size of the cursor when `XCURSOR_SIZE` does not tell
+const DEFAULT_CURSOR_SIZE: u32 = 24;
+
+/// The arrow of the cursor theme set by `XCURSOR_THEME` and `XCURSOR_SIZE`, scaled by
+/// `scale`, or a plain drawn one when the theme has none.
+fn fallback_arrow(scale: f64) -> (CursorBitmap, (i32, i32)) {
+    let (bitmap, hotspot) = theme_arrow().unwrap_or_else(|| {
+        tracing::warn!("cannot load the arrow of the cursor theme, draw one instead");
+        drawn_arrow()
+    });
+    scale_bitmap(bitmap, hotspot, scale)
+}
+
+/// The image of the cursor theme closest to its cursor size.
+fn theme_arrow() -> Option<(CursorBitmap, (i32, i32))> {
+    let theme = std::env::var("XCURSOR_THEME").unwrap_or_else(|_| "default".to_string());
+    let size = std::env::var("XCURSOR_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_CURSOR_SIZE);
+    let theme = xcursor::CursorTheme::load(&theme);
+    let path = theme
+        .load_icon("default")
+        .or_else(|| theme.load_icon("left_ptr"))?;
+    let images = xcursor::parser::parse_xcursor(&std::fs::read(path).ok()?)?;
+    let image = images
+        .into_iter()
+        .min_by_key(|image| image.size.abs_diff(size))?;
+    Some((
+        CursorBitmap {
+            // The pixels are kept in the byte order of the file, little endian ARGB
+            format: libspa_sys::SPA_VIDEO_FORMAT_BGRA,
+            width: image.width,
+            height: image.height,
+            pixels: image.pixels_rgba,
+        },
+        (image.xhot as i32, image.yhot as i32),
+    ))
+}
+
+/// A black arrow outlined in white, with its hotspot at the tip.
+fn drawn_arrow() -> (CursorBitmap, (i32, i32)) {
+    const ARROW: [&str; 16] = [
+        "X          ",
+        "XX         ",
+        "X.X        ",
+        "X..X       ",
+        "X...X      ",
+        "X....X     ",
+        "X.....X    ",
+        "X......X   ",
+        "X.......X  ",
+        "X........X ",
+        "X.....XXXXX",
+        "X..X..X    ",
+        "X.X X..X   ",
+        "XX  X..X   ",
+        "X    X..X  ",
+        "     XXXX  ",
+    ];
+    let width = ARROW[0].len() as u32;
+    let height = ARROW.len() as u32;
+    let pixels = ARROW
+        .iter()
+        .flat_map(|row| row.bytes())
+        .flat_map(|pixel| match pixel {
+            b'X' => [0xff, 0xff, 0xff, 0xff],
+            b'.' => [0, 0, 0, 0xff],
+            _ => [0, 0, 0, 0],
+        })
+        .collect();
+    (
+        CursorBitmap {
+            format: libspa_sys::SPA_VIDEO_FORMAT_BGRA,
+            width,
+            height,
+            pixels,
+        },
+        (0, 0),
+    )
+}
+
+/// Resize `bitmap` by `scale`, as much as fits in [`CURSOR_BITMAP_MAX_SIZE`], and its
+/// `hotspot` along with it.
+fn scale_bitmap(
+    bitmap: CursorBitmap,
+    (hotspot_x, hotspot_y): (i32, i32),
+    scale: f64,
+) -> (CursorBitmap, (i32, i32)) {
+    let largest = bitmap.width.max(bitmap.height).max(1) as f64;
+    let scale = scale.min(CURSOR_BITMAP_MAX_SIZE as f64 / largest);
+    if (scale - 1.).abs() < f64::EPSILON {
+        return (bitmap, (hotspot_x, hotspot_y));
+    }
+    let width = ((bitmap.width as f64 * scale).round() as u32).max(1);
+    let height = ((bitmap.height as f64 * scale).round() as u32).max(1);
+    let Some(image) = image::ImageBuffer::<image::Rgba<u8>, &[u8]>::from_raw(
+        bitmap.width,
+        bitmap.height,
+        &bitmap.pixels,
+    ) else {
+        return (bitmap, (hotspot_x, hotspot_y));
+    };
+    // Only the channel values are blended, so their order does not matter
+    let pixels =
+        image::imageops::resize(&image, width, height, image::imageops::FilterType::Triangle)
+            .into_raw();
+    let hotspot = (
+        (hotspot_x as f64 * scale).round() as i32,
+        (hotspot_y as f64 * scale).round() as i32,
+    );
+    (
+        CursorBitmap {
+            format: bitmap.format,
+            width,
+            height,
+            pixels,
+        },
+        hotspot,
+    )
+}
+
+impl Drop for CursorCapture {
+    fn drop(&mut self) {
+        if let Some(frame) = self.frame.take() {
+            frame.frame.destroy();
+            frame.buffer.destroy();
+            frame.pool.destroy();
+        }
+        self.capture_session.destroy();
+        self.session.destroy();
+        self.source.destroy();
+        if self.pointer.version() >= 3 {
+            self.pointer.release();
+        }
+        let _ = self.event_queue.flush();
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureCursorSessionV1, ()> for CursorState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureCursorSessionV1,
+        event: ext_image_copy_capture_cursor_session_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_cursor_session_v1::Event::Leave => state.position = None,
+            ext_image_copy_capture_cursor_session_v1::Event::Position { x, y } => {
+                state.position = Some((x, y))
+            }
+            ext_image_copy_capture_cursor_session_v1::Event::Hotspot { x, y } => {
+                state.pending_hotspot = (x, y)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for CursorState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureSessionV1,
+        event: ext_image_copy_capture_session_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
+                state.buffer_size = (width, height);
+                state.shm_formats.clear();
+            }
+            ext_image_copy_capture_session_v1::Event::ShmFormat {
+                format: WEnum::Value(format),
+            } => state.shm_formats.push(format),
+            ext_image_copy_capture_session_v1::Event::Done => state.constraints_done = true,
+            ext_image_copy_capture_session_v1::Event::Stopped => state.stopped = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for CursorState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureFrameV1,
+        event: ext_image_copy_capture_frame_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_frame_v1::Event::Ready => state.frame_ready = Some(true),
+            ext_image_copy_capture_frame_v1::Event::Failed { .. } => {
+                state.frame_ready = Some(false)
+            }
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(CursorState: ExtOutputImageCaptureSourceManagerV1);
+delegate_noop!(CursorState: ExtImageCopyCaptureManagerV1);
+delegate_noop!(CursorState: ExtImageCaptureSourceV1);
+delegate_noop!(CursorState: ignore WlSeat);
+delegate_noop!(CursorState: ignore WlPointer);
+delegate_noop!(CursorState: ignore WlShm);
+delegate_noop!(CursorState: WlShmPool);
+delegate_noop!(CursorState: ignore WlBuffer);
@@ -0,0 +1,605 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    os::fd::{AsFd, IntoRawFd},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use drm::node::{DrmNode, NodeType};
+use gbm::{BufferObject, BufferObjectFlags, Format, Modifier};
+use libwayshot::{CaptureRegion, WayshotConnection, reexport::WlOutput};
+use pipewire::spa::param::video::VideoFormat;
+use wayland_client::{
+    Connection, Dispatch, EventQueue, QueueHandle, delegate_noop, event_created_child,
+    protocol::wl_buffer::WlBuffer,
+};
+use wayland_protocols::wp::linux_dmabuf::zv1::client::{
+    zwp_linux_buffer_params_v1::{self, ZwpLinuxBufferParamsV1},
+    zwp_linux_dmabuf_feedback_v1::{self, ZwpLinuxDmabufFeedbackV1},
+    zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+};
+use wayland_protocols_wlr::screencopy::v1::client::{
+    zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+    zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+};
+
+/// The SPA video format matching the memory layout of the DRM `fourcc`.
+///
+/// DRM formats are little endian like the wl_shm ones, so the channel order is reversed.
+pub fn fourcc_video_format(fourcc: Format) -> Option<VideoFormat> {
+    match fourcc {
+        Format::Argb8888 => Some(VideoFormat::BGRA),
+        Format::Xrgb8888 => Some(VideoFormat::BGRx),
+        Format::Abgr8888 => Some(VideoFormat::RGBA),
+        Format::Xbgr8888 => Some(VideoFormat::RGBx),
+        Format::Argb2101010 => Some(VideoFormat::ARGB_210LE),
+        Format::Xrgb2101010 => Some(VideoFormat::xRGB_210LE),
+        Format::Abgr2101010 => Some(VideoFormat::ABGR_210LE),
+        Format::Xbgr2101010 => Some(VideoFormat::xBGR_210LE),
+        _ => None,
+    }
+}
+
+/// The DRM modifiers of `modifiers` a consumer can import from another GPU than the one the
+/// buffers are allocated on when `other_gpus` may run it: only linear buffers are laid out the
+/// same on every device.
+pub fn shareable_modifiers(mut modifiers: Vec<u64>, other_gpus: bool) -> Vec<u64> {
+    if other_gpus {
+        modifiers.retain(|&modifier| modifier == u64::from(Modifier::Linear));
+    }
+    modifiers
+}
+
+/// Whether there are render nodes but `render_node`, so GPUs a consumer may run on other than
+/// the one of the compositor, like the discrete GPU of a laptop with PRIME.
+fn has_other_gpus(render_node: &Path) -> bool {
+    let Ok(entries) = std::fs::read_dir("/dev/dri") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        entry.file_name().to_string_lossy().starts_with("renderD") && entry.path() != render_node
+    })
+}
+
+/// What the stream offers DMA-BUFs as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmabufOffer {
+    pub format: VideoFormat,
+    /// The DRM format modifiers the consumer can pick from
+    pub modifiers: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+struct DmabufState {
+    /// The format table of the feedback, as fourcc and modifier
+    format_table: Vec<(u32, u64)>,
+    main_device: Option<u64>,
+    tranche_device: Option<u64>,
+    tranche_formats: Vec<(u32, u64)>,
+    /// The formats and modifiers the compositor takes buffers in on its main device
+    formats: Vec<(u32, u64)>,
+    feedback_done: bool,
+    /// The format and size the compositor copies the frame into DMA-BUFs as, if it can
+    frame_dmabuf: Option<(u32, u32, u32)>,
+    buffer_done: bool,
+    frame_ready: Option<bool>,
+    /// The buffer made of the planes given, `None` until the compositor answers, and
+    /// `Some(None)` when it cannot use them
+    created: Option<Option<WlBuffer>>,
+}
+
+/// A DMA-BUF handed to the stream, along with the wl_buffer the compositor copies into.
+struct DmabufBuffer {
+    /// Kept for as long as the buffer is used
+    _bo: BufferObject<()>,
+    buffer: WlBuffer,
+}
+
+/// Copies an output through wlr-screencopy straight into DMA-BUFs, allocated on the render
+/// node of the compositor, so the frames never go through the CPU.
+///
+/// Compositors rendering in software do not copy into DMA-BUFs, their casts stay on shared
+/// memory.
+pub struct DmabufCapture {
+    event_queue: EventQueue<DmabufState>,
+    qh: QueueHandle<DmabufState>,
+    state: DmabufState,
+    screencopy: ZwlrScreencopyManagerV1,
+    linux_dmabuf: ZwpLinuxDmabufV1,
+    gbm: gbm::Device<File>,
+    /// The render node of the main device of the compositor, which allocates the buffers
+    render_node: PathBuf,
+    /// Whether the consumer may run on another GPU, which only takes linear buffers
+    other_gpus: bool,
+    fourcc: Format,
+    video_format: VideoFormat,
+    size: (u32, u32),
+    /// The modifiers the compositor takes buffers of `fourcc` with
+    modifiers: Vec<u64>,
+    /// The modifier agreed on with the consumer, and how many planes it takes
+    agreed: Option<(u64, u32)>,
+    /// The buffers of the stream, by the fd of their first plane
+    buffers: HashMap<i64, DmabufBuffer>,
+    /// Whether a buffer could not be allocated or copied into, DMA-BUFs are not offered
+    /// anymore then
+    failed: bool,
+}
+
+impl DmabufCapture {
+    /// Get ready to copy `output`, or the `region` of it, into DMA-BUFs.
+    ///
+    /// Fails when the compositor cannot copy it into one, or tells no device to allocate it on.
+    pub fn new(
+        connection: &WayshotConnection,
+        output: &WlOutput,
+        region: Option<CaptureRegion>,
+    ) -> anyhow::Result<Self> {
+        let conn: &Connection = &connection.conn;
+        let mut event_queue = conn.new_event_queue::<DmabufState>();
+        let qh = event_queue.handle();
+
+        // The linux_dmabuf event of frames came with version 3
+        let screencopy =
+            connection
+                .globals
+                .bind::<ZwlrScreencopyManagerV1, _, _>(&qh, 3..=3, ())?;
+        let linux_dmabuf = match connection
+            .globals
+            .bind::<ZwpLinuxDmabufV1, _, _>(&qh, 4..=4, ())
+        {
+            Ok(linux_dmabuf) => linux_dmabuf,
+            Err(e) => {
+                screencopy.destroy();
+                return Err(e.into());
+            }
+        };
+        let feedback = linux_dmabuf.get_default_feedback(&qh, ());
+        let mut state = DmabufState::default();
+        let result = (|| {
+            while !state.feedback_done {
+                event_queue.blocking_dispatch(&mut state)?;
+            }
+            let main_device = state
+                .main_device
+                .ok_or_else(|| anyhow::anyhow!("the compositor tells no main device"))?;
+            let render_node = DrmNode::from_dev_id(main_device)?
+                .dev_path_with_type(NodeType::Render)
+                .ok_or_else(|| anyhow::anyhow!("the main device has no render node"))?;
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&render_node)?;
+            anyhow::Ok((render_node, gbm::Device::new(file)?))
+        })();
+        feedback.destroy();
+        let (render_node, gbm) = match result {
+            Ok(device) => device,
+            Err(e) => {
+                screencopy.destroy();
+                linux_dmabuf.destroy();
+                let _ = event_queue.flush();
+                return Err(e);
+            }
+        };
+        tracing::info!("DMA-BUFs are allocated on {}", render_node.display());
+        let other_gpus = has_other_gpus(&render_node);
+        if other_gpus {
+            tracing::info!("there are other GPUs, only linear DMA-BUFs are offered");
+        }
+        let mut capture = Self {
+            event_queue,
+            qh,
+            state,
+            screencopy,
+            linux_dmabuf,
+            gbm,
+            render_node,
+            other_gpus,
+            fourcc: Format::Xrgb8888,
+            video_format: VideoFormat::BGRx,
+            size: (0, 0),
+            modifiers: Vec::new(),
+            agreed: None,
+            buffers: HashMap::new(),
+            failed: false,
+        };
+        capture.probe(output, region)?;
+        Ok(capture)
+    }
+
+    /// Learn the format and the size the compositor copies frames of `output` into DMA-BUFs
+    /// as, and the modifiers it takes them with. Done again once the output is resized.
+    pub fn probe(
+        &mut self,
+        output: &WlOutput,
+        region: Option<CaptureRegion>,
+    ) -> anyhow::Result<()> {
+        self.agreed = None;
+        let result = self.start_frame(output, region, false).and_then(|frame| {
+            frame.destroy();
+            self.use_frame_dmabuf()
+        });
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+
+    /// Take the format and the size of the frame started last.
+    fn use_frame_dmabuf(&mut self) -> anyhow::Result<()> {
+        let (fourcc, width, height) = self
+            .state
+            .frame_dmabuf
+            .ok_or_else(|| anyhow::anyhow!("the compositor does not copy frames into DMA-BUFs"))?;
+        let format = Format::try_from(fourcc)
+            .map_err(|_| anyhow::anyhow!("the compositor copies into DMA-BUFs of {fourcc:#x}"))?;
+        let video_format = fourcc_video_format(format).ok_or_else(|| {
+            anyhow::anyhow!("the compositor copies into DMA-BUFs of {format}, which cannot be cast")
+        })?;
+        let invalid = u64::from(Modifier::Invalid);
+        let mut modifiers: Vec<u64> = self
+            .state
+            .formats
+            .iter()
+            .filter(|&&(table_fourcc, modifier)| table_fourcc == fourcc && modifier != invalid)
+            .map(|&(_, modifier)| modifier)
+            .collect();
+        modifiers.sort_unstable();
+        modifiers.dedup();
+        if modifiers.is_empty() {
+            anyhow::bail!("the compositor takes no DMA-BUF of {format} with a modifier");
+        }
+        let modifiers = shareable_modifiers(modifiers, self.other_gpus);
+        if modifiers.is_empty() {
+            anyhow::bail!(
+                "the compositor takes no linear DMA-BUF of {format}, which another GPU could import"
+            );
+        }
+        self.fourcc = format;
+        self.video_format = video_format;
+        self.size = (width, height);
+        self.modifiers = modifiers;
+        Ok(())
+    }
+
+    /// What DMA-BUFs of `width` by `height` frames are offered as, `None` when they cannot be.
+    pub fn offer(&self, (width, height): (u32, u32)) -> Option<DmabufOffer> {
+        (!self.failed && self.size == (width, height)).then(|| DmabufOffer {
+            format: self.video_format,
+            modifiers: self.modifiers.clone(),
+        })
+    }
+
+    /// The format the frames are copied as.
+    pub fn video_format(&self) -> VideoFormat {
+        self.video_format
+    }
+
+    /// The render node the buffers are allocated on.
+    pub fn render_node(&self) -> &Path {
+        &self.render_node
+    }
+
+    /// Stop offering DMA-BUFs, the stream goes on with shared memory.
+    pub fn fail(&mut self) {
+        self.failed = true;
+    }
+
+    /// Pick one of the `offered` modifiers the consumer leaves to us, by allocating a buffer
+    /// with them.
+    pub fn fixate(&mut self, offered: &[u64]) -> anyhow::Result<u64> {
+        let modifiers = offered
+            .iter()
+            .filter(|modifier| self.modifiers.contains(modifier))
+            .map(|&modifier| Modifier::from(modifier));
+        let bo = self.allocate(modifiers)?;
+        let modifier = u64::from(bo.modifier());
+        self.agreed = Some((modifier, bo.plane_count()));
+        Ok(modifier)
+    }
+
+    /// How many planes the buffers take with the `modifier` the consumer agreed on.
+    pub fn agree(&mut self, modifier: u64) -> anyhow::Result<u32> {
+        if let Some((agreed, planes)) = self.agreed
+            && agreed == modifier
+        {
+            return Ok(planes);
+        }
+        if !self.modifiers.contains(&modifier) {
+            anyhow::bail!("the modifier {modifier:#x} was not offered");
+        }
+        let planes = self
+            .allocate(std::iter::once(Modifier::from(modifier)))?
+            .plane_count();
+        self.agreed = Some((modifier, planes));
+        Ok(planes)
+    }
+
+    fn allocate(
+        &self,
+        modifiers: impl Iterator<Item = Modifier>,
+    ) -> anyhow::Result<BufferObject<()>> {
+        let (width, height) = self.size;
+        Ok(self.gbm.create_buffer_object_with_modifiers2(
+            width,
+            height,
+            self.fourcc,
+            modifiers,
+            BufferObjectFlags::RENDERING,
+        )?)
+    }
+
+    /// Allocate a DMA-BUF with the modifier agreed on, and lay its planes out in `datas`.
+    ///
+    /// `datas` is left as it is on failure.
+    pub fn add_buffer(&mut self, datas: &mut [libspa_sys::spa_data]) -> anyhow::Result<()> {
+        let (modifier, planes) = self
+            .agreed
+            .ok_or_else(|| anyhow::anyhow!("no modifier is agreed on"))?;
+        if datas.len() < planes as usize {
+            anyhow::bail!("the buffer has room for {} of {planes} planes", datas.len());
+        }
+        let bo = self.allocate(std::iter::once(Modifier::from(modifier)))?;
+        let (width, height) = self.size;
+        // As fd, offset and stride
+        let planes = (0..planes as i32)
+            .map(|plane| {
+                let fd = bo.fd_for_plane(plane)?;
+                anyhow::Ok((fd, bo.offset(plane), bo.stride_for_plane(plane)))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let params = self.linux_dmabuf.create_params(&self.qh, ());
+        for (plane, (fd, offset, stride)) in planes.iter().enumerate() {
+            params.add(
+                fd.as_fd(),
+                plane as u32,
+                *offset,
+                *stride,
+                (modifier >> 32) as u32,
+                modifier as u32,
+            );
+        }
+        self.state.created = None;
+        params.create(
+            width as i32,
+            height as i32,
+            self.fourcc as u32,
+            zwp_linux_buffer_params_v1::Flags::empty(),
+        );
+        let created = (|| {
+            while self.state.created.is_none() {
+                self.event_queue.blocking_dispatch(&mut self.state)?;
+            }
+            anyhow::Ok(self.state.created.take().flatten())
+        })();
+        params.destroy();
+        let Some(buffer) = created? else {
+            anyhow::bail!("the compositor cannot use the DMA-BUF");
+        };
+        for (data, (fd, offset, stride)) in datas.iter_mut().zip(planes) {
+            data.type_ = libspa_sys::SPA_DATA_DmaBuf;
+            data.flags = 0;
+            data.fd = fd.into_raw_fd().into();
+            data.mapoffset = 0;
+            data.maxsize = stride * height;
+            data.data = std::ptr::null_mut();
+            let chunk = unsafe { &mut *data.chunk };
+            chunk.offset = offset;
+            chunk.stride = stride as i32;
+            chunk.size = stride * height;
+        }
+        self.buffers
+            .insert(datas[0].fd, DmabufBuffer { _bo: bo, buffer });
+        Ok(())
+    }
+
+    /// Let go of the buffer whose first plane is `fd`, the stream closes the fds.
+    pub fn remove_buffer(&mut self, fd: i64) {
+        if let Some(buffer) = self.buffers.remove(&fd) {
+            buffer.buffer.destroy();
+        }
+        let _ = self.event_queue.flush();
+    }
+
+    /// Copy a frame of `output`, or the `region` of it, into the buffer whose first plane is
+    /// `fd`.
+    pub fn capture(
+        &mut self,
+        output: &WlOutput,
+        region: Option<CaptureRegion>,
+        overlay_cursor: bool,
+        fd: i64,
+    ) -> anyhow::Result<()> {
+        let frame = self.start_frame(output, region, overlay_cursor)?;
+        let (width, height) = self.size;
+        let buffer = match self.buffers.get(&fd) {
+            Some(_) if self.state.frame_dmabuf != Some((self.fourcc as u32, width, height)) => {
+                frame.destroy();
+                anyhow::bail!("the compositor copies into DMA-BUFs of another format or size");
+            }
+            Some(buffer) => &buffer.buffer,
+            None => {
+                frame.destroy();
+                anyhow::bail!("the buffer is not one of the DMA-BUFs allocated");
+            }
+        };
+        frame.copy(buffer);
+        let result = (|| {
+            while self.state.frame_ready.is_none() {
+                self.event_queue.blocking_dispatch(&mut self.state)?;
+            }
+            anyhow::Ok(self.state.frame_ready == Some(true))
+        })();
+        frame.destroy();
+        if !result? {
+            anyhow::bail!("the compositor cannot copy the frame");
+        }
+        Ok(())
+    }
+
+    /// Ask for a frame of `output`, and wait for the compositor to tell what it copies into.
+    fn start_frame(
+        &mut self,
+        output: &WlOutput,
+        region: Option<CaptureRegion>,
+        overlay_cursor: bool,
+    ) -> anyhow::Result<ZwlrScreencopyFrameV1> {
+        self.state.frame_dmabuf = None;
+        self.state.buffer_done = false;
+        self.state.frame_ready = None;
+        let frame = match region {
+            Some(region) => self.screencopy.capture_output_region(
+                overlay_cursor as i32,
+                output,
+                region.x_coordinate,
+                region.y_coordinate,
+                region.width,
+                region.height,
+                &self.qh,
+                (),
+            ),
+            None => self
+                .screencopy
+                .capture_output(overlay_cursor as i32, output, &self.qh, ()),
+        };
+        let result = (|| {
+            while !self.state.buffer_done && self.state.frame_ready.is_none() {
+                self.event_queue.blocking_dispatch(&mut self.state)?;
+            }
+            if self.state.frame_ready == Some(false) {
+                anyhow::bail!("the compositor cannot copy the output");
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => Ok(frame),
+            Err(e) => {
+                frame.destroy();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for DmabufCapture {
+    fn drop(&mut self) {
+        for (_, buffer) in self.buffers.drain() {
+            buffer.buffer.destroy();
+        }
+        self.screencopy.destroy();
+        self.linux_dmabuf.destroy();
+        let _ = self.event_queue.flush();
+    }
+}
+
+/// A `dev_t` as the linux-dmabuf feedback sends it.
+fn device(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+impl Dispatch<ZwpLinuxDmabufFeedbackV1, ()> for DmabufState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwpLinuxDmabufFeedbackV1,
+        event: zwp_linux_dmabuf_feedback_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwp_linux_dmabuf_feedback_v1::Event::FormatTable { fd, size } => {
+                let mut table = vec![0; size as usize];
+                if File::from(fd).read_exact_at(&mut table, 0).is_err() {
+                    tracing::warn!("cannot read the format table of the compositor");
+                    return;
+                }
+                // Each entry is a fourcc, 4 bytes of padding and a modifier
+                state.format_table = table
+                    .chunks_exact(16)
+                    .map(|entry| {
+                        let fourcc = u32::from_ne_bytes(entry[..4].try_into().unwrap());
+                        let modifier = u64::from_ne_bytes(entry[8..].try_into().unwrap());
+                        (fourcc, modifier)
+                    })
+                    .collect();
+            }
+            zwp_linux_dmabuf_feedback_v1::Event::MainDevice {
+                device: main_device,
+            } => {
+                state.main_device = device(&main_device);
+            }
+            zwp_linux_dmabuf_feedback_v1::Event::TrancheTargetDevice { device: target } => {
+                state.tranche_device = device(&target);
+            }
+            zwp_linux_dmabuf_feedback_v1::Event::TrancheFormats { indices } => {
+                let format_table = &state.format_table;
+                state.tranche_formats.extend(
+                    indices
+                        .chunks_exact(2)
+                        .map(|index| u16::from_ne_bytes([index[0], index[1]]) as usize)
+                        .filter_map(|index| format_table.get(index)),
+                );
+            }
+            // Only the formats taken on the device the buffers are allocated on count
+            zwp_linux_dmabuf_feedback_v1::Event::TrancheDone => {
+                let formats = std::mem::take(&mut state.tranche_formats);
+                if state.tranche_device.take() == state.main_device {
+                    state.formats.extend(formats);
+                }
+            }
+            zwp_linux_dmabuf_feedback_v1::Event::Done => state.feedback_done = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrScreencopyFrameV1, ()> for DmabufState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrScreencopyFrameV1,
+        event: zwlr_screencopy_frame_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_screencopy_frame_v1::Event::LinuxDmabuf {
+                format,
+                width,
+                height,
+            } => state.frame_dmabuf = Some((format, width, height)),
+            zwlr_screencopy_frame_v1::Event::BufferDone => state.buffer_done = true,
+            zwlr_screencopy_frame_v1::Event::Ready { .. } => state.frame_ready = Some(true),
+            zwlr_screencopy_frame_v1::Event::Failed => state.frame_ready = Some(false),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwpLinuxBufferParamsV1, ()> for DmabufState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwpLinuxBufferParamsV1,
+        event: zwp_linux_buffer_params_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwp_linux_buffer_params_v1::Event::Created { buffer } => {
+                state.created = Some(Some(buffer))
+            }
+            zwp_linux_buffer_params_v1::Event::Failed => state.created = Some(None),
+            _ => {}
+        }
+    }
+
+    event_created_child!(DmabufState, ZwpLinuxBufferParamsV1, [
+        zwp_linux_buffer_params_v1::EVT_CREATED_OPCODE => (WlBuffer, ()),
+    ]);
+}
+
+delegate_noop!(DmabufState: ZwlrScreencopyManagerV1);
+delegate_noop!(DmabufState: ignore ZwpLinuxDmabufV1);
+delegate_noop!(DmabufState: ignore WlBuffer);
@@ -0,0 +1,175 @@
+use libwayshot::WayshotConnection;
+use pipewire::spa::param::video::VideoFormat;
+use wayland_client::protocol::wl_output;
+
+use crate::settings::HiddenRegion;
+use crate::utils::output_layout;
+
+use super::{CastTarget, is_10bit};
+
+/// How strongly hidden areas are blurred, in frame pixels
+const BLUR_SIGMA: f32 = 16.;
+
+/// The parts of the frames of a cast that are never shown, from the `hidden_regions` of the
+/// config.
+///
+/// They are kept as fractions of the frame, so they stay over the same part of the screen
+/// when the output changes mode or the frames are shrunk. Windows are not cast in output
+/// coordinates, so nothing is hidden from them.
+#[derive(Debug, Clone, Default)]
+pub struct HiddenAreas {
+    /// `(x, y, width, height)` in `0..=1`, in the orientation of the frames
+    areas: Vec<(f64, f64, f64, f64)>,
+    blur: bool,
+}
+
+impl HiddenAreas {
+    pub fn new(
+        connection: &WayshotConnection,
+        target: &CastTarget,
+        regions: &[HiddenRegion],
+        blur: bool,
+    ) -> Self {
+        if regions.is_empty() {
+            return Self::default();
+        }
+        let outputs = connection.get_all_outputs();
+        let areas = match target {
+            CastTarget::Output { output, region } => {
+                let Some(info) = outputs.iter().find(|info| info.wl_output == *output) else {
+                    return Self::default();
+                };
+                let (x, y, width, height) = region
+                    .map(|region| {
+                        (
+                            region.x_coordinate,
+                            region.y_coordinate,
+                            region.width,
+                            region.height,
+                        )
+                    })
+                    .unwrap_or((0, 0, info.dimensions.width, info.dimensions.height));
+                regions
+                    .iter()
+                    .filter(|hidden| hidden.output == info.name)
+                    .filter_map(|hidden| fraction(hidden, (-x, -y), (width, height)))
+                    .map(|area| buffer_area(area, info.transform))
+                    .collect()
+            }
+            // Each output is turned upright while they are pieced together
+            CastTarget::Desktop => {
+                let (x, y, width, height) = output_layout(connection);
+                regions
+                    .iter()
+                    .filter_map(|hidden| {
+                        let info = outputs.iter().find(|info| info.name == hidden.output)?;
+                        let offset = (info.dimensions.x - x, info.dimensions.y - y);
+                        fraction(hidden, offset, (width as i32, height as i32))
+                    })
+                    .collect()
+            }
+            CastTarget::Toplevel { .. } => Vec::new(),
+        };
+        Self { areas, blur }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.areas.is_empty()
+    }
+
+    /// Fill the hidden areas of the `width` by `height` frame `pixels`, laid out as `format`,
+    /// with black, or blur them.
+    ///
+    /// 10 bit frames are filled with black either way, their channels cannot be blurred byte
+    /// by byte.
+    pub fn apply(&self, pixels: &mut [u8], width: u32, height: u32, format: VideoFormat) {
+        for &(x, y, area_width, area_height) in &self.areas {
+            let left = (x * width as f64).floor() as u32;
+            let top = (y * height as f64).floor() as u32;
+            let right = (((x + area_width) * width as f64).ceil() as u32).min(width);
+            let bottom = (((y + area_height) * height as f64).ceil() as u32).min(height);
+            if left >= right || top >= bottom {
+                continue;
+            }
+            if self.blur && !is_10bit(format) {
+                blur(pixels, width, (left, top, right, bottom));
+            } else {
+                // The alpha of 10 bit formats is their 2 highest bits
+                let black = if is_10bit(format) {
+                    [0, 0, 0, 0xc0]
+                } else {
+                    [0, 0, 0, 0xff]
+                };
+                for row in top..bottom {
+                    let start = ((row * width + left) * 4) as usize;
+                    let end = ((row * width + right) * 4) as usize;
+                    for pixel in pixels[start..end].chunks_exact_mut(4) {
+                        pixel.copy_from_slice(&black);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Blur the `(left, top, right, bottom)` rectangle of the frame `pixels`, `width` wide.
+fn blur(pixels: &mut [u8], width: u32, (left, top, right, bottom): (u32, u32, u32, u32)) {
+    let (area_width, area_height) = (right - left, bottom - top);
+    let mut area = Vec::with_capacity((area_width * area_height * 4) as usize);
+    for row in top..bottom {
+        let start = ((row * width + left) * 4) as usize;
+        area.extend_from_slice(&pixels[start..start + (area_width * 4) as usize]);
+    }
+    let Some(area) = image::RgbaImage::from_raw(area_width, area_height, area) else {
+        return;
+    };
+    // Only the channel values are blended, so their order does not matter
+    let blurred = image::imageops::fast_blur(&area, BLUR_SIGMA).into_raw();
+    for (row, blurred_row) in (top..bottom).zip(blurred.chunks_exact((area_width * 4) as usize)) {
+        let start = ((row * width + left) * 4) as usize;
+        pixels[start..start + blurred_row.len()].copy_from_slice(blurred_row);
+    }
+}
+
+/// `hidden` moved by `offset`, as fractions of an upright `size`, `None` if it is outside.
+fn fraction(
+    hidden: &HiddenRegion,
+    (offset_x, offset_y): (i32, i32),
+    (width, height): (i32, i32),
+) -> Option<(f64, f64, f64, f64)> {
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+    let left = (hidden.x + offset_x).clamp(0, width);
+    let top = (hidden.y + offset_y).clamp(0, height);
+    let right = (hidden.x + offset_x + hidden.width).clamp(0, width);
+    let bottom = (hidden.y + offset_y + hidden.height).clamp(0, height);
+    if left >= right || top >= bottom {
+        return None;
+    }
+    Some((
+        left as f64 / width as f64,
+        top as f64 / height as f64,
+        (right - left) as f64 / width as f64,
+        (bottom - top) as f64 / height as f64,
+    ))
+}
+
+/// Where the upright `area` of an output turned by `transform` lies in its frames, which the
+/// compositor copies the way the output is laid out, not upright.
+fn buffer_area(
+    (x, y, width, height): (f64, f64, f64, f64),
+    transform: wl_output::Transform,
+) -> (f64, f64, f64, f64) {
+    // The frames are the upright output with the inverse of its transform applied
+    match transform {
+        wl_output::Transform::_90 => (y, 1. - x - width, height, width),
+        wl_output::Transform::_180 => (1. - x - width, 1. - y - height, width, height),
+        wl_output::Transform::_270 => (1. - y - height, x, height, width),
+        wl_output::Transform::Flipped => (1. - x - width, y, width, height),
+        wl_output::Transform::Flipped90 => (y, x, height, width),
+        wl_output::Transform::Flipped180 => (x, 1. - y - height, width, height),
+        wl_output::Transform::Flipped270 => (1. - y - height, 1. - x - width, height, width),
+        _ => (x, y, width, height),
+    }
+}
@@ -0,0 +1,237 @@
+use wayland_client::{
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
+    protocol::{
+        wl_output::{self, WlOutput},
+        wl_registry::{self, WlRegistry},
+    },
+};
+
+#[derive(Debug)]
+struct WatchedOutput {
+    global: u32,
+    output: WlOutput,
+    /// Connector name, once known
+    name: Option<String>,
+    /// Current mode in physical pixels, once known
+    mode: Option<(i32, i32)>,
+    /// Refresh rate of the current mode in mHz, once known
+    refresh: Option<i32>,
+}
+
+#[derive(Debug, Default)]
+struct WatchState {
+    outputs: Vec<WatchedOutput>,
+    removed: Vec<u32>,
+    /// Whether an output came, went or was told anything new since this was last cleared
+    changed: bool,
+}
+
+/// What happened to the output being cast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputChange {
+    /// The output is unplugged
+    Gone,
+    /// The output switched to a mode of another size, in physical pixels
+    Resized { width: u32, height: u32 },
+}
+
+/// Follows the registry and the outputs, to tell when the output being cast is unplugged or
+/// changes its mode.
+pub struct OutputWatch {
+    event_queue: EventQueue<WatchState>,
+    state: WatchState,
+    /// Global name of the watched output, `None` if the compositor never told its name
+    global: Option<u32>,
+    /// The last mode reported by [`OutputWatch::poll`]
+    mode: Option<(i32, i32)>,
+}
+
+impl OutputWatch {
+    pub fn new(conn: &Connection, output_name: &str) -> anyhow::Result<Self> {
+        let mut event_queue = conn.new_event_queue::<WatchState>();
+        let qh = event_queue.handle();
+        let _ = conn.display().get_registry(&qh, ());
+
+        let mut state = WatchState::default();
+        // The first roundtrip binds the outputs, the second one gets their names
+        event_queue.roundtrip(&mut state)?;
+        event_queue.roundtrip(&mut state)?;
+
+        let watched = state
+            .outputs
+            .iter()
+            .find(|output| output.name.as_deref() == Some(output_name));
+        if watched.is_none() {
+            tracing::warn!("cannot follow {output_name}, it has no name");
+        }
+        let global = watched.map(|output| output.global);
+        let mode = watched.and_then(|output| output.mode);
+        Ok(Self {
+            event_queue,
+            state,
+            global,
+            mode,
+        })
+    }
+
+    /// Handle whatever the compositor has sent meanwhile, without blocking, and tell what
+    /// happened to the output since the last call.
+    pub fn poll(&mut self) -> Option<OutputChange> {
+        let global = self.global?;
+        let _ = self.event_queue.flush();
+        if let Some(guard) = self.event_queue.prepare_read() {
+            let _ = guard.read();
+        }
+        if let Err(e) = self.event_queue.dispatch_pending(&mut self.state) {
+            tracing::warn!("output watch dispatch failed: {e}");
+        }
+        if self.state.removed.contains(&global) {
+            return Some(OutputChange::Gone);
+        }
+        let mode = self
+            .state
+            .outputs
+            .iter()
+            .find(|output| output.global == global)
+            .and_then(|output| output.mode);
+        if mode == self.mode {
+            return None;
+        }
+        self.mode = mode;
+        let (width, height) = mode?;
+        Some(OutputChange::Resized {
+            width: width as u32,
+            height: height as u32,
+        })
+    }
+}
+
+/// The refresh rate of each output in mHz, by connector name, which libwayshot does not tell.
+pub fn refresh_rates(conn: &Connection) -> anyhow::Result<Vec<(String, i32)>> {
+    let mut event_queue = conn.new_event_queue::<WatchState>();
+    let qh = event_queue.handle();
+    let _ = conn.display().get_registry(&qh, ());
+
+    let mut state = WatchState::default();
+    // The first roundtrip binds the outputs, the second one gets their names and modes
+    event_queue.roundtrip(&mut state)?;
+    event_queue.roundtrip(&mut state)?;
+
+    let rates = state
+        .outputs
+        .iter()
+        .filter_map(|output| Some((output.name.clone()?, output.refresh?)))
+        .collect();
+    for output in state.outputs.drain(..) {
+        output.output.release();
+    }
+    let _ = event_queue.flush();
+    Ok(rates)
+}
+
+/// Call `on_change(true)` from a thread of its own whenever an output is plugged, unplugged
+/// or changes, until the connection fails, which is told with `on_change(false)`.
+pub fn watch_outputs(
+    conn: &Connection,
+    mut on_change: impl FnMut(bool) + Send + 'static,
+) -> anyhow::Result<()> {
+    let mut event_queue = conn.new_event_queue::<WatchState>();
+    let qh = event_queue.handle();
+    let _ = conn.display().get_registry(&qh, ());
+
+    let mut state = WatchState::default();
+    event_queue.roundtrip(&mut state)?;
+    event_queue.roundtrip(&mut state)?;
+    state.changed = false;
+    std::thread::spawn(move || {
+        loop {
+            if let Err(e) = event_queue.blocking_dispatch(&mut state) {
+                tracing::warn!("cannot follow the outputs anymore: {e}");
+                break;
+            }
+            if std::mem::take(&mut state.changed) {
+                on_change(true);
+            }
+        }
+        on_change(false);
+    });
+    Ok(())
+}
+
+impl Drop for OutputWatch {
+    fn drop(&mut self) {
+        for output in self.state.outputs.drain(..) {
+            output.output.release();
+        }
+        let _ = self.event_queue.flush();
+    }
+}
+
+impl Dispatch<WlRegistry, ()> for WatchState {
+    fn event(
+        state: &mut Self,
+        proxy: &WlRegistry,
+        event: wl_registry::Event,
+        _data: &(),
+        _conn: &Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            // The name event comes with version 4
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } if interface == WlOutput::interface().name && version >= 4 => {
+                let output = proxy.bind::<WlOutput, _, _>(name, 4, qhandle, name);
+                state.outputs.push(WatchedOutput {
+                    global: name,
+                    output,
+                    name: None,
+                    mode: None,
+                    refresh: None,
+                });
+                state.changed = true;
+            }
+            wl_registry::Event::GlobalRemove { name } => {
+                state.changed |= state.outputs.iter().any(|output| output.global == name);
+                state.removed.push(name);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlOutput, u32> for WatchState {
+    fn event(
+        state: &mut Self,
+        _proxy: &WlOutput,
+        event: wl_output::Event,
+        global: &u32,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(output) = state
+            .outputs
+            .iter_mut()
+            .find(|output| output.global == *global)
+        else {
+            return;
+        };
+        match event {
+            wl_output::Event::Name { name } => output.name = Some(name),
+            wl_output::Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                refresh,
+            } if flags.contains(wl_output::Mode::Current) => {
+                output.mode = Some((width, height));
+                output.refresh = Some(refresh);
+            }
+            // Sent once the compositor told everything that changed, the geometry among it
+            wl_output::Event::Done => state.changed = true,
+            _ => {}
+        }
+    }
+}
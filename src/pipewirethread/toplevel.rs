@@ -0,0 +1,490 @@
+use std::{fs::File, os::fd::AsFd, os::unix::fs::FileExt};
+
+use libwayshot::WayshotConnection;
+use wayland_client::{
+    Connection, Dispatch, EventQueue, QueueHandle, WEnum, delegate_noop, event_created_child,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
+    },
+};
+use wayland_protocols::ext::{
+    foreign_toplevel_list::v1::client::{
+        ext_foreign_toplevel_handle_v1::{self, ExtForeignToplevelHandleV1},
+        ext_foreign_toplevel_list_v1::{self, ExtForeignToplevelListV1},
+    },
+    image_capture_source::v1::client::{
+        ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1,
+        ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+    },
+    image_copy_capture::v1::client::{
+        ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1},
+        ext_image_copy_capture_manager_v1::{self, ExtImageCopyCaptureManagerV1},
+        ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+    },
+};
+
+/// The shm formats a window can be copied as, the ones a stream can be offered
+const TOPLEVEL_FORMATS: [wl_shm::Format; 4] = [
+    wl_shm::Format::Xrgb8888,
+    wl_shm::Format::Argb8888,
+    wl_shm::Format::Xbgr8888,
+    wl_shm::Format::Abgr8888,
+];
+
+#[derive(Debug, Default)]
+struct ToplevelState {
+    /// The windows told by the list, with their identifier once known
+    toplevels: Vec<(ExtForeignToplevelHandleV1, Option<String>)>,
+    /// The window being cast, once found
+    handle: Option<ExtForeignToplevelHandleV1>,
+    buffer_size: (u32, u32),
+    shm_formats: Vec<wl_shm::Format>,
+    constraints_done: bool,
+    frame_ready: Option<bool>,
+    stopped: bool,
+}
+
+struct ToplevelBuffer {
+    buffer: WlBuffer,
+    pool: WlShmPool,
+    file: File,
+    size: (u32, u32),
+}
+
+impl ToplevelBuffer {
+    fn destroy(self) {
+        self.buffer.destroy();
+        self.pool.destroy();
+    }
+}
+
+/// A window open on the compositor, as the ext-foreign-toplevel list tells it.
+#[derive(Debug, Clone, Default)]
+pub struct ToplevelInfo {
+    /// What the window is cast by, see [`ToplevelCapture::new`]
+    pub identifier: String,
+    pub title: String,
+    pub app_id: String,
+}
+
+#[derive(Debug, Default)]
+struct ToplevelListState {
+    toplevels: Vec<(ExtForeignToplevelHandleV1, ToplevelInfo)>,
+}
+
+/// The windows open now, for the user to pick one to cast.
+///
+/// Fails when the compositor has no ext-foreign-toplevel list.
+pub fn list_toplevels(connection: &WayshotConnection) -> anyhow::Result<Vec<ToplevelInfo>> {
+    let conn: &Connection = &connection.conn;
+    let mut event_queue = conn.new_event_queue::<ToplevelListState>();
+    let qh = event_queue.handle();
+    let list = connection
+        .globals
+        .bind::<ExtForeignToplevelListV1, _, _>(&qh, 1..=1, ())?;
+    let mut state = ToplevelListState::default();
+    // The windows and their details are all told at once
+    let result = event_queue.roundtrip(&mut state);
+    list.stop();
+    let mut toplevels = Vec::new();
+    for (handle, info) in state.toplevels.drain(..) {
+        handle.destroy();
+        if !info.identifier.is_empty() {
+            toplevels.push(info);
+        }
+    }
+    list.destroy();
+    let _ = event_queue.flush();
+    result?;
+    Ok(toplevels)
+}
+
+/// Copies one window, found by its ext-foreign-toplevel identifier, through an
+/// ext-image-copy-capture session.
+///
+/// Frames are asked for one at a time without blocking, and the last one is kept, so a window
+/// that is hidden or not redrawn keeps being sent as it was last seen.
+pub struct ToplevelCapture {
+    event_queue: EventQueue<ToplevelState>,
+    qh: QueueHandle<ToplevelState>,
+    state: ToplevelState,
+    shm: WlShm,
+    list: ExtForeignToplevelListV1,
+    source: ExtImageCaptureSourceV1,
+    session: ExtImageCopyCaptureSessionV1,
+    format: wl_shm::Format,
+    buffer: Option<ToplevelBuffer>,
+    frame: Option<ExtImageCopyCaptureFrameV1>,
+    /// The last frame copied, empty until the first one is
+    pixels: Vec<u8>,
+    size: (u32, u32),
+}
+
+impl ToplevelCapture {
+    pub fn new(
+        connection: &WayshotConnection,
+        identifier: &str,
+        paint_cursors: bool,
+    ) -> anyhow::Result<Self> {
+        let conn: &Connection = &connection.conn;
+        let mut event_queue = conn.new_event_queue::<ToplevelState>();
+        let qh = event_queue.handle();
+
+        let list = connection
+            .globals
+            .bind::<ExtForeignToplevelListV1, _, _>(&qh, 1..=1, ())?;
+        let source_manager = connection
+            .globals
+            .bind::<ExtForeignToplevelImageCaptureSourceManagerV1, _, _>(&qh, 1..=1, ())?;
+        let capture_manager = connection
+            .globals
+            .bind::<ExtImageCopyCaptureManagerV1, _, _>(&qh, 1..=1, ())?;
+        let shm = connection.globals.bind::<WlShm, _, _>(&qh, 1..=1, ())?;
+
+        let mut state = ToplevelState::default();
+        // The windows and their identifiers are all told at once
+        event_queue.roundtrip(&mut state)?;
+
+        let found = state
+            .toplevels
+            .iter()
+            .position(|(_, toplevel_identifier)| toplevel_identifier.as_deref() == Some(identifier))
+            .map(|index| state.toplevels.swap_remove(index).0);
+        for (other, _) in state.toplevels.drain(..) {
+            other.destroy();
+        }
+        let Some(handle) = found else {
+            source_manager.destroy();
+            capture_manager.destroy();
+            list.destroy();
+            let _ = event_queue.flush();
+            anyhow::bail!("no window has the identifier {identifier}");
+        };
+        let source = source_manager.create_source(&handle, &qh, ());
+        let options = if paint_cursors {
+            ext_image_copy_capture_manager_v1::Options::PaintCursors
+        } else {
+            ext_image_copy_capture_manager_v1::Options::empty()
+        };
+        let session = capture_manager.create_session(&source, options, &qh, ());
+        source_manager.destroy();
+        capture_manager.destroy();
+        // No other window is needed, the handle of this one stays valid
+        list.stop();
+        state.handle = Some(handle);
+
+        while !state.constraints_done && !state.stopped {
+            event_queue.blocking_dispatch(&mut state)?;
+        }
+        let format = TOPLEVEL_FORMATS
+            .into_iter()
+            .find(|format| state.shm_formats.contains(format));
+
+        let mut capture = Self {
+            event_queue,
+            qh,
+            state,
+            shm,
+            list,
+            source,
+            session,
+            format: format.unwrap_or(wl_shm::Format::Xrgb8888),
+            buffer: None,
+            frame: None,
+            pixels: Vec::new(),
+            size: (0, 0),
+        };
+        if capture.state.stopped {
+            anyhow::bail!("the window {identifier} cannot be captured");
+        }
+        if format.is_none() {
+            anyhow::bail!("the window {identifier} cannot be copied in a supported format");
+        }
+        capture.request_frame()?;
+        Ok(capture)
+    }
+
+    /// The layout the frames are copied with.
+    pub fn format(&self) -> wl_shm::Format {
+        self.format
+    }
+
+    /// The size of the window in buffer pixels, as the compositor last told it.
+    pub fn buffer_size(&self) -> (u32, u32) {
+        self.state.buffer_size
+    }
+
+    /// The last frame copied, empty until the first one is.
+    pub fn frame(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// The size of [`ToplevelCapture::frame`].
+    pub fn frame_size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    /// Handle whatever the compositor has sent meanwhile without blocking, and ask for the
+    /// next frame. `false` once the window is closed.
+    pub fn update(&mut self) -> bool {
+        let _ = self.event_queue.flush();
+        if let Some(guard) = self.event_queue.prepare_read() {
+            let _ = guard.read();
+        }
+        if let Err(e) = self.event_queue.dispatch_pending(&mut self.state) {
+            tracing::warn!("window capture dispatch failed: {e}");
+        }
+        if self.state.stopped {
+            return false;
+        }
+        if let Some(ready) = self.state.frame_ready.take() {
+            self.finish_frame(ready);
+        }
+        if let Err(e) = self.request_frame() {
+            tracing::warn!("cannot capture the window: {e}");
+        }
+        true
+    }
+
+    /// Ask for the next frame, the compositor answers once the window is redrawn.
+    fn request_frame(&mut self) -> anyhow::Result<()> {
+        if self.state.stopped || !self.state.constraints_done || self.frame.is_some() {
+            return Ok(());
+        }
+        let (width, height) = self.state.buffer_size;
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+        if !self.state.shm_formats.contains(&self.format) {
+            anyhow::bail!("the window cannot be copied as {:?} anymore", self.format);
+        }
+        // The buffer is kept from frame to frame, until the window is resized
+        if self
+            .buffer
+            .as_ref()
+            .is_none_or(|buffer| buffer.size != (width, height))
+        {
+            if let Some(buffer) = self.buffer.take() {
+                buffer.destroy();
+            }
+            let len = width * height * 4;
+            let fd =
+                rustix::fs::memfd_create(c"luminous-toplevel", rustix::fs::MemfdFlags::CLOEXEC)?;
+            let file = File::from(fd);
+            file.set_len(len as u64)?;
+            let pool = self.shm.create_pool(file.as_fd(), len as i32, &self.qh, ());
+            let buffer = pool.create_buffer(
+                0,
+                width as i32,
+                height as i32,
+                width as i32 * 4,
+                self.format,
+                &self.qh,
+                (),
+            );
+            self.buffer = Some(ToplevelBuffer {
+                buffer,
+                pool,
+                file,
+                size: (width, height),
+            });
+        }
+        let Some(buffer) = self.buffer.as_ref() else {
+            return Ok(());
+        };
+        let frame = self.session.create_frame(&self.qh, ());
+        frame.attach_buffer(&buffer.buffer);
+        frame.damage_buffer(0, 0, width as i32, height as i32);
+        frame.capture();
+        self.frame = Some(frame);
+        Ok(())
+    }
+
+    fn finish_frame(&mut self, ready: bool) {
+        if let Some(frame) = self.frame.take() {
+            frame.destroy();
+        }
+        let Some(buffer) = self.buffer.as_ref().filter(|_| ready) else {
+            return;
+        };
+        let (width, height) = buffer.size;
+        self.pixels.resize((width * height * 4) as usize, 0);
+        if buffer.file.read_exact_at(&mut self.pixels, 0).is_ok() {
+            self.size = (width, height);
+        } else {
+            self.pixels.clear();
+            self.size = (0, 0);
+        }
+    }
+}
+
+impl Drop for ToplevelCapture {
+    fn drop(&mut self) {
+        if let Some(frame) = self.frame.take() {
+            frame.destroy();
+        }
+        if let Some(buffer) = self.buffer.take() {
+            buffer.destroy();
+        }
+        self.session.destroy();
+        self.source.destroy();
+        if let Some(handle) = self.state.handle.take() {
+            handle.destroy();
+        }
+        // Windows told before the list was stopped
+        for (other, _) in self.state.toplevels.drain(..) {
+            other.destroy();
+        }
+        self.list.destroy();
+        let _ = self.event_queue.flush();
+    }
+}
+
+impl Dispatch<ExtForeignToplevelListV1, ()> for ToplevelState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtForeignToplevelListV1,
+        event: ext_foreign_toplevel_list_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let ext_foreign_toplevel_list_v1::Event::Toplevel { toplevel } = event {
+            state.toplevels.push((toplevel, None));
+        }
+    }
+
+    event_created_child!(ToplevelState, ExtForeignToplevelListV1, [
+        ext_foreign_toplevel_list_v1::EVT_TOPLEVEL_OPCODE => (ExtForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ExtForeignToplevelHandleV1, ()> for ToplevelState {
+    fn event(
+        state: &mut Self,
+        proxy: &ExtForeignToplevelHandleV1,
+        event: ext_foreign_toplevel_handle_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_foreign_toplevel_handle_v1::Event::Identifier { identifier } => {
+                if let Some((_, toplevel_identifier)) = state
+                    .toplevels
+                    .iter_mut()
+                    .find(|(toplevel, _)| toplevel == proxy)
+                {
+                    *toplevel_identifier = Some(identifier);
+                }
+            }
+            ext_foreign_toplevel_handle_v1::Event::Closed
+                if state.handle.as_ref() == Some(proxy) =>
+            {
+                state.stopped = true
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtForeignToplevelListV1, ()> for ToplevelListState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtForeignToplevelListV1,
+        event: ext_foreign_toplevel_list_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let ext_foreign_toplevel_list_v1::Event::Toplevel { toplevel } = event {
+            state.toplevels.push((toplevel, ToplevelInfo::default()));
+        }
+    }
+
+    event_created_child!(ToplevelListState, ExtForeignToplevelListV1, [
+        ext_foreign_toplevel_list_v1::EVT_TOPLEVEL_OPCODE => (ExtForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ExtForeignToplevelHandleV1, ()> for ToplevelListState {
+    fn event(
+        state: &mut Self,
+        proxy: &ExtForeignToplevelHandleV1,
+        event: ext_foreign_toplevel_handle_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Some((_, info)) = state
+            .toplevels
+            .iter_mut()
+            .find(|(toplevel, _)| toplevel == proxy)
+        else {
+            return;
+        };
+        match event {
+            ext_foreign_toplevel_handle_v1::Event::Identifier { identifier } => {
+                info.identifier = identifier
+            }
+            ext_foreign_toplevel_handle_v1::Event::Title { title } => info.title = title,
+            ext_foreign_toplevel_handle_v1::Event::AppId { app_id } => info.app_id = app_id,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for ToplevelState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureSessionV1,
+        event: ext_image_copy_capture_session_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
+                state.buffer_size = (width, height);
+                state.shm_formats.clear();
+            }
+            ext_image_copy_capture_session_v1::Event::ShmFormat {
+                format: WEnum::Value(format),
+            } => state.shm_formats.push(format),
+            ext_image_copy_capture_session_v1::Event::Done => state.constraints_done = true,
+            ext_image_copy_capture_session_v1::Event::Stopped => state.stopped = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for ToplevelState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureFrameV1,
+        event: ext_image_copy_capture_frame_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_frame_v1::Event::Ready => state.frame_ready = Some(true),
+            ext_image_copy_capture_frame_v1::Event::Failed { reason } => {
+                if reason == WEnum::Value(ext_image_copy_capture_frame_v1::FailureReason::Stopped) {
+                    state.stopped = true;
+                }
+                state.frame_ready = Some(false)
+            }
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(ToplevelState: ExtForeignToplevelImageCaptureSourceManagerV1);
+delegate_noop!(ToplevelState: ExtImageCopyCaptureManagerV1);
+delegate_noop!(ToplevelState: ExtImageCaptureSourceV1);
+delegate_noop!(ToplevelState: ignore WlShm);
+delegate_noop!(ToplevelState: WlShmPool);
+delegate_noop!(ToplevelState: ignore WlBuffer);
@@ -9,22 +9,23 @@ use remote_thread::RemoteControl;
 use std::collections::HashMap;
 
 use enumflags2::BitFlags;
-use zbus::interface;
+use zbus::{interface, message::Header};
 
 use zbus::zvariant::{DeserializeDict, ObjectPath, OwnedValue, SerializeDict, Type, Value};
 
-use serde::{Deserialize, Serialize};
-
 use std::sync::{Arc, LazyLock};
 use tokio::sync::Mutex;
 
-use crate::pipewirethread::ScreencastThread;
+use crate::pipewirethread::{CastTarget, ScreencastThread};
 use crate::request::RequestInterface;
+use crate::screencast::{Stream, StreamProperties};
 use crate::session::{
     DeviceType, PersistMode, SESSIONS, Session, SessionType, SourceType, append_session,
+    close_session, handle_token,
 };
 
 use crate::PortalResponse;
+use crate::utils::{connect_wayshot, output_layout};
 
 use self::remote_thread::KeyOrPointerRequest;
 
@@ -35,20 +36,6 @@ struct SessionCreateResult {
     handle_token: String,
 }
 
-#[derive(Clone, Serialize, Deserialize, Type, Default, Debug)]
-/// A PipeWire stream.
-pub struct Stream(u32, StreamProperties);
-
-#[derive(Clone, SerializeDict, DeserializeDict, Default, Type, Debug)]
-/// The stream properties.
-#[zvariant(signature = "dict")]
-struct StreamProperties {
-    id: Option<String>,
-    position: Option<(i32, i32)>,
-    size: Option<(i32, i32)>,
-    source_type: Option<SourceType>,
-}
-
 // TODO: this is copy from ashpd, but the dict is a little different from xdg_desktop_portal
 #[derive(Clone, SerializeDict, DeserializeDict, Default, Debug, Type)]
 #[zvariant(signature = "dict")]
@@ -69,7 +56,7 @@ pub struct SelectDevicesOptions {
     pub persist_mode: Option<PersistMode>,
 }
 
-pub type RemoteSessionData = (String, ScreencastThread, RemoteControl);
+pub type RemoteSessionData = (String, ScreencastThread, RemoteControl, StreamProperties);
 pub static REMOTE_SESSIONS: LazyLock<Arc<Mutex<Vec<RemoteSessionData>>>> =
     LazyLock::new(|| Arc::new(Mutex::new(Vec::new())));
 
@@ -78,6 +65,15 @@ pub async fn append_remote_session(session: RemoteSessionData) {
     sessions.push(session)
 }
 
+/// Whether the session at `path` is started, and so may be sending input.
+pub async fn is_remote_started(path: &str) -> bool {
+    REMOTE_SESSIONS
+        .lock()
+        .await
+        .iter()
+        .any(|the_session| the_session.0 == path)
+}
+
 pub async fn remove_remote_session(path: &str) {
     let mut sessions = REMOTE_SESSIONS.lock().await;
     let Some(index) = sessions
@@ -92,6 +88,50 @@ pub async fn remove_remote_session(path: &str) {
     sessions.remove(index);
 }
 
+/// Find the started session at `session_handle`, only those take input.
+fn find_remote_session<'a>(
+    sessions: &'a [RemoteSessionData],
+    session_handle: &ObjectPath<'_>,
+) -> zbus::fdo::Result<&'a RemoteSessionData> {
+    sessions
+        .iter()
+        .find(|session| session.0 == session_handle.as_str())
+        .ok_or_else(|| {
+            zbus::Error::Failure(format!(
+                "{session_handle} is not a started remote desktop session"
+            ))
+            .into()
+        })
+}
+
+/// Map `x` and `y` on `stream` to the layout the virtual pointer spans.
+///
+/// The stream coordinates are logical pixels of the cast output.
+fn stream_to_layout(
+    session: &RemoteSessionData,
+    stream: u32,
+    x: f64,
+    y: f64,
+) -> zbus::fdo::Result<(u32, u32)> {
+    if session.1.node_id() != stream {
+        return Err(zbus::Error::Failure(format!("No stream {stream} in this session")).into());
+    }
+    let (position_x, position_y) = session.3.position.unwrap_or_default();
+    let (width, height) = session.3.size.unwrap_or_default();
+    let (layout_x, layout_y, _, _) = session.2.layout;
+    let x = position_x - layout_x + x.clamp(0., width as f64) as i32;
+    let y = position_y - layout_y + y.clamp(0., height as f64) as i32;
+    Ok((x.max(0) as u32, y.max(0) as u32))
+}
+
+/// Fail unless the session at hand selected `device`.
+fn check_device(session: &RemoteSessionData, device: DeviceType) -> zbus::fdo::Result<()> {
+    if session.2.devices.contains(device) {
+        return Ok(());
+    }
+    Err(zbus::Error::Failure(format!("The {device:?} was not selected for this session")).into())
+}
+
 pub struct RemoteDesktopBackend;
 
 #[interface(name = "org.freedesktop.impl.portal.RemoteDesktop")]
@@ -103,22 +143,34 @@ impl RemoteDesktopBackend {
 
     #[zbus(property)]
     fn available_device_types(&self) -> u32 {
-        (DeviceType::Keyboard | DeviceType::Pointer).bits()
+        (DeviceType::Keyboard | DeviceType::Pointer | DeviceType::TouchScreen).bits()
     }
 
+    #[tracing::instrument(skip_all, fields(session_handle = %session_handle, app_id = %app_id))]
     async fn create_session(
         &self,
         request_handle: ObjectPath<'_>,
         session_handle: ObjectPath<'_>,
         app_id: String,
-        _options: HashMap<String, Value<'_>>,
+        options: HashMap<String, Value<'_>>,
+        #[zbus(header)] header: Header<'_>,
         #[zbus(object_server)] server: &zbus::ObjectServer,
     ) -> zbus::fdo::Result<PortalResponse<SessionCreateResult>> {
-        tracing::info!(
-            "Start shot: path :{}, appid: {}",
-            request_handle.as_str(),
-            app_id
+        let request_token = handle_token(&options, "handle_token", &request_handle);
+        let session_token = handle_token(&options, "session_handle_token", &session_handle);
+        tracing::info!("create session {session_token} for request {request_token}");
+        let mut current_session = Session::new(
+            session_handle.clone(),
+            session_token,
+            SessionType::Remote,
+            app_id,
         );
+        current_session.sender = header.sender().map(ToString::to_string);
+        // The token the session is found by from now on
+        let handle_token = current_session.handle_token.clone();
+        if !append_session(current_session.clone()).await {
+            return Ok(PortalResponse::Other);
+        }
         server
             .at(
                 request_handle.clone(),
@@ -127,22 +179,21 @@ impl RemoteDesktopBackend {
                 },
             )
             .await?;
-        let current_session = Session::new(session_handle.clone(), SessionType::Remote);
-        append_session(current_session.clone()).await;
         server.at(session_handle.clone(), current_session).await?;
         Ok(PortalResponse::Success(SessionCreateResult {
-            handle_token: session_handle.to_string(),
+            handle_token,
         }))
     }
 
+    #[tracing::instrument(skip_all, fields(session_handle = %session_handle, app_id = %app_id))]
     async fn select_devices(
         &self,
         _request_handle: ObjectPath<'_>,
         session_handle: ObjectPath<'_>,
-        _app_id: String,
+        app_id: String,
         options: SelectDevicesOptions,
     ) -> zbus::fdo::Result<PortalResponse<HashMap<String, OwnedValue>>> {
-        let mut locked_sessions = SESSIONS.lock().await;
+        let mut locked_sessions = SESSIONS.write().await;
         let Some(index) = locked_sessions
             .iter()
             .position(|this_session| this_session.handle_path == session_handle.clone().into())
@@ -150,22 +201,24 @@ impl RemoteDesktopBackend {
             tracing::warn!("No session is created or it is removed");
             return Ok(PortalResponse::Other);
         };
-        if locked_sessions[index].session_type != SessionType::Remote {
-            return Ok(PortalResponse::Other);
+        if let Err(e) = locked_sessions[index].check_type(&[SessionType::Remote]) {
+            tracing::warn!("{e}");
+            return Ok(PortalResponse::Error(e));
         }
         locked_sessions[index].set_remote_options(options);
         Ok(PortalResponse::Success(HashMap::new()))
     }
 
+    #[tracing::instrument(skip_all, fields(session_handle = %session_handle, app_id = %app_id))]
     async fn start(
         &self,
         _request_handle: ObjectPath<'_>,
         session_handle: ObjectPath<'_>,
-        _app_id: String,
+        app_id: String,
         _parent_window: String,
         _options: HashMap<String, Value<'_>>,
     ) -> zbus::fdo::Result<PortalResponse<RemoteStartReturnValue>> {
-        let locked_sessions = SESSIONS.lock().await;
+        let locked_sessions = SESSIONS.read().await;
         let Some(index) = locked_sessions
             .iter()
             .position(|this_session| this_session.handle_path == session_handle.clone().into())
@@ -175,9 +228,29 @@ impl RemoteDesktopBackend {
         };
 
         let current_session = locked_sessions[index].clone();
-        if current_session.session_type != SessionType::Remote {
+        if let Err(e) = current_session.check_type(&[SessionType::Remote]) {
+            tracing::warn!("{e}");
+            return Ok(PortalResponse::Error(e));
+        }
+        if current_session.app_id != app_id {
+            tracing::warn!(
+                "{app_id} tried to start {session_handle}, created by {}",
+                current_session.app_id
+            );
             return Ok(PortalResponse::Other);
         }
+        // Its input lands on the screen it casts, so a remote desktop casts only one of them
+        if !current_session.source_type.contains(SourceType::Monitor) {
+            let e = format!(
+                "{session_handle} selected {:?}, a remote desktop session only casts a monitor",
+                current_session.source_type
+            );
+            tracing::warn!("{e}");
+            return Ok(PortalResponse::Error(e));
+        }
+        if current_session.multiple {
+            tracing::info!("{session_handle} selected multiple sources, only one screen is cast");
+        }
         let device_type = current_session.device_type;
         drop(locked_sessions);
 
@@ -187,7 +260,7 @@ impl RemoteDesktopBackend {
             .find(|session| session.0 == session_handle.to_string())
         {
             return Ok(PortalResponse::Success(RemoteStartReturnValue {
-                streams: vec![Stream(session.1.node_id(), StreamProperties::default())],
+                streams: vec![Stream(session.1.node_id(), session.3.clone())],
                 devices: device_type,
                 ..Default::default()
             }));
@@ -195,8 +268,8 @@ impl RemoteDesktopBackend {
         drop(remote_sessions);
 
         // TODO: use slurp now
-        let show_cursor = current_session.cursor_mode.show_cursor();
-        let connection = libwayshot::WayshotConnection::new().unwrap();
+        let connection = connect_wayshot()?;
+        let layout = output_layout(&connection);
         let info = match libwaysip::get_area(
             Some(libwaysip::WaysipConnection {
                 connection: &connection.conn,
@@ -205,34 +278,63 @@ impl RemoteDesktopBackend {
             SelectionType::Screen,
         ) {
             Ok(Some(info)) => info,
-            Ok(None) => return Err(zbus::Error::Failure("You cancel it".to_string()).into()),
+            Ok(None) => return Ok(PortalResponse::Cancelled),
             Err(e) => return Err(zbus::Error::Failure(format!("wayland error, {e}")).into()),
         };
 
+        let screen_info = info.screen_info;
         let WlOutputInfo {
             output,
+            name: output_name,
             size: (width, height),
             ..
-        } = info.screen_info.output_info;
+        } = screen_info.output_info;
+        let mut stream_properties = StreamProperties {
+            id: Some(output_name),
+            position: Some((screen_info.start_x, screen_info.start_y)),
+            size: Some((screen_info.width, screen_info.height)),
+            source_type: Some(SourceType::Monitor),
+            ..Default::default()
+        };
 
         let cast_thread = ScreencastThread::start_cast(
-            show_cursor,
+            current_session.cursor_mode,
             width as u32,
             height as u32,
             None,
-            output,
+            (None, None),
+            CastTarget::Output {
+                output,
+                region: None,
+            },
             connection,
+            {
+                let handle = tokio::runtime::Handle::current();
+                let path = session_handle.to_string();
+                move |_| {
+                    handle.spawn(async move {
+                        close_session(&path, "The cast of the session has ended").await;
+                    });
+                }
+            },
         )
         .await
         .map_err(|e| zbus::Error::Failure(format!("cannot start pipewire stream, error: {e}")))?;
 
-        let remote_control = RemoteControl::init();
+        stream_properties.framerate = Some(cast_thread.settled_framerate());
+        let remote_control = RemoteControl::init(layout, device_type);
         let node_id = cast_thread.node_id();
 
-        append_remote_session((session_handle.to_string(), cast_thread, remote_control)).await;
+        append_remote_session((
+            session_handle.to_string(),
+            cast_thread,
+            remote_control,
+            stream_properties.clone(),
+        ))
+        .await;
 
         Ok(PortalResponse::Success(RemoteStartReturnValue {
-            streams: vec![Stream(node_id, StreamProperties::default())],
+            streams: vec![Stream(node_id, stream_properties)],
             devices: device_type,
             ..Default::default()
         }))
@@ -248,12 +350,8 @@ impl RemoteDesktopBackend {
         dy: f64,
     ) -> zbus::fdo::Result<()> {
         let remote_sessions = REMOTE_SESSIONS.lock().await;
-        let Some(session) = remote_sessions
-            .iter()
-            .find(|session| session.0 == session_handle.to_string())
-        else {
-            return Ok(());
-        };
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::Pointer)?;
         let remote_control = &session.2;
         remote_control
             .sender
@@ -266,25 +364,23 @@ impl RemoteDesktopBackend {
         &self,
         session_handle: ObjectPath<'_>,
         _options: HashMap<String, Value<'_>>,
-        _steam: u32,
+        stream: u32,
         x: f64,
         y: f64,
     ) -> zbus::fdo::Result<()> {
         let remote_sessions = REMOTE_SESSIONS.lock().await;
-        let Some(session) = remote_sessions
-            .iter()
-            .find(|session| session.0 == session_handle.to_string())
-        else {
-            return Ok(());
-        };
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::Pointer)?;
+        let (x, y) = stream_to_layout(session, stream, x, y)?;
         let remote_control = &session.2;
+        let (_, _, x_extent, y_extent) = remote_control.layout;
         remote_control
             .sender
             .send(KeyOrPointerRequest::PointerMotionAbsolute {
                 x,
                 y,
-                x_extent: 2000,
-                y_extent: 2000,
+                x_extent,
+                y_extent,
             })
             .map_err(|_| zbus::Error::Failure("Send failed".to_string()))?;
         Ok(())
@@ -298,12 +394,8 @@ impl RemoteDesktopBackend {
         state: u32,
     ) -> zbus::fdo::Result<()> {
         let remote_sessions = REMOTE_SESSIONS.lock().await;
-        let Some(session) = remote_sessions
-            .iter()
-            .find(|session| session.0 == session_handle.to_string())
-        else {
-            return Ok(());
-        };
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::Pointer)?;
         let remote_control = &session.2;
         remote_control
             .sender
@@ -315,21 +407,21 @@ impl RemoteDesktopBackend {
     async fn notify_pointer_axis(
         &self,
         session_handle: ObjectPath<'_>,
-        _options: HashMap<String, Value<'_>>,
+        options: HashMap<String, Value<'_>>,
         dx: f64,
         dy: f64,
     ) -> zbus::fdo::Result<()> {
+        let finish = options
+            .get("finish")
+            .and_then(|value| bool::try_from(value).ok())
+            .unwrap_or(false);
         let remote_sessions = REMOTE_SESSIONS.lock().await;
-        let Some(session) = remote_sessions
-            .iter()
-            .find(|session| session.0 == session_handle.to_string())
-        else {
-            return Ok(());
-        };
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::Pointer)?;
         let remote_control = &session.2;
         remote_control
             .sender
-            .send(KeyOrPointerRequest::PointerAxis { dx, dy })
+            .send(KeyOrPointerRequest::PointerAxis { dx, dy, finish })
             .map_err(|_| zbus::Error::Failure("Send failed".to_string()))?;
         Ok(())
     }
@@ -342,12 +434,8 @@ impl RemoteDesktopBackend {
         steps: i32,
     ) -> zbus::fdo::Result<()> {
         let remote_sessions = REMOTE_SESSIONS.lock().await;
-        let Some(session) = remote_sessions
-            .iter()
-            .find(|session| session.0 == session_handle.to_string())
-        else {
-            return Ok(());
-        };
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::Pointer)?;
         let remote_control = &session.2;
         remote_control
             .sender
@@ -364,12 +452,8 @@ impl RemoteDesktopBackend {
         state: u32,
     ) -> zbus::fdo::Result<()> {
         let remote_sessions = REMOTE_SESSIONS.lock().await;
-        let Some(session) = remote_sessions
-            .iter()
-            .find(|session| session.0 == session_handle.to_string())
-        else {
-            return Ok(());
-        };
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::Keyboard)?;
         let remote_control = &session.2;
         remote_control
             .sender
@@ -386,12 +470,8 @@ impl RemoteDesktopBackend {
         state: u32,
     ) -> zbus::fdo::Result<()> {
         let remote_sessions = REMOTE_SESSIONS.lock().await;
-        let Some(session) = remote_sessions
-            .iter()
-            .find(|session| session.0 == session_handle.to_string())
-        else {
-            return Ok(());
-        };
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::Keyboard)?;
         let remote_control = &session.2;
         remote_control
             .sender
@@ -399,4 +479,77 @@ impl RemoteDesktopBackend {
             .map_err(|_| zbus::Error::Failure("Send failed".to_string()))?;
         Ok(())
     }
+
+    async fn notify_touch_down(
+        &self,
+        session_handle: ObjectPath<'_>,
+        _options: HashMap<String, Value<'_>>,
+        stream: u32,
+        slot: u32,
+        x: f64,
+        y: f64,
+    ) -> zbus::fdo::Result<()> {
+        let remote_sessions = REMOTE_SESSIONS.lock().await;
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::TouchScreen)?;
+        let (x, y) = stream_to_layout(session, stream, x, y)?;
+        let remote_control = &session.2;
+        let (_, _, x_extent, y_extent) = remote_control.layout;
+        remote_control
+            .sender
+            .send(KeyOrPointerRequest::TouchDown {
+                slot,
+                x,
+                y,
+                x_extent,
+                y_extent,
+            })
+            .map_err(|_| zbus::Error::Failure("Send failed".to_string()))?;
+        Ok(())
+    }
+
+    async fn notify_touch_motion(
+        &self,
+        session_handle: ObjectPath<'_>,
+        _options: HashMap<String, Value<'_>>,
+        stream: u32,
+        slot: u32,
+        x: f64,
+        y: f64,
+    ) -> zbus::fdo::Result<()> {
+        let remote_sessions = REMOTE_SESSIONS.lock().await;
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::TouchScreen)?;
+        let (x, y) = stream_to_layout(session, stream, x, y)?;
+        let remote_control = &session.2;
+        let (_, _, x_extent, y_extent) = remote_control.layout;
+        remote_control
+            .sender
+            .send(KeyOrPointerRequest::TouchMotion {
+                slot,
+                x,
+                y,
+                x_extent,
+                y_extent,
+            })
+            .map_err(|_| zbus::Error::Failure("Send failed".to_string()))?;
+        Ok(())
+    }
+
+    async fn notify_touch_up(
+        &self,
+        session_handle: ObjectPath<'_>,
+        _options: HashMap<String, Value<'_>>,
+        slot: u32,
+    ) -> zbus::fdo::Result<()> {
+        let remote_sessions = REMOTE_SESSIONS.lock().await;
+        let session = find_remote_session(&remote_sessions, &session_handle)?;
+        check_device(session, DeviceType::TouchScreen)?;
+        let remote_control = &session.2;
+        remote_control
+            .sender
+            .send(KeyOrPointerRequest::TouchUp { slot })
+            .map_err(|_| zbus::Error::Failure("Send failed".to_string()))?;
+        Ok(())
+    }
 }
@@ -15,7 +15,7 @@ use wayland_protocols_wlr::virtual_pointer::v1::client::{
     zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1,
 };
 use xkbcommon::xkb;
-pub fn get_keymap_as_file() -> (File, u32) {
+pub fn get_keymap_as_file() -> (File, u32, xkb::Keymap) {
     let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
 
     let keymap = xkb::Keymap::new_from_names(
@@ -28,19 +28,17 @@ pub fn get_keymap_as_file() -> (File, u32) {
         xkb::KEYMAP_COMPILE_NO_FLAGS,
     )
     .expect("xkbcommon keymap panicked!");
-    let xkb_state = xkb::State::new(&keymap);
-    let keymap = xkb_state
-        .get_keymap()
-        .get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
-    let keymap = CString::new(keymap).expect("Keymap should not contain interior nul bytes");
-    let keymap = keymap.as_bytes_with_nul();
+    let keymap_string = keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+    let keymap_string =
+        CString::new(keymap_string).expect("Keymap should not contain interior nul bytes");
+    let keymap_bytes = keymap_string.as_bytes_with_nul();
     let dir = std::env::var_os("XDG_RUNTIME_DIR")
         .map(PathBuf::from)
         .unwrap_or_else(std::env::temp_dir);
     let mut file = tempfile::tempfile_in(dir).expect("File could not be created!");
-    file.write_all(keymap).unwrap();
+    file.write_all(keymap_bytes).unwrap();
     file.flush().unwrap();
-    (file, keymap.len() as u32)
+    (file, keymap_bytes.len() as u32, keymap)
 }
 impl Dispatch<wl_registry::WlRegistry, ()> for AppData {
     fn event(
@@ -100,9 +98,10 @@ impl Dispatch<WlSeat, ()> for AppData {
     ) {
         if let Some(virtual_keyboard_manager) = state.virtual_keyboard_manager.as_ref() {
             let virtual_keyboard = virtual_keyboard_manager.create_virtual_keyboard(seat, qh, ());
-            let (file, size) = get_keymap_as_file();
+            let (file, size, keymap) = get_keymap_as_file();
             virtual_keyboard.keymap(wl_keyboard::KeymapFormat::XkbV1.into(), file.as_fd(), size);
             state.virtual_keyboard = Some(virtual_keyboard);
+            state.xkb_state = Some(xkb::State::new(&keymap));
         }
         if let Some(virtual_pointer_manager) = state.virtual_pointer_manager.as_ref() {
             let virtual_pointer =
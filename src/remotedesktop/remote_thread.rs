@@ -1,5 +1,8 @@
+use enumflags2::BitFlags;
 use wayland_client::Connection;
 
+use crate::session::DeviceType;
+
 use super::state::AppData;
 use super::state::KeyPointerError;
 
@@ -12,8 +15,8 @@ pub enum KeyOrPointerRequest {
         dy: f64,
     },
     PointerMotionAbsolute {
-        x: f64,
-        y: f64,
+        x: u32,
+        y: u32,
         x_extent: u32,
         y_extent: u32,
     },
@@ -24,6 +27,7 @@ pub enum KeyOrPointerRequest {
     PointerAxis {
         dx: f64,
         dy: f64,
+        finish: bool,
     },
     PointerAxisDiscrate {
         axis: u32,
@@ -37,21 +41,46 @@ pub enum KeyOrPointerRequest {
         keysym: i32,
         state: u32,
     },
+    TouchDown {
+        slot: u32,
+        x: u32,
+        y: u32,
+        x_extent: u32,
+        y_extent: u32,
+    },
+    TouchMotion {
+        slot: u32,
+        x: u32,
+        y: u32,
+        x_extent: u32,
+        y_extent: u32,
+    },
+    TouchUp {
+        slot: u32,
+    },
     Exit,
 }
 
 #[derive(Debug)]
 pub struct RemoteControl {
     pub sender: Sender<KeyOrPointerRequest>,
+    /// The box around all the outputs when the session started, as `(x, y, width, height)`
+    pub layout: (i32, i32, u32, u32),
+    /// The devices the session asked for, no other input is taken
+    pub devices: BitFlags<DeviceType>,
 }
 
 impl RemoteControl {
-    pub fn init() -> Self {
+    pub fn init(layout: (i32, i32, u32, u32), devices: BitFlags<DeviceType>) -> Self {
         let (sender, receiver) = mpsc::channel();
         std::thread::spawn(move || {
             let _ = remote_loop(receiver);
         });
-        Self { sender }
+        Self {
+            sender,
+            layout,
+            devices,
+        }
     }
 
     pub fn stop(&self) {
@@ -97,7 +126,9 @@ pub fn remote_loop(receiver: Receiver<KeyOrPointerRequest>) -> Result<(), KeyPoi
             KeyOrPointerRequest::PointerButton { button, state } => {
                 data.notify_pointer_button(button, state)
             }
-            KeyOrPointerRequest::PointerAxis { dx, dy } => data.notify_pointer_axis(dx, dy),
+            KeyOrPointerRequest::PointerAxis { dx, dy, finish } => {
+                data.notify_pointer_axis(dx, dy, finish)
+            }
             KeyOrPointerRequest::PointerAxisDiscrate { axis, steps } => {
                 data.notify_pointer_axis_discrete(axis, steps)
             }
@@ -107,10 +138,28 @@ pub fn remote_loop(receiver: Receiver<KeyOrPointerRequest>) -> Result<(), KeyPoi
             KeyOrPointerRequest::KeyboardKeysym { keysym, state } => {
                 data.notify_keyboard_keysym(keysym, state)
             }
+            KeyOrPointerRequest::TouchDown {
+                slot,
+                x,
+                y,
+                x_extent,
+                y_extent,
+            } => data.notify_touch_down(slot, x, y, x_extent, y_extent),
+            KeyOrPointerRequest::TouchMotion {
+                slot,
+                x,
+                y,
+                x_extent,
+                y_extent,
+            } => data.notify_touch_motion(slot, x, y, x_extent, y_extent),
+            KeyOrPointerRequest::TouchUp { slot } => data.notify_touch_up(slot),
             KeyOrPointerRequest::Exit => break,
         }
         event_queue.roundtrip(&mut data).ok();
     }
+    // The session may end, or the portal go away, in the middle of a click
+    data.release_buttons();
+    event_queue.roundtrip(&mut data).ok();
 
     Ok(())
 }
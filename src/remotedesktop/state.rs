@@ -9,16 +9,30 @@ use wayland_protocols_wlr::virtual_pointer::v1::client::{
     zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1,
 };
 
+use std::time::Instant;
 use thiserror::Error;
+use xkbcommon::xkb;
+
+/// The evdev code of the left mouse button
+const BTN_LEFT: i32 = 0x110;
 // This struct represents the state of our app. This simple app does not
 // need any state, by this type still supports the `Dispatch` implementations.
-#[derive(Debug)]
 pub struct AppData {
     pub(crate) virtual_keyboard_manager: Option<ZwpVirtualKeyboardManagerV1>,
     pub(crate) virtual_keyboard: Option<ZwpVirtualKeyboardV1>,
+    /// Follows the keys pressed on the virtual keyboard, with the keymap it was given
+    pub(crate) xkb_state: Option<xkb::State>,
 
     pub(crate) virtual_pointer_manager: Option<ZwlrVirtualPointerManagerV1>,
     pub(crate) virtual_pointer: Option<ZwlrVirtualPointerV1>,
+    /// The buttons pressed and not released yet
+    pressed_buttons: Vec<u32>,
+    /// The touch slot driving the pointer, there is no virtual touch protocol so only one
+    /// touch point is emulated
+    touch_slot: Option<u32>,
+
+    /// Event times are milliseconds since then
+    started: Instant,
 }
 
 impl AppData {
@@ -26,10 +40,18 @@ impl AppData {
         Self {
             virtual_keyboard_manager: None,
             virtual_keyboard: None,
+            xkb_state: None,
             virtual_pointer_manager: None,
             virtual_pointer: None,
+            pressed_buttons: Vec::new(),
+            touch_slot: None,
+            started: Instant::now(),
         }
     }
+
+    fn time(&self) -> u32 {
+        self.started.elapsed().as_millis() as u32
+    }
 }
 
 #[derive(Error, Debug)]
@@ -52,63 +74,253 @@ impl AppData {
     }
 
     pub fn notify_pointer_motion(&self, dx: f64, dy: f64) {
-        self.virtual_pointer.as_ref().unwrap().motion(10, dx, dy);
+        let virtual_pointer = self.virtual_pointer.as_ref().unwrap();
+        virtual_pointer.motion(self.time(), dx, dy);
+        virtual_pointer.frame();
     }
 
-    pub fn notify_pointer_motion_absolute(&self, x: f64, y: f64, x_extent: u32, y_extent: u32) {
-        self.virtual_pointer
-            .as_ref()
-            .unwrap()
-            .motion_absolute(10, x as u32, y as u32, x_extent, y_extent);
+    pub fn notify_pointer_motion_absolute(&self, x: u32, y: u32, x_extent: u32, y_extent: u32) {
+        let virtual_pointer = self.virtual_pointer.as_ref().unwrap();
+        virtual_pointer.motion_absolute(self.time(), x, y, x_extent, y_extent);
+        virtual_pointer.frame();
     }
 
-    pub fn notify_pointer_button(&self, button: i32, state: u32) {
-        self.virtual_pointer.as_ref().unwrap().button(
-            100,
-            button as u32,
-            if state == 0 {
+    /// Press or release `button`, a state of 1 presses it.
+    pub fn notify_pointer_button(&mut self, button: i32, state: u32) {
+        let button = button as u32;
+        let pressed = state != 0;
+        self.pressed_buttons.retain(|held| *held != button);
+        if pressed {
+            self.pressed_buttons.push(button);
+        }
+        let virtual_pointer = self.virtual_pointer.as_ref().unwrap();
+        virtual_pointer.button(
+            self.time(),
+            button,
+            if pressed {
                 wl_pointer::ButtonState::Pressed
             } else {
                 wl_pointer::ButtonState::Released
             },
         );
+        virtual_pointer.frame();
     }
 
-    pub fn notify_pointer_axis(&self, dx: f64, dy: f64) {
-        self.virtual_pointer
-            .as_ref()
-            .unwrap()
-            .axis(100, wl_pointer::Axis::HorizontalScroll, dx);
-        self.virtual_pointer
-            .as_ref()
-            .unwrap()
-            .axis(100, wl_pointer::Axis::VerticalScroll, dy);
+    /// Release the buttons still held, so none stays stuck once the session ends.
+    pub fn release_buttons(&mut self) {
+        let time = self.time();
+        let virtual_pointer = self.virtual_pointer.as_ref().unwrap();
+        for button in self.pressed_buttons.drain(..) {
+            virtual_pointer.button(time, button, wl_pointer::ButtonState::Released);
+        }
+        virtual_pointer.frame();
     }
 
+    /// Scroll by `dx` and `dy` pixels, positive values scroll right and down.
+    ///
+    /// With `finish`, the scroll sequence ends, so kinetic scrolling can start.
+    pub fn notify_pointer_axis(&self, dx: f64, dy: f64, finish: bool) {
+        let time = self.time();
+        let virtual_pointer = self.virtual_pointer.as_ref().unwrap();
+        virtual_pointer.axis_source(wl_pointer::AxisSource::Finger);
+        if dx != 0. || finish {
+            virtual_pointer.axis(time, wl_pointer::Axis::HorizontalScroll, dx);
+        }
+        if dy != 0. || finish {
+            virtual_pointer.axis(time, wl_pointer::Axis::VerticalScroll, dy);
+        }
+        if finish {
+            virtual_pointer.axis_stop(time, wl_pointer::Axis::HorizontalScroll);
+            virtual_pointer.axis_stop(time, wl_pointer::Axis::VerticalScroll);
+        }
+        virtual_pointer.frame();
+    }
+
+    /// Scroll by wheel `steps` along `axis`, 0 being vertical, positive steps scroll right
+    /// and down.
     pub fn notify_pointer_axis_discrete(&self, axis: u32, steps: i32) {
-        self.virtual_pointer.as_ref().unwrap().axis_discrete(
-            100,
+        let virtual_pointer = self.virtual_pointer.as_ref().unwrap();
+        virtual_pointer.axis_source(wl_pointer::AxisSource::Wheel);
+        virtual_pointer.axis_discrete(
+            self.time(),
             if axis == 0 {
                 wl_pointer::Axis::VerticalScroll
             } else {
                 wl_pointer::Axis::HorizontalScroll
             },
-            10.0,
+            // The usual distance of a wheel step
+            steps as f64 * 15.,
             steps,
         );
+        virtual_pointer.frame();
+    }
+
+    /// Start a touch at `x` and `y`, emulated as a left click there.
+    pub fn notify_touch_down(&mut self, slot: u32, x: u32, y: u32, x_extent: u32, y_extent: u32) {
+        if self.touch_slot.is_some() {
+            tracing::debug!("touch slot {slot} is ignored, only one touch point is emulated");
+            return;
+        }
+        self.touch_slot = Some(slot);
+        self.notify_pointer_motion_absolute(x, y, x_extent, y_extent);
+        self.notify_pointer_button(BTN_LEFT, 1);
+    }
+
+    pub fn notify_touch_motion(&self, slot: u32, x: u32, y: u32, x_extent: u32, y_extent: u32) {
+        if self.touch_slot != Some(slot) {
+            return;
+        }
+        self.notify_pointer_motion_absolute(x, y, x_extent, y_extent);
+    }
+
+    /// End the touch of `slot`, ignored if it never started.
+    pub fn notify_touch_up(&mut self, slot: u32) {
+        if self.touch_slot != Some(slot) {
+            return;
+        }
+        self.touch_slot = None;
+        self.notify_pointer_button(BTN_LEFT, 0);
+    }
+
+    /// Press or release the evdev `keycode`.
+    pub fn notify_keyboard_keycode(&mut self, keycode: i32, state: u32) {
+        let time = self.time();
+        let virtual_keyboard = self.virtual_keyboard.as_ref().unwrap();
+        virtual_keyboard.key(time, keycode as u32, state);
+        // Clients only apply the modifiers the virtual keyboard reports, like Shift
+        let Some(xkb_state) = self.xkb_state.as_mut() else {
+            return;
+        };
+        let direction = if state == 0 {
+            xkb::KeyDirection::Up
+        } else {
+            xkb::KeyDirection::Down
+        };
+        // xkb keycodes are the evdev ones shifted by 8
+        let changed = xkb_state.update_key(xkb::Keycode::new(keycode as u32 + 8), direction);
+        if changed == 0 {
+            return;
+        }
+        virtual_keyboard.modifiers(
+            xkb_state.serialize_mods(xkb::STATE_MODS_DEPRESSED),
+            xkb_state.serialize_mods(xkb::STATE_MODS_LATCHED),
+            xkb_state.serialize_mods(xkb::STATE_MODS_LOCKED),
+            xkb_state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE),
+        );
     }
 
-    pub fn notify_keyboard_keycode(&self, keycode: i32, state: u32) {
-        self.virtual_keyboard
-            .as_ref()
-            .unwrap()
-            .key(100, keycode as u32, state);
+    /// Press or release the key which gives `keysym` in the uploaded keymap.
+    ///
+    /// The key is pressed with the modifiers reaching the level of `keysym`, like Shift for an
+    /// uppercase letter, and the modifiers go back to the keys held right after.
+    pub fn notify_keyboard_keysym(&mut self, keysym: i32, state: u32) {
+        let Some((keycode, level)) = self.keysym_to_keycode(xkb::Keysym::new(keysym as u32)) else {
+            tracing::warn!("no key gives the keysym {keysym:#x}, it is dropped");
+            return;
+        };
+        if state == 0 {
+            self.notify_keyboard_keycode(keycode, state);
+            return;
+        }
+        let Some(depressed) = self.depressed_for_level(keycode, level) else {
+            tracing::warn!("no modifiers give the keysym {keysym:#x}, it is dropped");
+            return;
+        };
+        self.send_modifiers(Some(depressed));
+        self.notify_keyboard_keycode(keycode, state);
+        self.send_modifiers(None);
     }
 
-    pub fn notify_keyboard_keysym(&self, keysym: i32, state: u32) {
-        self.virtual_keyboard
-            .as_ref()
-            .unwrap()
-            .key(100, keysym as u32, state);
+    /// Tell the modifiers of the keys held, with `depressed` in place of the ones depressed.
+    fn send_modifiers(&self, depressed: Option<xkb::ModMask>) {
+        let (Some(virtual_keyboard), Some(xkb_state)) = (&self.virtual_keyboard, &self.xkb_state)
+        else {
+            return;
+        };
+        virtual_keyboard.modifiers(
+            depressed.unwrap_or_else(|| xkb_state.serialize_mods(xkb::STATE_MODS_DEPRESSED)),
+            xkb_state.serialize_mods(xkb::STATE_MODS_LATCHED),
+            xkb_state.serialize_mods(xkb::STATE_MODS_LOCKED),
+            xkb_state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE),
+        );
+    }
+
+    /// The evdev keycode giving `keysym` on the first layout, at the lowest level, along with
+    /// that level.
+    fn keysym_to_keycode(&self, keysym: xkb::Keysym) -> Option<(i32, xkb::LevelIndex)> {
+        let keymap = self.xkb_state.as_ref()?.get_keymap();
+        let mut found: Option<(u32, xkb::Keycode)> = None;
+        keymap.key_for_each(|keymap, keycode| {
+            for level in 0..keymap.num_levels_for_key(keycode, 0) {
+                if found.is_some_and(|(found_level, _)| found_level <= level) {
+                    return;
+                }
+                if keymap
+                    .key_get_syms_by_level(keycode, 0, level)
+                    .contains(&keysym)
+                {
+                    found = Some((level, keycode));
+                }
+            }
+        });
+        found.map(|(level, keycode)| (keycode.raw() as i32 - 8, level))
+    }
+
+    /// The modifiers to depress so that the evdev `keycode` gives `level` on the first layout.
+    ///
+    /// Shift and AltGr are depressed or not as the level needs, the other modifiers held stay,
+    /// and the ones locked count, so an uppercase letter needs no Shift under Caps Lock.
+    fn depressed_for_level(&self, keycode: i32, level: xkb::LevelIndex) -> Option<xkb::ModMask> {
+        let xkb_state = self.xkb_state.as_ref()?;
+        let keymap = xkb_state.get_keymap();
+        let mask = |name: &str| match keymap.mod_get_index(name) {
+            xkb::MOD_INVALID => 0,
+            index => 1 << index,
+        };
+        let shift = mask(xkb::MOD_NAME_SHIFT);
+        let level3 = mask(xkb::MOD_NAME_ISO_LEVEL3_SHIFT);
+        let held = xkb_state.serialize_mods(xkb::STATE_MODS_DEPRESSED) & !(shift | level3);
+        let latched = xkb_state.serialize_mods(xkb::STATE_MODS_LATCHED);
+        let locked = xkb_state.serialize_mods(xkb::STATE_MODS_LOCKED);
+        let keycode = xkb::Keycode::new(keycode as u32 + 8);
+        let mut probe = xkb::State::new(&keymap);
+        [0, shift, level3, shift | level3]
+            .into_iter()
+            .map(|mods| held | mods)
+            .find(|&depressed| {
+                probe.update_mask(depressed, latched, locked, 0, 0, 0);
+                probe.key_get_level(keycode, 0) == level
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remotedesktop::dispatch::get_keymap_as_file;
+
+    /// The evdev keycodes of A and Caps Lock
+    const KEY_A: i32 = 30;
+    const KEY_CAPSLOCK: i32 = 58;
+
+    #[test]
+    fn an_uppercase_letter_is_typed_with_shift() {
+        let (_, _, keymap) = get_keymap_as_file();
+        let shift = 1 << keymap.mod_get_index(xkb::MOD_NAME_SHIFT);
+        let mut data = AppData::new();
+        data.xkb_state = Some(xkb::State::new(&keymap));
+
+        assert_eq!(data.keysym_to_keycode(xkb::Keysym::a), Some((KEY_A, 0)));
+        assert_eq!(data.depressed_for_level(KEY_A, 0), Some(0));
+        assert_eq!(data.keysym_to_keycode(xkb::Keysym::A), Some((KEY_A, 1)));
+        assert_eq!(data.depressed_for_level(KEY_A, 1), Some(shift));
+
+        // Under Caps Lock, Shift gives the lowercase letter
+        let xkb_state = data.xkb_state.as_mut().unwrap();
+        let capslock = xkb::Keycode::new(KEY_CAPSLOCK as u32 + 8);
+        xkb_state.update_key(capslock, xkb::KeyDirection::Down);
+        xkb_state.update_key(capslock, xkb::KeyDirection::Up);
+        assert_eq!(data.depressed_for_level(KEY_A, 1), Some(0));
+        assert_eq!(data.depressed_for_level(KEY_A, 0), Some(shift));
     }
 }
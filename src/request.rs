@@ -1,5 +1,94 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+
+use rustix::fd::OwnedFd;
+use tokio::sync::Mutex;
 use zbus::{interface, zvariant::OwnedObjectPath};
 
+/// The wayland sockets of the pickers shown for a request, so closing the request can take
+/// the picker down. Each picker has a connection of its own, see
+/// [`crate::utils::connect_picker`].
+static PICKERS: LazyLock<Arc<Mutex<HashMap<OwnedObjectPath, Option<Arc<OwnedFd>>>>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// A picker shown for a request, see [`append_picker`].
+///
+/// If it is dropped before [`PickerGuard::finish`], like when `start` returns early or its
+/// future is dropped, the picker is taken down so no overlay stays on screen.
+pub struct PickerGuard {
+    handle_path: OwnedObjectPath,
+    fd: Option<Arc<OwnedFd>>,
+    finished: bool,
+}
+
+impl PickerGuard {
+    /// Stop tracking the picker, once it is done.
+    ///
+    /// Returns `true` if the request was closed meanwhile, and so the picker cancelled.
+    pub async fn finish(mut self) -> bool {
+        self.finished = true;
+        let mut pickers = PICKERS.lock().await;
+        pickers.remove(&self.handle_path).is_none()
+    }
+}
+
+impl Drop for PickerGuard {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        tracing::info!(
+            "the picker of {} is left behind, take it down",
+            self.handle_path
+        );
+        if let Some(fd) = &self.fd {
+            shutdown_picker(fd);
+        }
+        let handle_path = self.handle_path.clone();
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                PICKERS.lock().await.remove(&handle_path);
+            });
+        }
+    }
+}
+
+/// Track the picker shown on `connection` for the request at `handle_path`.
+///
+/// Cancelling the picker shuts the socket of `connection` down, so nothing else may use it.
+pub async fn append_picker(
+    handle_path: OwnedObjectPath,
+    connection: &wayland_client::Connection,
+) -> PickerGuard {
+    let fd = rustix::io::dup(connection.backend().poll_fd())
+        .inspect_err(|e| tracing::warn!("the picker cannot be taken down, error: {e}"))
+        .ok()
+        .map(Arc::new);
+    let mut pickers = PICKERS.lock().await;
+    pickers.insert(handle_path.clone(), fd.clone());
+    PickerGuard {
+        handle_path,
+        fd,
+        finished: false,
+    }
+}
+
+/// The pending dispatch of the picker fails once its socket is shut down.
+fn shutdown_picker(fd: &OwnedFd) {
+    if let Err(e) = rustix::net::shutdown(fd, rustix::net::Shutdown::ReadWrite) {
+        tracing::warn!("cannot cancel the picker, error: {e}");
+    }
+}
+
+/// Take down the picker of the request at `handle_path`.
+async fn cancel_picker(handle_path: &OwnedObjectPath) {
+    let mut pickers = PICKERS.lock().await;
+    let Some(Some(fd)) = pickers.remove(handle_path) else {
+        return;
+    };
+    shutdown_picker(&fd);
+}
+
 pub struct RequestInterface {
     pub handle_path: OwnedObjectPath,
 }
@@ -10,9 +99,97 @@ impl RequestInterface {
         &self,
         #[zbus(object_server)] server: &zbus::ObjectServer,
     ) -> zbus::fdo::Result<()> {
+        cancel_picker(&self.handle_path).await;
         server
             .remove::<Self, &OwnedObjectPath>(&self.handle_path)
             .await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use zbus::zvariant::ObjectPath;
+
+    use super::*;
+    use crate::testing::serve;
+
+    /// A picker connection, and the end of its socket where the compositor would be.
+    fn picker_socket() -> (wayland_client::Connection, UnixStream) {
+        let (client, compositor) = UnixStream::pair().unwrap();
+        compositor
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        (
+            wayland_client::Connection::from_socket(client).unwrap(),
+            compositor,
+        )
+    }
+
+    /// Whether the picker hung up on the compositor.
+    fn is_down(compositor: &mut UnixStream) -> bool {
+        matches!(compositor.read(&mut [0; 16]), Ok(0))
+    }
+
+    fn request_path(token: &str) -> OwnedObjectPath {
+        ObjectPath::try_from(format!(
+            "/org/freedesktop/portal/desktop/request/1_1/{token}"
+        ))
+        .unwrap()
+        .into()
+    }
+
+    #[tokio::test]
+    async fn closing_the_request_takes_the_picker_down() {
+        let path = request_path("luminous_close");
+        let (connection, mut compositor) = picker_socket();
+        let picker = append_picker(path.clone(), &connection).await;
+        let request = RequestInterface {
+            handle_path: path.clone(),
+        };
+        let (_server, client) = serve(path.as_str(), request).await;
+        client
+            .call_method(
+                None::<&str>,
+                path.as_str(),
+                Some("org.freedesktop.impl.portal.Request"),
+                "Close",
+                &(),
+            )
+            .await
+            .unwrap();
+        assert!(is_down(&mut compositor));
+        assert!(picker.finish().await, "the picker is not cancelled");
+    }
+
+    #[tokio::test]
+    async fn a_picker_left_behind_is_taken_down() {
+        let path = request_path("luminous_left");
+        let (connection, mut compositor) = picker_socket();
+        let picker = append_picker(path.clone(), &connection).await;
+        drop(picker);
+        assert!(is_down(&mut compositor));
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while PICKERS.lock().await.contains_key(&path) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the picker is still tracked");
+    }
+
+    #[tokio::test]
+    async fn a_finished_picker_is_left_alone() {
+        let path = request_path("luminous_finished");
+        let (connection, mut compositor) = picker_socket();
+        let picker = append_picker(path.clone(), &connection).await;
+        assert!(!picker.finish().await, "the picker is cancelled");
+        assert!(!PICKERS.lock().await.contains_key(&path));
+        compositor.set_nonblocking(true).unwrap();
+        assert!(!is_down(&mut compositor));
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use zbus::interface;
+
+use crate::screencast::check_restore_token;
+use crate::session::{CursorMode, PersistMode, SourceType};
+use crate::utils::USER_STATE_DIR;
+
+static RESTORE_TOKENS_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
+    // The tests grant and revoke tokens, which must not end up in the state of the user
+    let state_dir = if cfg!(test) {
+        std::env::temp_dir().join(format!("luminous-test-{}", std::process::id()))
+    } else {
+        USER_STATE_DIR.clone()
+    };
+    state_dir
+        .join("xdg-desktop-portal-luminous")
+        .join("restore_tokens.json")
+});
+
+/// A source cast by a session, enough to pick it again without the picker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreSource {
+    /// Connector name of the output, like `DP-1`, or the ext-foreign-toplevel identifier of
+    /// the window of a [`SourceType::Window`] source without a region
+    pub output_name: String,
+    pub source_type: SourceType,
+    /// The cropped area as `(x, y, width, height)`, logical and relative to the output
+    pub region: Option<(i32, i32, i32, i32)>,
+}
+
+/// What a restore token stands for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreData {
+    /// The application the token was handed to, no one else can use it
+    pub app_id: String,
+    pub sources: Vec<RestoreSource>,
+    pub cursor_mode: CursorMode,
+    pub persist_mode: PersistMode,
+}
+
+pub static RESTORE_TOKENS: LazyLock<Arc<Mutex<HashMap<String, RestoreData>>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(load_restore_state().tokens)));
+
+/// The sources each application cast last, by app id, offered again before the picker.
+///
+/// Locked after [`RESTORE_TOKENS`] when both are, and never across an await.
+pub static LAST_SELECTIONS: LazyLock<std::sync::Mutex<HashMap<String, Vec<RestoreSource>>>> =
+    LazyLock::new(|| std::sync::Mutex::new(load_restore_state().last_selections));
+
+/// What the state file holds.
+#[derive(Debug, Default, Deserialize)]
+struct RestoreState {
+    tokens: HashMap<String, RestoreData>,
+    #[serde(default)]
+    last_selections: HashMap<String, Vec<RestoreSource>>,
+}
+
+/// [`RestoreState`] as it is written, with only the persisted tokens.
+#[derive(Serialize)]
+struct SavedRestoreState<'a> {
+    tokens: HashMap<&'a String, &'a RestoreData>,
+    last_selections: &'a HashMap<String, Vec<RestoreSource>>,
+}
+
+/// Read what an earlier run kept, the tokens kept until explicitly revoked and the last
+/// selections.
+fn load_restore_state() -> RestoreState {
+    let Ok(content) = std::fs::read_to_string(&*RESTORE_TOKENS_PATH) else {
+        return RestoreState::default();
+    };
+    // Older runs only kept the tokens
+    let state = serde_json::from_str(&content).or_else(|e| {
+        serde_json::from_str(&content)
+            .map(|tokens| RestoreState {
+                tokens,
+                ..Default::default()
+            })
+            .map_err(|_| e)
+    });
+    match state {
+        Ok(state) => state,
+        Err(e) => {
+            tracing::warn!("cannot read the restore tokens, error: {e}");
+            RestoreState::default()
+        }
+    }
+}
+
+/// Counts the states taken by [`snapshot_restore_state`], in the order they are taken.
+static SNAPSHOTS_TAKEN: AtomicU64 = AtomicU64::new(0);
+
+/// The number of the state written last, held while a state is written, so that a state
+/// taken earlier never overwrites one taken later.
+static SNAPSHOT_WRITTEN: Mutex<u64> = Mutex::const_new(0);
+
+/// What [`save_restore_state`] writes, numbered in the order it was taken.
+struct RestoreSnapshot {
+    number: u64,
+    content: String,
+}
+
+/// Take the tokens persisted until explicitly revoked, the others die with the process, and
+/// the last selections, to be written once the locks are released.
+///
+/// Called with [`RESTORE_TOKENS`] locked, as `tokens`, so the numbers follow the changes.
+fn snapshot_restore_state(tokens: &HashMap<String, RestoreData>) -> Option<RestoreSnapshot> {
+    let last_selections = LAST_SELECTIONS.lock().unwrap();
+    let kept = SavedRestoreState {
+        tokens: tokens
+            .iter()
+            .filter(|(_, data)| data.persist_mode == PersistMode::ExplicitlyRevoked)
+            .collect(),
+        last_selections: &last_selections,
+    };
+    match serde_json::to_string(&kept) {
+        Ok(content) => Some(RestoreSnapshot {
+            number: SNAPSHOTS_TAKEN.fetch_add(1, Ordering::Relaxed) + 1,
+            content,
+        }),
+        Err(e) => {
+            tracing::warn!("cannot save the restore tokens, error: {e}");
+            None
+        }
+    }
+}
+
+/// Write `snapshot` to the state file, unless a later one is written already.
+async fn save_restore_state(snapshot: Option<RestoreSnapshot>) {
+    let Some(snapshot) = snapshot else {
+        return;
+    };
+    let mut written = SNAPSHOT_WRITTEN.lock().await;
+    if snapshot.number <= *written {
+        return;
+    }
+    let result = async {
+        if let Some(dir) = RESTORE_TOKENS_PATH.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&*RESTORE_TOKENS_PATH, snapshot.content).await
+    };
+    match result.await {
+        Ok(()) => *written = snapshot.number,
+        Err(e) => tracing::warn!("cannot save the restore tokens, error: {e}"),
+    }
+}
+
+/// Store `data` under a new token, and return the token.
+pub async fn append_restore_data(data: RestoreData) -> String {
+    let token = uuid::Uuid::new_v4().to_string();
+    let mut tokens = RESTORE_TOKENS.lock().await;
+    let persisted = data.persist_mode == PersistMode::ExplicitlyRevoked;
+    tokens.insert(token.clone(), data);
+    let snapshot = persisted.then(|| snapshot_restore_state(&tokens)).flatten();
+    drop(tokens);
+    save_restore_state(snapshot).await;
+    token
+}
+
+/// Remember that `app_id` picked `sources`, to offer them to it again next time.
+pub async fn remember_selection(app_id: &str, sources: Vec<RestoreSource>) {
+    let tokens = RESTORE_TOKENS.lock().await;
+    LAST_SELECTIONS
+        .lock()
+        .unwrap()
+        .insert(app_id.to_string(), sources);
+    let snapshot = snapshot_restore_state(&tokens);
+    drop(tokens);
+    save_restore_state(snapshot).await;
+}
+
+/// The sources `app_id` picked last, see [`remember_selection`].
+pub fn last_selection(app_id: &str) -> Option<Vec<RestoreSource>> {
+    LAST_SELECTIONS.lock().unwrap().get(app_id).cloned()
+}
+
+/// What `token` stands for, if it was handed to `app_id`, left in place.
+pub async fn peek_restore_data(token: &str, app_id: &str) -> Option<RestoreData> {
+    let tokens = RESTORE_TOKENS.lock().await;
+    tokens
+        .get(token)
+        .filter(|data| data.app_id == app_id)
+        .cloned()
+}
+
+/// Take what `token` stands for, if it was handed to `app_id`.
+///
+/// A token is only good for one use, it is taken once the casts of the new session are
+/// started, and the caller hands out a new one. Until then it is only peeked at, see
+/// [`peek_restore_data`]. Returns `None` for any token that cannot be used, which is no error.
+pub async fn take_restore_data(token: &str, app_id: &str) -> Option<RestoreData> {
+    let mut tokens = RESTORE_TOKENS.lock().await;
+    if tokens.get(token)?.app_id != app_id {
+        tracing::warn!("{app_id} tried to use a restore token of another application");
+        return None;
+    }
+    let data = tokens.remove(token)?;
+    if data.persist_mode == PersistMode::ExplicitlyRevoked {
+        let snapshot = snapshot_restore_state(&tokens);
+        drop(tokens);
+        save_restore_state(snapshot).await;
+    }
+    Some(data)
+}
+
+/// Forget `token`, so it cannot skip the picker anymore. Whatever is cast already goes on.
+///
+/// Returns whether the token was known.
+pub async fn revoke_restore_token(token: &str) -> bool {
+    let mut tokens = RESTORE_TOKENS.lock().await;
+    let Some(data) = tokens.remove(token) else {
+        return false;
+    };
+    if data.persist_mode == PersistMode::ExplicitlyRevoked {
+        let snapshot = snapshot_restore_state(&tokens);
+        drop(tokens);
+        save_restore_state(snapshot).await;
+    }
+    tracing::info!("the restore token of {} is revoked", data.app_id);
+    true
+}
+
+/// Forget every token handed to `app_id`, and return how many there were.
+pub async fn revoke_app_restore_tokens(app_id: &str) -> usize {
+    let mut tokens = RESTORE_TOKENS.lock().await;
+    let count = tokens.len();
+    tokens.retain(|_, data| data.app_id != app_id);
+    let revoked = count - tokens.len();
+    // What it cast last is not offered again either
+    let removed_selection = LAST_SELECTIONS.lock().unwrap().remove(app_id).is_some();
+    if removed_selection || revoked != 0 {
+        let snapshot = snapshot_restore_state(&tokens);
+        drop(tokens);
+        save_restore_state(snapshot).await;
+    }
+    if revoked != 0 {
+        tracing::info!("{revoked} restore tokens of {app_id} are revoked");
+    }
+    revoked
+}
+
+/// Ask the user to confirm a revoke asked for over D-Bus, which any client of the session bus
+/// can call.
+async fn confirm_revoke(information: String) -> bool {
+    let title = "Revoke the restore tokens".to_string();
+    tokio::task::spawn_blocking(move || accessdialog::confirmgui(title, information))
+        .await
+        .unwrap_or_default()
+}
+
+/// Lets the user list the applications that can cast without the picker, and revoke them,
+/// for `busctl`.
+pub struct RestoreTokensBackend;
+
+#[interface(name = "org.freedesktop.impl.portal.desktop.luminous.RestoreTokens")]
+impl RestoreTokensBackend {
+    /// The applications holding tokens that can still be used, as `(app_id, tokens,
+    /// persisted)`, where `persisted` tells if any of them outlives the portal.
+    ///
+    /// The tokens themselves are never listed, any client could cast with them otherwise.
+    async fn list(&self) -> Vec<(String, u32, bool)> {
+        let tokens = RESTORE_TOKENS.lock().await;
+        let mut apps: Vec<(String, u32, bool)> = Vec::new();
+        for data in tokens.values() {
+            let persisted = data.persist_mode == PersistMode::ExplicitlyRevoked;
+            match apps.iter_mut().find(|(app_id, ..)| *app_id == data.app_id) {
+                Some(app) => {
+                    app.1 += 1;
+                    app.2 |= persisted;
+                }
+                None => apps.push((data.app_id.clone(), 1, persisted)),
+            }
+        }
+        apps.sort();
+        apps
+    }
+
+    /// Whether `token` would skip the picker for `app_id` now, and the sources it stands for,
+    /// see [`check_restore_token`]. The token stays good for a start.
+    async fn check(&self, token: String, app_id: String) -> zbus::fdo::Result<(bool, Vec<String>)> {
+        Ok(match check_restore_token(&token, &app_id).await? {
+            Some(sources) => (true, sources),
+            None => (false, Vec::new()),
+        })
+    }
+
+    /// Forget `token` once the user confirms, see [`revoke_restore_token`].
+    async fn revoke(&self, token: String) -> bool {
+        let app_id = RESTORE_TOKENS
+            .lock()
+            .await
+            .get(&token)
+            .map(|data| data.app_id.clone());
+        let Some(app_id) = app_id else {
+            return false;
+        };
+        let information = format!("{app_id} can cast the screen without the picker. Revoke it?");
+        if !confirm_revoke(information).await {
+            tracing::info!("the user kept the restore token of {app_id}");
+            return false;
+        }
+        revoke_restore_token(&token).await
+    }
+
+    /// Forget every token of `app_id` once the user confirms, see
+    /// [`revoke_app_restore_tokens`].
+    async fn revoke_app(&self, app_id: String) -> u32 {
+        let held = RESTORE_TOKENS
+            .lock()
+            .await
+            .values()
+            .any(|data| data.app_id == app_id);
+        if !held && last_selection(&app_id).is_none() {
+            return 0;
+        }
+        let information = format!(
+            "{app_id} can cast the screen without the picker, or as it did last time. Revoke \
+             all of it?"
+        );
+        if !confirm_revoke(information).await {
+            tracing::info!("the user kept the restore tokens of {app_id}");
+            return 0;
+        }
+        revoke_app_restore_tokens(&app_id).await as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::testing::{lock_globals, serve};
+
+    #[tokio::test]
+    async fn the_state_file_ends_up_with_every_change() {
+        let _globals = lock_globals().await;
+        let grants = (0..10).map(|index| {
+            append_restore_data(RestoreData {
+                app_id: format!("org.luminous.Test{index}"),
+                sources: Vec::new(),
+                cursor_mode: CursorMode::Hidden,
+                persist_mode: PersistMode::ExplicitlyRevoked,
+            })
+        });
+        let granted: HashSet<String> = futures::future::join_all(grants)
+            .await
+            .into_iter()
+            .collect();
+        assert_eq!(granted.len(), 10);
+        let saved: HashSet<String> = load_restore_state().tokens.into_keys().collect();
+        assert_eq!(saved, granted);
+
+        let revoked = granted.iter().next().unwrap();
+        assert!(revoke_restore_token(revoked).await);
+        let saved: HashSet<String> = load_restore_state().tokens.into_keys().collect();
+        assert_eq!(saved.len(), 9);
+        assert!(!saved.contains(revoked));
+    }
+
+    #[tokio::test]
+    async fn list_tells_the_apps_and_not_their_tokens() {
+        let _globals = lock_globals().await;
+        let grant = |app_id: &str, persist_mode| {
+            append_restore_data(RestoreData {
+                app_id: app_id.to_string(),
+                sources: Vec::new(),
+                cursor_mode: CursorMode::Hidden,
+                persist_mode,
+            })
+        };
+        let tokens = [
+            grant("org.luminous.Test", PersistMode::Application).await,
+            grant("org.luminous.Test", PersistMode::ExplicitlyRevoked).await,
+            grant("org.luminous.Other", PersistMode::Application).await,
+        ];
+        let path = "/org/freedesktop/portal/desktop";
+        let (_server, client) = serve(path, RestoreTokensBackend).await;
+        let reply = client
+            .call_method(
+                None::<&str>,
+                path,
+                Some("org.freedesktop.impl.portal.desktop.luminous.RestoreTokens"),
+                "List",
+                &(),
+            )
+            .await
+            .unwrap();
+        let apps: Vec<(String, u32, bool)> = reply.body().deserialize().unwrap();
+        assert_eq!(
+            apps,
+            [
+                ("org.luminous.Other".to_string(), 1, false),
+                ("org.luminous.Test".to_string(), 2, true),
+            ]
+        );
+        // Nothing else is in the reply to carry a token
+        assert_eq!(reply.body().signature().to_string(), "a(sub)");
+        assert_eq!(RESTORE_TOKENS.lock().await.len(), tokens.len());
+    }
+}
@@ -1,9 +1,15 @@
 use std::collections::HashMap;
 
+use libwayshot::output::OutputInfo;
+use libwayshot::{CaptureRegion, WayshotConnection};
+use libwaysip::Point;
 use libwaysip::state::WlOutputInfo;
-use zbus::interface;
+use screenshotdialog::ScreenInfo;
+use zbus::{interface, message::Header};
 
-use zbus::zvariant::{DeserializeDict, ObjectPath, OwnedValue, SerializeDict, Type, Value};
+use zbus::zvariant::{
+    DeserializeDict, ObjectPath, OwnedObjectPath, OwnedValue, SerializeDict, Type, Value,
+};
 
 use enumflags2::BitFlags;
 
@@ -11,13 +17,27 @@ use serde::{Deserialize, Serialize};
 
 use std::sync::Arc;
 use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::Mutex;
 
 use crate::PortalResponse;
-use crate::pipewirethread::ScreencastThread;
-use crate::request::RequestInterface;
+use crate::pipewirethread::{
+    CastTarget, ScreencastThread, ToplevelInfo, list_toplevels, output_under_cursor, refresh_rates,
+    watch_outputs,
+};
+use crate::request::{RequestInterface, append_picker};
+use crate::restore::{
+    RestoreData, RestoreSource, append_restore_data, last_selection, peek_restore_data,
+    remember_selection, take_restore_data,
+};
 use crate::session::{
     CursorMode, PersistMode, SESSIONS, Session, SessionType, SourceType, append_session,
+    close_session, handle_token,
+};
+use crate::settings::SETTING_CONFIG;
+use crate::utils::{
+    CaptureBackend, connect_picker, connect_wayshot, output_layout, pick_area_with_command,
+    region_picker,
 };
 
 use libwaysip::SelectionType;
@@ -46,16 +66,27 @@ pub struct SelectSourcesOptions {
 
 #[derive(Clone, Serialize, Deserialize, Type, Default, Debug)]
 /// A PipeWire stream.
-pub struct Stream(u32, StreamProperties);
+pub struct Stream(pub u32, pub StreamProperties);
 
 #[derive(Clone, SerializeDict, DeserializeDict, Default, Type, Debug)]
 /// The stream properties.
 #[zvariant(signature = "dict")]
-struct StreamProperties {
-    id: Option<String>,
-    position: Option<(i32, i32)>,
-    size: Option<(i32, i32)>,
-    source_type: Option<SourceType>,
+pub struct StreamProperties {
+    /// The connector name of the captured output, like `DP-1`, as announced by `wl_output`.
+    ///
+    /// It stays the same for the same connector, across restarts and output re-enumeration.
+    /// A region adds its area, like `DP-1:0,0:640x480`, so that each stream of a session,
+    /// which may cast several regions of one output, has an id of its own.
+    pub id: Option<String>,
+    pub position: Option<(i32, i32)>,
+    pub size: Option<(i32, i32)>,
+    /// What the user picked for this very stream. A screen, or a box dragged on it, is a
+    /// [`SourceType::Monitor`], and only a window picked from the list a [`SourceType::Window`]
+    pub source_type: Option<SourceType>,
+    /// Not in the spec, the frames per second of the stream, see
+    /// [`ScreencastThread::settled_framerate`]. Frames are held back to keep to it, even when
+    /// the consumer is driven faster
+    pub framerate: Option<u32>,
 }
 
 // TODO: this is copy from ashpd, but the dict is a little different from xdg_desktop_portal
@@ -67,60 +98,1119 @@ struct StartReturnValue {
     restore_token: Option<String>,
 }
 
-pub type CastSessionData = (String, ScreencastThread);
+pub type CastSessionData = (String, ScreencastThread, StreamProperties);
 pub static CAST_SESSIONS: LazyLock<Arc<Mutex<Vec<CastSessionData>>>> =
     LazyLock::new(|| Arc::new(Mutex::new(Vec::new())));
 
-pub async fn append_cast_session(session: CastSessionData) {
-    let mut sessions = CAST_SESSIONS.lock().await;
-    sessions.push(session)
+/// What the first start of a session settled on, as `(persist_mode, restore_token)`, told
+/// again to the starts after it.
+type Granted = (u32, Option<String>);
+
+/// One lock per session being started, so concurrent `start` calls of a session cast once.
+/// It holds what the start granted once the session is started.
+static START_LOCKS: LazyLock<Mutex<HashMap<String, Arc<Mutex<Option<Granted>>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Take the start lock of the session at `path`, held until its casts are appended.
+async fn lock_start(path: &str) -> tokio::sync::OwnedMutexGuard<Option<Granted>> {
+    let lock = START_LOCKS
+        .lock()
+        .await
+        .entry(path.to_string())
+        .or_default()
+        .clone();
+    lock.lock_owned().await
 }
 
+/// Stop every stream of the session at `path`, there is one per selected source.
 pub async fn remove_cast_session(path: &str) {
+    START_LOCKS.lock().await.remove(path);
+    let mut sessions = CAST_SESSIONS.lock().await;
+    sessions.retain(|the_session| {
+        if the_session.0 != path {
+            return true;
+        }
+        the_session.1.stop();
+        tracing::info!(
+            "session {} is stopped, {:?}",
+            the_session.0,
+            the_session.1.stats()
+        );
+        false
+    });
+}
+
+/// Whether no consumer is connected to any stream of the session at `path`, which is also
+/// the case before it is started.
+pub async fn is_cast_idle(path: &str) -> bool {
+    CAST_SESSIONS
+        .lock()
+        .await
+        .iter()
+        .filter(|the_session| the_session.0 == path)
+        .all(|the_session| the_session.1.is_idle())
+}
+
+/// Drop the stream `node_id` of the session at `path`, once its cast ended on its own.
+pub async fn remove_cast_stream(path: &str, node_id: u32) {
     let mut sessions = CAST_SESSIONS.lock().await;
-    let Some(index) = sessions
+    sessions.retain(|the_session| the_session.0 != path || the_session.1.node_id() != node_id);
+    tracing::info!("stream {node_id} of session {path} is removed");
+    let ended = !sessions.iter().any(|the_session| the_session.0 == path);
+    drop(sessions);
+    if ended {
+        close_session(path, "All the streams of the session have ended").await;
+    }
+}
+
+/// Get the outputs anew for a cast thread, and find the output called `output_name` among
+/// them, or the whole desktop for a [`SourceType::Virtual`] source, or the window it
+/// identifies for a [`SourceType::Window`] source.
+fn connect_output(
+    output_name: &str,
+    source_type: SourceType,
+    region: Option<CaptureRegion>,
+) -> zbus::fdo::Result<(WayshotConnection, CastTarget)> {
+    let connection = connect_wayshot()?;
+    if source_type == SourceType::Virtual {
+        return Ok((connection, CastTarget::Desktop));
+    }
+    if source_type == SourceType::Window {
+        let identifier = output_name.to_string();
+        return Ok((connection, CastTarget::Toplevel { identifier }));
+    }
+    let output = connection
+        .get_all_outputs()
+        .iter()
+        .find(|output| output.name == output_name)
+        .map(|output| output.wl_output.clone())
+        .ok_or_else(|| zbus::Error::Failure(format!("The output {output_name} is gone")))?;
+    Ok((connection, CastTarget::Output { output, region }))
+}
+
+/// The source picked by the user, ready to be handed to [`ScreencastThread::start_cast`].
+#[derive(Debug, Clone)]
+pub struct SelectedSource {
+    /// Connector name of the output, or the identifier of a window, see [`toplevel_source`]
+    output_name: String,
+    source_type: SourceType,
+    capture_region: Option<CaptureRegion>,
+    width: u32,
+    height: u32,
+    /// Logical position of the source in the compositor space
+    position: (i32, i32),
+    /// Logical size of the source, the cropped one for a region
+    size: (i32, i32),
+}
+
+impl SelectedSource {
+    /// The id of the stream of this source, see [`StreamProperties::id`].
+    fn stream_id(&self) -> String {
+        match self.capture_region {
+            Some(region) => format!(
+                "{}:{},{}:{}x{}",
+                self.output_name,
+                region.x_coordinate,
+                region.y_coordinate,
+                region.width,
+                region.height
+            ),
+            None => self.output_name.clone(),
+        }
+    }
+
+    fn to_restore_source(&self) -> RestoreSource {
+        RestoreSource {
+            output_name: self.output_name.clone(),
+            source_type: self.source_type,
+            region: self.capture_region.map(|region| {
+                (
+                    region.x_coordinate,
+                    region.y_coordinate,
+                    region.width,
+                    region.height,
+                )
+            }),
+        }
+    }
+}
+
+/// The physical pixels per logical pixel of an output with `mode`, whose logical size is
+/// `logical`.
+///
+/// The mode is not turned with the output while the logical size is, so their longest sides
+/// are compared.
+fn buffer_scale(mode: (i32, i32), logical: (i32, i32)) -> f64 {
+    let logical_side = logical.0.max(logical.1);
+    if logical_side > 0 {
+        mode.0.max(mode.1) as f64 / logical_side as f64
+    } else {
+        1.
+    }
+}
+
+/// The id of the stream of the whole desktop, where other streams have their connector name
+const DESKTOP_STREAM_ID: &str = "desktop";
+
+/// All the outputs as one [`SourceType::Virtual`] source, in logical pixels.
+fn desktop_source(backend: &impl CaptureBackend) -> SelectedSource {
+    let (x, y, width, height) = output_layout(backend);
+    SelectedSource {
+        output_name: DESKTOP_STREAM_ID.to_string(),
+        source_type: SourceType::Virtual,
+        capture_region: None,
+        width,
+        height,
+        position: (x, y),
+        size: (width as i32, height as i32),
+    }
+}
+
+/// The window with the ext-foreign-toplevel `identifier`, a [`SourceType::Window`] source
+/// without a region. Its size is only known once it is cast.
+fn toplevel_source(identifier: &str) -> SelectedSource {
+    SelectedSource {
+        output_name: identifier.to_string(),
+        source_type: SourceType::Window,
+        capture_region: None,
+        width: 0,
+        height: 0,
+        position: (0, 0),
+        size: (0, 0),
+    }
+}
+
+/// The connector name of the output at the origin of the layout, or else the first one.
+///
+/// Wayland has no primary output, the one at the origin is where most compositors put it.
+fn primary_output(backend: &impl CaptureBackend) -> Option<String> {
+    let outputs = backend.outputs();
+    outputs
+        .iter()
+        .find(|output| (output.dimensions.x, output.dimensions.y) == (0, 0))
+        .or(outputs.first())
+        .map(|output| output.name.clone())
+}
+
+/// Whether `token` of `app_id` would restore its sources now, without the picker, and the
+/// ids of the streams it would cast. Nothing is captured, and the token is left for a start.
+///
+/// `None` if the token is unknown, revoked, of another application, or if an output it
+/// casts is gone. Windows are only found once they are cast.
+pub async fn check_restore_token(
+    token: &str,
+    app_id: &str,
+) -> zbus::fdo::Result<Option<Vec<String>>> {
+    let Some(data) = peek_restore_data(token, app_id).await else {
+        return Ok(None);
+    };
+    let connection = connect_wayshot()?;
+    Ok(data
+        .sources
+        .iter()
+        .map(|source| restore_source(&connection, source).map(|source| source.output_name))
+        .collect())
+}
+
+/// Pick `source` again on the current outputs, `None` if its output is gone.
+pub fn restore_source(
+    backend: &impl CaptureBackend,
+    source: &RestoreSource,
+) -> Option<SelectedSource> {
+    if source.source_type == SourceType::Virtual {
+        return Some(desktop_source(backend));
+    }
+    if source.source_type == SourceType::Window && source.region.is_none() {
+        return Some(toplevel_source(&source.output_name));
+    }
+    let output = backend
+        .outputs()
         .iter()
-        .position(|the_session| the_session.0 == path)
-    else {
-        return;
+        .find(|output| output.name == source.output_name)?;
+    let dimensions = &output.dimensions;
+    let Some((x, y, width, height)) = source.region else {
+        return Some(SelectedSource {
+            output_name: source.output_name.clone(),
+            source_type: source.source_type,
+            capture_region: None,
+            width: output.mode.width as u32,
+            height: output.mode.height as u32,
+            position: (dimensions.x, dimensions.y),
+            size: (dimensions.width, dimensions.height),
+        });
+    };
+    let scale = buffer_scale(
+        (output.mode.width, output.mode.height),
+        (dimensions.width, dimensions.height),
+    );
+    // Tokens from before windows were cast on their own may have a box as a window
+    Some(SelectedSource {
+        output_name: source.output_name.clone(),
+        source_type: SourceType::Monitor,
+        capture_region: Some(CaptureRegion {
+            x_coordinate: x,
+            y_coordinate: y,
+            width,
+            height,
+        }),
+        width: (width as f64 * scale).round() as u32,
+        height: (height as f64 * scale).round() as u32,
+        position: (dimensions.x + x, dimensions.y + y),
+        size: (width, height),
+    })
+}
+
+/// Let the user pick what to cast out of the requested `source_types`.
+///
+/// Once [`SourceType::Window`] is allowed, the user picks a window out of a list of those
+/// open, along with the screens if monitors are allowed too. Otherwise the user clicks a
+/// screen, or drags a box on it when a `region` is asked for, where a plain click without
+/// dragging picks the whole screen under the pointer. Boxes are always monitor regions.
+///
+/// With `list_picker`, screens are picked from a list of the outputs instead of by a click.
+/// Boxes are dragged with the `region_picker` command when there is one. When the click
+/// picker cannot run, as on a compositor without layer shell, or the windows cannot be
+/// listed, screens are picked from the list instead, provided monitors are allowed.
+///
+/// Returns `None` when the user cancels the picker.
+fn select_source(
+    connection: &WayshotConnection,
+    source_types: BitFlags<SourceType>,
+    region: bool,
+    list_picker: bool,
+    region_picker: Option<&str>,
+) -> zbus::fdo::Result<Option<SelectedSource>> {
+    let monitors = source_types.contains(SourceType::Monitor);
+    if source_types.contains(SourceType::Window) && !region {
+        match list_toplevels(connection) {
+            Ok(windows) => {
+                tracing::info!("pick the window from the list");
+                return Ok(select_window_from_list(connection, windows, monitors));
+            }
+            Err(e) if monitors => {
+                tracing::warn!("cannot list the windows, pick a screen instead: {e}");
+            }
+            Err(e) => {
+                return Err(zbus::Error::Failure(format!(
+                    "The windows cannot be listed to pick one, error: {e}"
+                ))
+                .into());
+            }
+        }
+    }
+    let selection_type = if region {
+        SelectionType::Area
+    } else {
+        SelectionType::Screen
+    };
+    if list_picker && matches!(selection_type, SelectionType::Screen) {
+        tracing::info!("pick the output from the list");
+        return Ok(select_output_from_list(connection));
+    }
+    if matches!(selection_type, SelectionType::Area)
+        && let Some(command) = region_picker
+        && let Some(selected) = select_area_with_command(connection, command)
+    {
+        tracing::info!("pick the area with {command}");
+        return selected;
+    }
+    tracing::info!("pick the {selection_type:?} with libwaysip");
+    let info = match libwaysip::get_area(
+        Some(libwaysip::WaysipConnection {
+            connection: &connection.conn,
+            globals: &connection.globals,
+        }),
+        selection_type,
+    ) {
+        Ok(Some(info)) => info,
+        Ok(None) => return Ok(None),
+        Err(e) if monitors => {
+            tracing::warn!("libwaysip cannot run, pick the output from the list instead: {e}");
+            return Ok(select_output_from_list(connection));
+        }
+        Err(e) => return Err(zbus::Error::Failure(format!("wayland error, {e}")).into()),
+    };
+
+    let Point { x, y } = info.left_top_point();
+    let (area_width, area_height) = (info.width(), info.height());
+    let screen_info = info.screen_info;
+    let WlOutputInfo {
+        name: output_name,
+        size: (output_width, output_height),
+        ..
+    } = screen_info.output_info;
+
+    let empty_area = area_width == 0 || area_height == 0;
+    if matches!(selection_type, SelectionType::Screen) || empty_area {
+        return Ok(Some(SelectedSource {
+            output_name,
+            source_type: SourceType::Monitor,
+            capture_region: None,
+            width: output_width as u32,
+            height: output_height as u32,
+            position: (screen_info.start_x, screen_info.start_y),
+            size: (screen_info.width, screen_info.height),
+        }));
+    }
+    if x < screen_info.start_x
+        || y < screen_info.start_y
+        || x + area_width > screen_info.start_x + screen_info.width
+        || y + area_height > screen_info.start_y + screen_info.height
+    {
+        return Err(zbus::Error::Failure(
+            "The selected area spans more than one output, select it within a single screen"
+                .to_string(),
+        )
+        .into());
+    }
+
+    // The area is picked in logical coordinates, while the buffers hold physical pixels
+    let scale = buffer_scale(
+        (output_width, output_height),
+        (screen_info.width, screen_info.height),
+    );
+    Ok(Some(SelectedSource {
+        output_name,
+        source_type: SourceType::Monitor,
+        capture_region: Some(CaptureRegion {
+            x_coordinate: x - screen_info.start_x,
+            y_coordinate: y - screen_info.start_y,
+            width: area_width,
+            height: area_height,
+        }),
+        width: (area_width as f64 * scale).round() as u32,
+        height: (area_height as f64 * scale).round() as u32,
+        position: (x, y),
+        size: (area_width, area_height),
+    }))
+}
+
+/// Let the user drag a box with the external `command`, see [`select_source`].
+///
+/// Returns `None` when `command` is not found.
+fn select_area_with_command(
+    connection: &WayshotConnection,
+    command: &str,
+) -> Option<zbus::fdo::Result<Option<SelectedSource>>> {
+    let area = match pick_area_with_command(command)? {
+        Ok(Some(area)) => area,
+        Ok(None) => return Some(Ok(None)),
+        Err(e) => {
+            return Some(Err(zbus::Error::Failure(format!(
+                "the region picker failed, error: {e}"
+            ))
+            .into()));
+        }
+    };
+    let CaptureRegion {
+        x_coordinate: x,
+        y_coordinate: y,
+        width,
+        height,
+    } = area;
+    let Some(output) = connection.get_all_outputs().iter().find(|output| {
+        let dimensions = &output.dimensions;
+        x >= dimensions.x
+            && y >= dimensions.y
+            && x + width <= dimensions.x + dimensions.width
+            && y + height <= dimensions.y + dimensions.height
+    }) else {
+        return Some(Err(zbus::Error::Failure(
+            "The selected area spans more than one output, select it within a single screen"
+                .to_string(),
+        )
+        .into()));
+    };
+    let empty_area = width == 0 || height == 0;
+    let source = RestoreSource {
+        output_name: output.name.clone(),
+        source_type: SourceType::Monitor,
+        region: (!empty_area).then(|| {
+            (
+                x - output.dimensions.x,
+                y - output.dimensions.y,
+                width,
+                height,
+            )
+        }),
+    };
+    Some(Ok(restore_source(connection, &source)))
+}
+
+/// Let the user pick one of the open `windows` out of a list, or a whole output, listed
+/// first, if `monitors` are allowed.
+fn select_window_from_list(
+    connection: &WayshotConnection,
+    windows: Vec<ToplevelInfo>,
+    monitors: bool,
+) -> Option<SelectedSource> {
+    let outputs: &[OutputInfo] = if monitors {
+        connection.get_all_outputs()
+    } else {
+        &[]
+    };
+    let infos = outputs
+        .iter()
+        .map(|output| ScreenInfo {
+            name: output.name.clone().into(),
+            description: output.description.clone().into(),
+        })
+        .chain(windows.iter().map(|window| ScreenInfo {
+            name: if window.title.is_empty() {
+                window.app_id.clone().into()
+            } else {
+                window.title.clone().into()
+            },
+            description: window.app_id.clone().into(),
+        }))
+        .collect();
+    let index = screenshotdialog::selectwindow(infos)?;
+    let Some(output) = outputs.get(index) else {
+        let window = windows.get(index - outputs.len())?;
+        return Some(toplevel_source(&window.identifier));
+    };
+    restore_source(
+        connection,
+        &RestoreSource {
+            output_name: output.name.clone(),
+            source_type: SourceType::Monitor,
+            region: None,
+        },
+    )
+}
+
+/// Let the user pick a whole output out of a list of the connectors.
+///
+/// Unlike the click picker, the list cannot be closed by cancelling the request.
+fn select_output_from_list(connection: &WayshotConnection) -> Option<SelectedSource> {
+    let outputs = connection.get_all_outputs();
+    let screen_infos = outputs
+        .iter()
+        .map(|output| ScreenInfo {
+            name: output.name.clone().into(),
+            description: output.description.clone().into(),
+        })
+        .collect();
+    let output = outputs.get(screenshotdialog::selectoutput(screen_infos)?)?;
+    restore_source(
+        connection,
+        &RestoreSource {
+            output_name: output.name.clone(),
+            source_type: SourceType::Monitor,
+            region: None,
+        },
+    )
+}
+
+/// Ask the user whether `app_id` casts the `sources` it cast last, instead of picking anew.
+async fn ask_last_selection(app_id: &str, sources: &[SelectedSource]) -> bool {
+    let names: Vec<String> = sources
+        .iter()
+        .map(|source| match source.capture_region {
+            Some(_) => format!("an area of {}", source.output_name),
+            None => source.output_name.clone(),
+        })
+        .collect();
+    let title = "Cast the last selection".to_string();
+    let information = format!(
+        "{app_id} wants to cast the screen. Cast {} like last time? Pick anew otherwise",
+        names.join(", ")
+    );
+    tokio::task::spawn_blocking(move || accessdialog::confirmgui(title, information))
+        .await
+        .unwrap_or_default()
+}
+
+/// Tell the user with a desktop notification that `app_id` casts the `streams` now.
+///
+/// Only a warning is logged when there is no notification server, the cast goes on anyway.
+async fn notify_cast_started(app_id: String, streams: Vec<String>) {
+    let connection = crate::get_connection().await;
+    let summary = "Your screen is being shared";
+    let body = format!("{app_id} casts {}", streams.join(", "));
+    let hints: HashMap<&str, Value<'_>> = HashMap::from([("urgency", Value::U8(1))]);
+    let result = connection
+        .call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.freedesktop.Notifications"),
+            "Notify",
+            &(
+                "xdg-desktop-portal-luminous",
+                0u32,
+                "video-display",
+                summary,
+                body.as_str(),
+                Vec::<&str>::new(),
+                hints,
+                -1i32,
+            ),
+        )
+        .await;
+    if let Err(e) = result {
+        tracing::warn!("cannot tell the user that {app_id} casts the screen: {e}");
+    }
+}
+
+/// Run the picker until the user is done, see [`select_source`].
+///
+/// With `multiple`, the picker is shown again after each pick, until the user cancels it, so
+/// several regions, of one output or of several, can be cast each in a stream of its own.
+fn pick_sources(
+    connection: &WayshotConnection,
+    source_types: BitFlags<SourceType>,
+    multiple: bool,
+    region: bool,
+    list_picker: bool,
+    region_picker: Option<&str>,
+) -> zbus::fdo::Result<Vec<SelectedSource>> {
+    let mut sources: Vec<SelectedSource> = Vec::new();
+    while let Some(source) =
+        select_source(connection, source_types, region, list_picker, region_picker)?
+    {
+        let restore = source.to_restore_source();
+        if sources
+            .iter()
+            .any(|picked| picked.to_restore_source() == restore)
+        {
+            tracing::warn!("{} is already selected", source.stream_id());
+        } else {
+            sources.push(source);
+        }
+        if !multiple {
+            break;
+        }
+    }
+    Ok(sources)
+}
+
+/// What the picker lets the user pick, see [`pick_sources`].
+#[derive(Debug, Clone, Copy)]
+pub struct PickerOptions {
+    pub source_types: BitFlags<SourceType>,
+    pub multiple: bool,
+    /// Drag a box instead of clicking a whole screen
+    pub region: bool,
+}
+
+/// The compositor and PipeWire a start casts with, [`WaylandCasts`] for the real ones.
+///
+/// `start` takes it along with [`CaptureBackend`], so the tests can start sessions over D-Bus
+/// with canned outputs and picks.
+pub trait CastBackend: Clone + Send + Sync + 'static {
+    type Outputs: CaptureBackend + Send + Sync + 'static;
+
+    /// Connect to the compositor and get the outputs, which takes roundtrips.
+    fn connect(&self) -> zbus::fdo::Result<Self::Outputs>;
+
+    /// The connector name of the output the pointer is over, which takes roundtrips too.
+    fn output_under_cursor(&self, outputs: &Self::Outputs) -> anyhow::Result<Option<String>>;
+
+    /// Show the picker of the request at `request_path` until the user is done, see
+    /// [`pick_sources`]. Returns `None` when the request is closed meanwhile.
+    fn pick(
+        &self,
+        request_path: OwnedObjectPath,
+        options: PickerOptions,
+    ) -> impl Future<Output = zbus::fdo::Result<Option<Vec<SelectedSource>>>> + Send;
+
+    /// Ask the user whether `app_id` casts the `sources` it cast last, instead of picking
+    /// anew, see [`ask_last_selection`].
+    fn confirm_last_selection(
+        &self,
+        app_id: String,
+        sources: Vec<SelectedSource>,
+    ) -> impl Future<Output = bool> + Send;
+
+    /// Cast `source`, see [`ScreencastThread::start_cast`]. Fails with what the frontend is
+    /// told.
+    fn start_cast(
+        &self,
+        source: &SelectedSource,
+        cursor_mode: CursorMode,
+        framerate: Option<u32>,
+        max_size: (Option<u32>, Option<u32>),
+        on_ended: impl FnOnce(u32) + Send + 'static,
+    ) -> impl Future<Output = Result<ScreencastThread, String>> + Send;
+}
+
+/// The compositor the portal runs in, and the PipeWire daemon of the session.
+#[derive(Debug, Clone, Copy)]
+pub struct WaylandCasts;
+
+impl CastBackend for WaylandCasts {
+    type Outputs = WayshotConnection;
+
+    fn connect(&self) -> zbus::fdo::Result<WayshotConnection> {
+        connect_wayshot()
+    }
+
+    fn output_under_cursor(&self, outputs: &WayshotConnection) -> anyhow::Result<Option<String>> {
+        output_under_cursor(outputs)
+    }
+
+    async fn pick(
+        &self,
+        request_path: OwnedObjectPath,
+        options: PickerOptions,
+    ) -> zbus::fdo::Result<Option<Vec<SelectedSource>>> {
+        // Taking the picker down shuts its socket, which must not be the one of the casts
+        let connection = connect_picker()?;
+        let picker = append_picker(request_path, &connection.conn).await;
+        let list_picker = SETTING_CONFIG.lock().await.uses_list_picker();
+        let region_picker = region_picker().await;
+        // The picker runs off the executor, so closing the request can cancel it meanwhile
+        let picked = tokio::task::spawn_blocking(move || {
+            pick_sources(
+                &connection,
+                options.source_types,
+                options.multiple,
+                options.region,
+                list_picker,
+                region_picker.as_deref(),
+            )
+        })
+        .await;
+        if picker.finish().await {
+            return Ok(None);
+        }
+        let sources = picked
+            .map_err(|e| zbus::Error::Failure(format!("the picker failed, error: {e}")))??;
+        Ok(Some(sources))
+    }
+
+    async fn confirm_last_selection(&self, app_id: String, sources: Vec<SelectedSource>) -> bool {
+        ask_last_selection(&app_id, &sources).await
+    }
+
+    async fn start_cast(
+        &self,
+        source: &SelectedSource,
+        cursor_mode: CursorMode,
+        framerate: Option<u32>,
+        max_size: (Option<u32>, Option<u32>),
+        on_ended: impl FnOnce(u32) + Send + 'static,
+    ) -> Result<ScreencastThread, String> {
+        let output_name = source.output_name.clone();
+        let source_type = source.source_type;
+        let capture_region = source.capture_region;
+        let (connection, target) = tokio::task::spawn_blocking(move || {
+            connect_output(&output_name, source_type, capture_region)
+        })
+        .await
+        .unwrap_or_else(|e| Err(zbus::Error::Failure(format!("cannot connect, error: {e}")).into()))
+        .map_err(|e| e.to_string())?;
+        ScreencastThread::start_cast(
+            cursor_mode,
+            source.width,
+            source.height,
+            framerate,
+            max_size,
+            target,
+            connection,
+            on_ended,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!(
+                "cannot start the pipewire stream of {}: {e}",
+                source.output_name
+            );
+            format!("cannot start pipewire stream, error: {e}")
+        })
+    }
+}
+
+/// What a start asks for that skips the picker or bears on it, see [`choose_sources`].
+#[derive(Debug, Default)]
+struct SourceOptions {
+    /// The connector of the output to cast, from the `output` option or the pointer
+    output_name: Option<String>,
+    /// The ext-foreign-toplevel identifier of the window to cast
+    toplevel: Option<String>,
+    /// Cast the configured or the primary output when nothing else is asked for
+    headless: bool,
+    /// The dialogs must be modal to the window of the application, which they cannot be
+    modal: bool,
+}
+
+/// What [`choose_sources`] settled on.
+enum ChosenSources {
+    /// Cast `sources`, which are remembered for the next time only when they were `picked`
+    Sources {
+        sources: Vec<SelectedSource>,
+        cursor_mode: CursorMode,
+        picked: bool,
+    },
+    /// Nothing is cast, the start answers this
+    Response(PortalResponse<StartReturnValue>),
+}
+
+/// Find out what `session` casts out of the outputs of `backend`, without casting anything.
+///
+/// What the `options` ask for goes first, then the restore token of the session, then the
+/// last selection if the user agrees, and `pick` shows the picker only when none of them
+/// gives a source. `pick` returns `None` when the request is closed meanwhile.
+async fn choose_sources<F, P, A, Q>(
+    backend: &impl CaptureBackend,
+    session: &Session,
+    options: SourceOptions,
+    pick: F,
+    ask: A,
+) -> zbus::fdo::Result<ChosenSources>
+where
+    F: FnOnce() -> P,
+    P: Future<Output = zbus::fdo::Result<Option<Vec<SelectedSource>>>>,
+    A: FnOnce(Vec<SelectedSource>) -> Q,
+    Q: Future<Output = bool>,
+{
+    let session_handle = session.handle_path.as_str();
+    let app_id = session.app_id.as_str();
+    let SourceOptions {
+        output_name,
+        toplevel,
+        headless,
+        modal,
+    } = options;
+    let output_name = match output_name {
+        None if headless => {
+            let configured = SETTING_CONFIG.lock().await.headless_output.clone();
+            let Some(output_name) = configured.or_else(|| primary_output(backend)) else {
+                tracing::warn!("{session_handle} is headless, but there is no output");
+                return Ok(ChosenSources::Response(PortalResponse::Error(
+                    "There is no output to cast without a picker".to_string(),
+                )));
+            };
+            tracing::info!("{session_handle} is headless, cast {output_name}");
+            Some(output_name)
+        }
+        output_name => output_name,
     };
-    sessions[index].1.stop();
-    tracing::info!("session {} is stopped", sessions[index].0);
-    sessions.remove(index);
+    // Like the spec wants, a token that is unknown, revoked or used already is ignored,
+    // the user picks anew and a new token is handed out. It is only used up by `start` once
+    // the casts are started, so a start that fails or is cancelled leaves it good
+    let restore_data = match session.restore_token.as_deref() {
+        Some(token) => {
+            let restore_data = peek_restore_data(token, app_id).await;
+            if restore_data.is_none() {
+                tracing::info!("the restore token of {app_id} is not known, ignore it");
+            }
+            restore_data
+        }
+        None => None,
+    };
+    let mut cursor_mode = session.cursor_mode;
+    // A token the application still holds outranks what it cast last, it is not asked about
+    let has_token = restore_data.is_some();
+    let mut sources: Vec<SelectedSource> = Vec::new();
+    if let Some(identifier) = toplevel {
+        tracing::info!("{session_handle} casts the window {identifier}");
+        sources.push(toplevel_source(&identifier));
+    } else if let Some(output_name) = output_name {
+        let restore = RestoreSource {
+            output_name,
+            source_type: SourceType::Monitor,
+            region: None,
+        };
+        let Some(source) = restore_source(backend, &restore) else {
+            let known: Vec<&str> = backend
+                .outputs()
+                .iter()
+                .map(|output| output.name.as_str())
+                .collect();
+            return Err(zbus::Error::Failure(format!(
+                "No output is called {}, the outputs are: {}",
+                restore.output_name,
+                known.join(", ")
+            ))
+            .into());
+        };
+        sources.push(source);
+    } else if let Some(restore_data) = restore_data {
+        // The cursor mode is kept even if the user has to pick the sources again
+        cursor_mode = restore_data.cursor_mode;
+        // If any output is gone, the user picks again and gets a fresh token
+        match restore_data
+            .sources
+            .iter()
+            .map(|restore| restore_source(backend, restore))
+            .collect::<Option<Vec<SelectedSource>>>()
+        {
+            Some(restored) => sources = restored,
+            None => tracing::info!("Some restored output is gone, fall back to the picker"),
+        }
+    }
+    if sources.is_empty()
+        && session.source_type & supported_source_types() == BitFlags::from(SourceType::Virtual)
+    {
+        sources.push(desktop_source(backend));
+    }
+    // The picker is an overlay over every output and the dialog a window of its own, so the
+    // client is told rather than shown either as if it were modal
+    if sources.is_empty() && modal {
+        tracing::warn!("{session_handle} asks for a modal picker, which cannot be shown");
+        return Ok(ChosenSources::Response(PortalResponse::Error(
+            "The picker cannot be modal to the parent window, start without modal".to_string(),
+        )));
+    }
+    if sources.is_empty()
+        && !has_token
+        && let Some(last) = last_selection(app_id)
+        && let Some(restored) = last
+            .iter()
+            .filter(|source| session.source_type.contains(source.source_type))
+            .map(|source| restore_source(backend, source))
+            .collect::<Option<Vec<SelectedSource>>>()
+        && restored.len() == last.len()
+        && (session.multiple || restored.len() == 1)
+        && ask(restored.clone()).await
+    {
+        sources = restored;
+    }
+    // Only what the user picks is remembered, not what is restored or asked for
+    let mut picked = false;
+    if sources.is_empty() {
+        picked = true;
+        let Some(selected) = pick().await? else {
+            return Ok(ChosenSources::Response(PortalResponse::Cancelled));
+        };
+        sources = selected;
+    }
+    // Nothing picked means the user dismissed the picker, which is no failure
+    if sources.is_empty() {
+        tracing::info!("the user cancelled the picker");
+        return Ok(ChosenSources::Response(PortalResponse::Cancelled));
+    }
+    Ok(ChosenSources::Sources {
+        sources,
+        cursor_mode,
+        picked,
+    })
 }
 
-pub struct ScreenCastBackend;
+pub struct ScreenCastBackend<B> {
+    casts: B,
+}
+
+impl<B: CastBackend> ScreenCastBackend<B> {
+    pub fn new(casts: B) -> Self {
+        Self { casts }
+    }
+}
+
+/// An output as the compositor tells it, for [`ScreenCastBackend::list_outputs`] and
+/// [`ScreenCastBackend::list_output_modes`].
+#[derive(Debug, Clone)]
+struct ListedOutput {
+    /// The connector name, like `DP-1`
+    name: String,
+    /// The layout in logical pixels
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    /// The refresh rate in mHz, 0 when unknown
+    refresh: u32,
+    /// The size of the current mode in physical pixels
+    mode_width: i32,
+    mode_height: i32,
+}
+
+/// An output as [`ScreenCastBackend::list_outputs`] tells it, `(name, x, y, width, height,
+/// refresh)`.
+type OutputLayout = (String, i32, i32, i32, i32, u32);
+
+/// The current mode of an output, as [`ScreenCastBackend::list_output_modes`] tells it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
+struct OutputMode {
+    /// The connector name, like `DP-1`
+    name: String,
+    /// The size in physical pixels
+    width: i32,
+    height: i32,
+    /// The refresh rate in Hz, see [`refresh_hz`]
+    refresh_hz: f64,
+}
+
+impl ListedOutput {
+    fn layout(&self) -> OutputLayout {
+        (
+            self.name.clone(),
+            self.x,
+            self.y,
+            self.width,
+            self.height,
+            self.refresh,
+        )
+    }
+
+    fn mode(&self) -> OutputMode {
+        OutputMode {
+            name: self.name.clone(),
+            width: self.mode_width,
+            height: self.mode_height,
+            refresh_hz: refresh_hz(self.refresh),
+        }
+    }
+}
+
+/// A refresh rate in mHz as whole Hz, with two decimals for rates like 59.94Hz, 0 when it is
+/// unknown.
+fn refresh_hz(refresh: u32) -> f64 {
+    (refresh as f64 / 10.).round() / 100.
+}
+
+/// The outputs listed last, dropped whenever an output comes, goes or changes.
+static LISTED_OUTPUTS: LazyLock<std::sync::Mutex<Option<Vec<ListedOutput>>>> =
+    LazyLock::new(|| std::sync::Mutex::new(None));
+
+/// Whether the outputs are followed, without which [`LISTED_OUTPUTS`] cannot be kept.
+static OUTPUTS_WATCHED: AtomicBool = AtomicBool::new(false);
+
+/// Drop the listed outputs, and stop keeping them once they are not followed anymore.
+fn outputs_changed(watched: bool) {
+    if !watched {
+        OUTPUTS_WATCHED.store(false, Ordering::Relaxed);
+    }
+    *LISTED_OUTPUTS.lock().unwrap() = None;
+}
+
+/// The sessions whose sources are selected here, remote desktop ones cast their screens too.
+const SELECT_SESSION_TYPES: &[SessionType] = &[SessionType::ScreenCast, SessionType::Remote];
+
+/// The sessions started here. Remote desktop ones are started by
+/// [`RemoteDesktopBackend`](crate::remotedesktop::RemoteDesktopBackend), which casts their
+/// screens along with the input, so they are never cast twice.
+const START_SESSION_TYPES: &[SessionType] = &[SessionType::ScreenCast];
+
+/// The source types a cast can be started for.
+///
+/// [`SourceType::Virtual`] casts the whole desktop as one stream. It needs no picker, so it
+/// is only cast when the session asks for no other type.
+fn supported_source_types() -> BitFlags<SourceType> {
+    SourceType::Monitor | SourceType::Window | SourceType::Virtual
+}
+
+/// Ask the compositor for the outputs, and keep them if they are followed. It blocks on the
+/// compositor.
+fn list_outputs() -> zbus::fdo::Result<Vec<ListedOutput>> {
+    let connection = connect_wayshot()?;
+    if !OUTPUTS_WATCHED.swap(true, Ordering::Relaxed)
+        && let Err(e) = watch_outputs(&connection.conn, outputs_changed)
+    {
+        tracing::warn!("cannot follow the outputs, they are listed anew each time: {e}");
+        OUTPUTS_WATCHED.store(false, Ordering::Relaxed);
+    }
+    let refresh_rates = refresh_rates(&connection.conn).unwrap_or_else(|e| {
+        tracing::warn!("cannot get the refresh rates of the outputs: {e}");
+        Vec::new()
+    });
+    let outputs = connection
+        .get_all_outputs()
+        .iter()
+        .map(|output| {
+            let refresh = refresh_rates
+                .iter()
+                .find(|(name, _)| *name == output.name)
+                .map_or(0, |(_, refresh)| (*refresh).max(0) as u32);
+            let dimensions = &output.dimensions;
+            ListedOutput {
+                name: output.name.clone(),
+                x: dimensions.x,
+                y: dimensions.y,
+                width: dimensions.width,
+                height: dimensions.height,
+                refresh,
+                mode_width: output.mode.width,
+                mode_height: output.mode.height,
+            }
+        })
+        .collect::<Vec<ListedOutput>>();
+    if OUTPUTS_WATCHED.load(Ordering::Relaxed) {
+        *LISTED_OUTPUTS.lock().unwrap() = Some(outputs.clone());
+    }
+    Ok(outputs)
+}
+
+/// The outputs kept, or else listed anew off the executor.
+async fn listed_outputs() -> zbus::fdo::Result<Vec<ListedOutput>> {
+    if let Some(outputs) = LISTED_OUTPUTS.lock().unwrap().clone() {
+        return Ok(outputs);
+    }
+    tokio::task::spawn_blocking(list_outputs)
+        .await
+        .unwrap_or_else(|e| Err(zbus::Error::Failure(format!("cannot list, error: {e}")).into()))
+}
+
+/// The version of the interface advertised, the restore tokens of version 4 included.
+const SCREENCAST_VERSION: u32 = 4;
 
 #[interface(name = "org.freedesktop.impl.portal.ScreenCast")]
-impl ScreenCastBackend {
+impl<B: CastBackend> ScreenCastBackend<B> {
     #[zbus(property, name = "version")]
     fn version(&self) -> u32 {
-        4
+        SCREENCAST_VERSION
     }
 
     #[zbus(property)]
     fn available_cursor_modes(&self) -> u32 {
-        (CursorMode::Hidden | CursorMode::Embedded).bits()
+        (CursorMode::Hidden | CursorMode::Embedded | CursorMode::Metadata).bits()
     }
 
     #[zbus(property)]
     fn available_source_types(&self) -> u32 {
-        BitFlags::from_flag(SourceType::Monitor).bits()
+        supported_source_types().bits()
+    }
+
+    /// Not in the spec, the outputs as `(name, x, y, width, height, refresh)`, in logical
+    /// pixels and mHz, with a refresh of 0 when unknown.
+    ///
+    /// A frontend can show them its own way, and pass the picked name as the `output` option
+    /// of start. The list is kept until an output comes, goes or changes.
+    async fn list_outputs(&self) -> zbus::fdo::Result<Vec<OutputLayout>> {
+        let outputs = listed_outputs().await?;
+        Ok(outputs.iter().map(ListedOutput::layout).collect())
+    }
+
+    /// Not in the spec, the current mode of each output, as `(name, width, height,
+    /// refresh_hz)` in physical pixels and Hz, so `3840x2160@60` can be shown. Listed like
+    /// [`ScreenCastBackend::list_outputs`], in the same order.
+    async fn list_output_modes(&self) -> zbus::fdo::Result<Vec<OutputMode>> {
+        let outputs = listed_outputs().await?;
+        Ok(outputs.iter().map(ListedOutput::mode).collect())
     }
 
+    /// Not in the spec, forget the listed outputs, so that the next
+    /// [`ScreenCastBackend::list_outputs`] asks the compositor again.
+    fn refresh_outputs(&self) {
+        *LISTED_OUTPUTS.lock().unwrap() = None;
+    }
+
+    #[tracing::instrument(skip_all, fields(session_handle = %session_handle, app_id = %app_id))]
     async fn create_session(
         &self,
         request_handle: ObjectPath<'_>,
         session_handle: ObjectPath<'_>,
         app_id: String,
-        _options: HashMap<String, Value<'_>>,
+        options: HashMap<String, Value<'_>>,
+        #[zbus(header)] header: Header<'_>,
         #[zbus(object_server)] server: &zbus::ObjectServer,
     ) -> zbus::fdo::Result<PortalResponse<SessionCreateResult>> {
-        tracing::info!(
-            "Start shot: path :{}, appid: {}",
-            request_handle.as_str(),
-            app_id
+        let request_token = handle_token(&options, "handle_token", &request_handle);
+        let session_token = handle_token(&options, "session_handle_token", &session_handle);
+        tracing::info!("create session {session_token} for request {request_token}");
+        let mut current_session = Session::new(
+            session_handle.clone(),
+            session_token,
+            SessionType::ScreenCast,
+            app_id,
         );
+        current_session.sender = header.sender().map(ToString::to_string);
+        // The token the session is found by from now on
+        let handle_token = current_session.handle_token.clone();
+        if !append_session(current_session.clone()).await {
+            return Ok(PortalResponse::Other);
+        }
         server
             .at(
                 request_handle.clone(),
@@ -129,22 +1219,24 @@ impl ScreenCastBackend {
                 },
             )
             .await?;
-        let current_session = Session::new(session_handle.clone(), SessionType::ScreenCast);
-        append_session(current_session.clone()).await;
         server.at(session_handle.clone(), current_session).await?;
         Ok(PortalResponse::Success(SessionCreateResult {
-            handle_token: session_handle.to_string(),
+            handle_token,
         }))
     }
 
+    #[tracing::instrument(skip_all, fields(session_handle = %session_handle, app_id = %app_id))]
     async fn select_sources(
         &self,
         _request_handle: ObjectPath<'_>,
         session_handle: ObjectPath<'_>,
-        _app_id: String,
-        options: SelectSourcesOptions,
+        app_id: String,
+        mut options: SelectSourcesOptions,
     ) -> zbus::fdo::Result<PortalResponse<HashMap<String, OwnedValue>>> {
-        let mut locked_sessions = SESSIONS.lock().await;
+        if options.cursor_mode.is_none() {
+            options.cursor_mode = SETTING_CONFIG.lock().await.get_default_cursor_mode();
+        }
+        let mut locked_sessions = SESSIONS.write().await;
         let Some(index) = locked_sessions
             .iter()
             .position(|this_session| this_session.handle_path == session_handle.clone().into())
@@ -152,31 +1244,42 @@ impl ScreenCastBackend {
             tracing::warn!("No session is created or it is removed");
             return Ok(PortalResponse::Other);
         };
+        if let Err(e) = locked_sessions[index].check_type(SELECT_SESSION_TYPES) {
+            tracing::warn!("{e}");
+            return Ok(PortalResponse::Error(e));
+        }
+        if let Some(types) = options.types {
+            let supported = supported_source_types();
+            if !types.intersects(supported) {
+                tracing::warn!("{session_handle} asks for the source types {types:?} alone");
+                return Ok(PortalResponse::Error(format!(
+                    "None of the source types {types:?} can be cast, only {supported:?} are supported"
+                )));
+            }
+            let unsupported = types & !supported;
+            if !unsupported.is_empty() {
+                tracing::warn!("the source types {unsupported:?} cannot be cast, they are ignored");
+            }
+            // So that start never has to deal with them
+            options.types = Some(types & supported);
+        }
         locked_sessions[index].set_screencast_options(options);
         Ok(PortalResponse::Success(HashMap::new()))
     }
 
+    #[tracing::instrument(skip_all, fields(session_handle = %session_handle, app_id = %app_id))]
     async fn start(
         &self,
-        _request_handle: ObjectPath<'_>,
+        request_handle: ObjectPath<'_>,
         session_handle: ObjectPath<'_>,
-        _app_id: String,
+        app_id: String,
         _parent_window: String,
-        _options: HashMap<String, Value<'_>>,
+        options: HashMap<String, Value<'_>>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
     ) -> zbus::fdo::Result<PortalResponse<StartReturnValue>> {
-        let cast_sessions = CAST_SESSIONS.lock().await;
-        if let Some(session) = cast_sessions
-            .iter()
-            .find(|session| session.0 == session_handle.to_string())
-        {
-            return Ok(PortalResponse::Success(StartReturnValue {
-                streams: vec![Stream(session.1.node_id(), StreamProperties::default())],
-                ..Default::default()
-            }));
-        }
-        drop(cast_sessions);
-
-        let locked_sessions = SESSIONS.lock().await;
+        // A concurrent start of this session waits here, and then reuses its streams
+        let mut granted = lock_start(session_handle.as_str()).await;
+        let locked_sessions = SESSIONS.read().await;
         let Some(index) = locked_sessions
             .iter()
             .position(|this_session| this_session.handle_path == session_handle.clone().into())
@@ -186,50 +1289,1127 @@ impl ScreenCastBackend {
         };
 
         let current_session = locked_sessions[index].clone();
-        if current_session.session_type != SessionType::ScreenCast {
+        if let Err(e) = current_session.check_type(START_SESSION_TYPES) {
+            tracing::warn!("{e}");
+            return Ok(PortalResponse::Error(e));
+        }
+        if current_session.app_id != app_id {
+            tracing::warn!(
+                "{app_id} tried to start {session_handle}, created by {}",
+                current_session.app_id
+            );
             return Ok(PortalResponse::Other);
         }
         drop(locked_sessions);
 
-        let show_cursor = current_session.cursor_mode.show_cursor();
-        let connection = libwayshot::WayshotConnection::new().unwrap();
+        let cast_sessions = CAST_SESSIONS.lock().await;
+        let streams: Vec<Stream> = cast_sessions
+            .iter()
+            .filter(|session| session.0 == session_handle.to_string())
+            .map(|session| {
+                // A consumer may have connected since, and agreed on a lower framerate
+                let stream_properties = StreamProperties {
+                    framerate: Some(session.1.settled_framerate()),
+                    ..session.2.clone()
+                };
+                Stream(session.1.node_id(), stream_properties)
+            })
+            .collect();
+        if !streams.is_empty() {
+            let (persist_mode, restore_token) = granted.clone().unwrap_or_default();
+            return Ok(PortalResponse::Success(StartReturnValue {
+                streams,
+                persist_mode,
+                restore_token,
+            }));
+        }
+        drop(cast_sessions);
 
-        let info = match libwaysip::get_area(
-            Some(libwaysip::WaysipConnection {
-                connection: &connection.conn,
-                globals: &connection.globals,
-            }),
-            SelectionType::Screen,
-        ) {
-            Ok(Some(info)) => info,
-            Ok(None) => return Err(zbus::Error::Failure("You cancel it".to_string()).into()),
-            Err(e) => return Err(zbus::Error::Failure(format!("wayland error, {e}")).into()),
-        };
-
-        let WlOutputInfo {
-            output,
-            size: (width, height),
-            ..
-        } = info.screen_info.output_info;
-
-        let cast_thread = ScreencastThread::start_cast(
-            show_cursor,
-            width as u32,
-            height as u32,
-            None,
-            output,
-            connection,
-        )
-        .await
-        .map_err(|e| zbus::Error::Failure(format!("cannot start pipewire stream, error: {e}")))?;
+        // Talking to the compositor blocks, so it is kept off the executor like the picker is
+        let backend = self.casts.clone();
+        let connection = tokio::task::spawn_blocking(move || backend.connect())
+            .await
+            .unwrap_or_else(|e| {
+                Err(zbus::Error::Failure(format!("cannot connect, error: {e}")).into())
+            })?;
+        let connection = Arc::new(connection);
+
+        let region = options
+            .get("region")
+            .and_then(|value| bool::try_from(value).ok())
+            .unwrap_or(false);
+        let framerate = options
+            .get("framerate")
+            .and_then(|value| u32::try_from(value).ok());
+        // Caps the size of the frames, they are shrunk to fit but never enlarged
+        let max_size = (
+            options
+                .get("max_width")
+                .and_then(|value| u32::try_from(value).ok()),
+            options
+                .get("max_height")
+                .and_then(|value| u32::try_from(value).ok()),
+        );
+        // An explicit connector, like `DP-2`, skips the picker for scripted casts
+        let output_name = options
+            .get("output")
+            .and_then(|value| <&str>::try_from(value).ok())
+            .map(str::to_string);
+        // Not in the spec, the ext-foreign-toplevel identifier of a window to cast alone
+        let toplevel = options
+            .get("toplevel")
+            .and_then(|value| <&str>::try_from(value).ok())
+            .map(str::to_string);
+        // Not in the spec, never show the picker, for unattended casts
+        let headless = options
+            .get("headless")
+            .and_then(|value| bool::try_from(value).ok())
+            .unwrap_or(false);
+        // Not in the spec, `cursor` casts the output under the pointer without a picker, for
+        // hotkeys sharing the screen the user is at
+        let auto = options
+            .get("auto")
+            .and_then(|value| <&str>::try_from(value).ok());
+        let output_name = match (output_name, auto) {
+            (None, Some("cursor")) => {
+                let (backend, outputs) = (self.casts.clone(), connection.clone());
+                let under_cursor =
+                    tokio::task::spawn_blocking(move || backend.output_under_cursor(&outputs))
+                        .await
+                        .unwrap_or_else(|e| Err(e.into()));
+                match under_cursor {
+                    Ok(Some(output_name)) => {
+                        tracing::info!("the pointer is over {output_name}, cast it");
+                        Some(output_name)
+                    }
+                    Ok(None) => {
+                        return Ok(PortalResponse::Error(
+                            "The pointer is over no output, it may be hidden".to_string(),
+                        ));
+                    }
+                    Err(e) => {
+                        tracing::warn!("cannot tell which output the pointer is over: {e}");
+                        return Ok(PortalResponse::Error(format!(
+                            "Cannot find the output under the pointer, error: {e}"
+                        )));
+                    }
+                }
+            }
+            (None, Some(auto)) => {
+                return Ok(PortalResponse::Error(format!(
+                    "Unknown auto {auto}, it must be cursor"
+                )));
+            }
+            (output_name, _) => output_name,
+        };
+        let modal = options
+            .get("modal")
+            .and_then(|value| bool::try_from(value).ok())
+            .unwrap_or(false);
+        let source_options = SourceOptions {
+            output_name,
+            toplevel,
+            headless,
+            modal,
+        };
+        let picker_options = PickerOptions {
+            source_types: current_session.source_type,
+            multiple: current_session.multiple,
+            region,
+        };
+        let casts = &self.casts;
+        let pick = || async move {
+            let request_path: OwnedObjectPath = request_handle.clone().into();
+            server
+                .at(
+                    request_handle.clone(),
+                    RequestInterface {
+                        handle_path: request_path.clone(),
+                    },
+                )
+                .await?;
+            let picked = casts.pick(request_path.clone(), picker_options).await;
+            let _ = server
+                .remove::<RequestInterface, &OwnedObjectPath>(&request_path)
+                .await;
+            picked
+        };
+        let ask =
+            |sources: Vec<SelectedSource>| casts.confirm_last_selection(app_id.clone(), sources);
+        let chosen = choose_sources(&*connection, &current_session, source_options, pick, ask);
+        let (sources, cursor_mode, picked) = match chosen.await? {
+            ChosenSources::Sources {
+                sources,
+                cursor_mode,
+                picked,
+            } => (sources, cursor_mode, picked),
+            ChosenSources::Response(response) => return Ok(response),
+        };
+        let restore_sources: Vec<RestoreSource> = sources
+            .iter()
+            .map(SelectedSource::to_restore_source)
+            .collect();
+        let physical_size = SETTING_CONFIG.lock().await.reports_physical_size();
+        // Each cast has a thread of its own, so the outputs are captured side by side, and
+        // they are started side by side too. They share the connection to the compositor,
+        // each with an event queue of its own, and are set up off the executor
+        let session_handle = &session_handle;
+        let starts = sources.into_iter().map(|source| async move {
+            let on_ended = {
+                let handle = tokio::runtime::Handle::current();
+                let path = session_handle.to_string();
+                move |node_id| {
+                    handle.spawn(async move {
+                        remove_cast_stream(&path, node_id).await;
+                    });
+                }
+            };
+            // Failures are told to the frontend in the response, so it can show them
+            let cast_thread = casts
+                .start_cast(&source, cursor_mode, framerate, max_size, on_ended)
+                .await?;
+            let stream_id = source.stream_id();
+            let SelectedSource {
+                source_type,
+                position,
+                size,
+                ..
+            } = source;
+            let toplevel = source_type == SourceType::Window;
+            // Shrunk frames have no logical size of their own, nor do windows here
+            let size = if physical_size || max_size != (None, None) || toplevel {
+                let (width, height) = cast_thread.size();
+                (width as i32, height as i32)
+            } else {
+                size
+            };
+            let stream_properties = StreamProperties {
+                id: Some(stream_id),
+                position: Some(position),
+                size: Some(size),
+                source_type: Some(source_type),
+                framerate: Some(cast_thread.settled_framerate()),
+            };
+            Ok::<_, String>((cast_thread, stream_properties))
+        });
+        let mut cast_threads = Vec::new();
+        let mut failure = None;
+        for started in futures::future::join_all(starts).await {
+            match started {
+                Ok(cast_thread) => cast_threads.push(cast_thread),
+                Err(e) => failure = failure.or(Some(e)),
+            }
+        }
+        if let Some(e) = failure {
+            for (cast_thread, _) in cast_threads {
+                cast_thread.stop();
+            }
+            return Ok(PortalResponse::Error(e));
+        }
 
-        let node_id = cast_thread.node_id();
+        // The client may have closed the session while the picker was shown. Checked with
+        // the casts locked, so a close either comes before and is seen here, or stops them
+        let mut cast_sessions = CAST_SESSIONS.lock().await;
+        let closed = !SESSIONS
+            .read()
+            .await
+            .iter()
+            .any(|this_session| this_session.handle_path == session_handle.clone().into());
+        if closed {
+            drop(cast_sessions);
+            tracing::warn!("{session_handle} was closed while it started, stop its casts");
+            for (cast_thread, _) in cast_threads {
+                cast_thread.stop();
+            }
+            return Ok(PortalResponse::Cancelled);
+        }
+        let mut streams = Vec::new();
+        for (cast_thread, stream_properties) in cast_threads {
+            streams.push(Stream(cast_thread.node_id(), stream_properties.clone()));
+            cast_sessions.push((session_handle.to_string(), cast_thread, stream_properties));
+        }
+        drop(cast_sessions);
+        // The new token below replaces it
+        if let Some(token) = current_session.restore_token.as_deref() {
+            take_restore_data(token, &app_id).await;
+        }
+        // Sent off the call, so a notification server that hangs does not hold the start
+        if SETTING_CONFIG.lock().await.notify_on_cast {
+            let names = streams
+                .iter()
+                .map(|stream| stream.1.id.clone().unwrap_or_default())
+                .collect();
+            tokio::spawn(notify_cast_started(app_id.clone(), names));
+        }
 
-        append_cast_session((session_handle.to_string(), cast_thread)).await;
+        // The backend may grant less than asked, the client learns what from the result
+        let max_persist_mode = SETTING_CONFIG.lock().await.get_max_persist_mode();
+        let persist_mode = if (current_session.persist_mode as u32) > (max_persist_mode as u32) {
+            tracing::info!(
+                "{app_id} asked for {:?}, only {max_persist_mode:?} is allowed",
+                current_session.persist_mode
+            );
+            max_persist_mode
+        } else {
+            current_session.persist_mode
+        };
+        // Nothing of a session that is not to persist is kept, not even what was picked
+        if picked && persist_mode != PersistMode::DoNot {
+            remember_selection(&app_id, restore_sources.clone()).await;
+        }
+        let restore_token = if persist_mode != PersistMode::DoNot {
+            Some(
+                append_restore_data(RestoreData {
+                    app_id,
+                    sources: restore_sources,
+                    cursor_mode,
+                    persist_mode,
+                })
+                .await,
+            )
+        } else {
+            None
+        };
 
+        *granted = Some((persist_mode as u32, restore_token.clone()));
         Ok(PortalResponse::Success(StartReturnValue {
-            streams: vec![Stream(node_id, StreamProperties::default())],
-            ..Default::default()
+            streams,
+            persist_mode: persist_mode as u32,
+            restore_token,
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+
+    use super::*;
+    use crate::remotedesktop::RemoteDesktopBackend;
+    use crate::restore::revoke_restore_token;
+    use crate::session::remove_session;
+    use crate::testing::{FakeCasts, lock_globals, serve};
+
+    const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+    const SCREENCAST: &str = "org.freedesktop.impl.portal.ScreenCast";
+    const APP_ID: &str = "org.luminous.Test";
+
+    /// A laptop screen, with a smaller screen on its right.
+    const OUTPUTS: &[(&str, i32, i32, i32, i32)] = &[
+        ("eDP-1", 0, 0, 1920, 1080),
+        ("HDMI-A-1", 1920, 0, 1280, 720),
+    ];
+
+    /// The path of the request `token` of a call.
+    fn request_path(token: &str) -> ObjectPath<'static> {
+        ObjectPath::try_from(format!("{PORTAL_PATH}/request/1_1/{token}")).unwrap()
+    }
+
+    /// The path of the session `token`.
+    fn session_path(token: &str) -> ObjectPath<'static> {
+        ObjectPath::try_from(format!("{PORTAL_PATH}/session/1_1/{token}")).unwrap()
+    }
+
+    /// Call `method` of the portal `interface` with `args`, and return its response code and
+    /// results.
+    async fn call<B>(
+        client: &zbus::Connection,
+        interface: &str,
+        method: &str,
+        args: &B,
+    ) -> (u32, HashMap<String, OwnedValue>)
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        client
+            .call_method(None::<&str>, PORTAL_PATH, Some(interface), method, args)
+            .await
+            .expect("the call fails")
+            .body()
+            .deserialize()
+            .expect("the response is no portal response")
+    }
+
+    /// The session at `path`, as the portal keeps it.
+    async fn kept_session(path: &ObjectPath<'_>) -> Session {
+        SESSIONS
+            .read()
+            .await
+            .iter()
+            .find(|this_session| this_session.handle_path == path.clone().into())
+            .cloned()
+            .expect("the session is not kept")
+    }
+
+    /// Create the session `token` of `app_id` over D-Bus with the portal `interface`, and
+    /// select its sources with `options`.
+    async fn session_with(
+        client: &zbus::Connection,
+        interface: &str,
+        token: &str,
+        app_id: &str,
+        options: HashMap<&str, Value<'_>>,
+    ) -> Session {
+        let request = request_path(token);
+        let session = session_path(token);
+        let create_options: HashMap<&str, Value<'_>> = HashMap::new();
+        let (response, _) = call(
+            client,
+            interface,
+            "CreateSession",
+            &(&request, &session, app_id, create_options),
+        )
+        .await;
+        assert_eq!(response, 0, "the session is not created");
+        let (response, _) = call(
+            client,
+            SCREENCAST,
+            "SelectSources",
+            &(&request, &session, app_id, options),
+        )
+        .await;
+        assert_eq!(response, 0, "the sources are not selected");
+        kept_session(&session).await
+    }
+
+    /// Like [`session_with`], with monitors to be picked.
+    async fn selected_session(
+        client: &zbus::Connection,
+        interface: &str,
+        token: &str,
+        app_id: &str,
+    ) -> Session {
+        let options = HashMap::from([("types", Value::U32(SourceType::Monitor as u32))]);
+        session_with(client, interface, token, app_id, options).await
+    }
+
+    /// Start the session `token` of `app_id` over D-Bus with `options`, and return the
+    /// response code, along with the results when it succeeds.
+    async fn start(
+        client: &zbus::Connection,
+        token: &str,
+        app_id: &str,
+        options: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<(u32, Option<StartReturnValue>)> {
+        let request = request_path(&format!("{token}_start"));
+        let session = session_path(token);
+        let reply = client
+            .call_method(
+                None::<&str>,
+                PORTAL_PATH,
+                Some(SCREENCAST),
+                "Start",
+                &(&request, &session, app_id, "", options),
+            )
+            .await?;
+        let (response, _): (u32, HashMap<String, OwnedValue>) = reply.body().deserialize()?;
+        if response != 0 {
+            return Ok((response, None));
+        }
+        let (_, results): (u32, StartReturnValue) = reply.body().deserialize()?;
+        Ok((response, Some(results)))
+    }
+
+    /// The ids of `streams`, in order.
+    fn stream_ids(streams: &[Stream]) -> Vec<String> {
+        streams
+            .iter()
+            .map(|stream| stream.1.id.clone().unwrap_or_default())
+            .collect()
+    }
+
+    /// Hand `app_id` a restore token for the whole of `output_name`, cast with the embedded
+    /// cursor.
+    async fn granted_token(app_id: &str, output_name: &str) -> String {
+        append_restore_data(RestoreData {
+            app_id: app_id.to_string(),
+            sources: vec![RestoreSource {
+                output_name: output_name.to_string(),
+                source_type: SourceType::Monitor,
+                region: None,
+            }],
+            cursor_mode: CursorMode::Embedded,
+            persist_mode: PersistMode::Application,
+        })
+        .await
+    }
+
+    /// Like [`selected_session`], passing `restore_token` and asking for a new one.
+    async fn restoring_session(
+        client: &zbus::Connection,
+        token: &str,
+        restore_token: &str,
+    ) -> Session {
+        let options = HashMap::from([
+            ("types", Value::U32(SourceType::Monitor as u32)),
+            ("restore_token", Value::from(restore_token)),
+            ("persist_mode", Value::U32(PersistMode::Application as u32)),
+        ]);
+        session_with(client, SCREENCAST, token, APP_ID, options).await
+    }
+
+    #[tokio::test]
+    async fn create_session_hands_back_the_token_it_is_found_by() {
+        let _globals = lock_globals().await;
+        let backend = ScreenCastBackend::new(FakeCasts::new(OUTPUTS));
+        let (_server, client) = serve(PORTAL_PATH, backend).await;
+        let request = request_path("luminous_token");
+        let session = session_path("luminous_token");
+        let options = HashMap::from([("session_handle_token", Value::from("luminous_token"))]);
+        let (response, results) = call(
+            &client,
+            SCREENCAST,
+            "CreateSession",
+            &(&request, &session, APP_ID, options),
+        )
+        .await;
+        assert_eq!(response, 0);
+        let token = String::try_from(results["handle_token"].clone()).unwrap();
+        assert_eq!(token, "luminous_token");
+
+        let found = session_path(&token);
+        let options: HashMap<&str, Value<'_>> = HashMap::new();
+        let (response, _) = call(
+            &client,
+            SCREENCAST,
+            "SelectSources",
+            &(&request, &found, APP_ID, options),
+        )
+        .await;
+        assert_eq!(response, 0, "the session is not found by its token");
+        remove_session(&kept_session(&found).await).await;
+    }
+
+    #[tokio::test]
+    async fn start_casts_what_is_picked() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        casts.then_pick(Some(&["HDMI-A-1"]));
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let session = selected_session(&client, SCREENCAST, "luminous_picked", APP_ID).await;
+        assert_eq!(session.source_type, SourceType::Monitor);
+
+        let (response, results) = start(&client, "luminous_picked", APP_ID, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response, 0);
+        let streams = results.unwrap().streams;
+        assert_eq!(stream_ids(&streams), ["HDMI-A-1"]);
+        let Stream(node_id, properties) = &streams[0];
+        assert_eq!(properties.position, Some((1920, 0)));
+        assert_eq!(properties.size, Some((1280, 720)));
+        assert_eq!(properties.source_type, Some(SourceType::Monitor));
+        assert!(casts.picked_all());
+        assert_eq!(casts.started(), 1);
+        let cast_sessions = CAST_SESSIONS.lock().await;
+        assert_eq!(cast_sessions.len(), 1);
+        assert_eq!(cast_sessions[0].0, session.handle_path.as_str());
+        assert_eq!(cast_sessions[0].1.node_id(), *node_id);
+        drop(cast_sessions);
+
+        remove_session(&session).await;
+        assert_eq!(casts.stopped(), 1);
+    }
+
+    #[tokio::test]
+    async fn remote_desktop_sessions_select_here_and_start_there() {
+        let _globals = lock_globals().await;
+        let (server, client) = serve(PORTAL_PATH, RemoteDesktopBackend).await;
+        // No pick is canned, the picker of a start here would fail the test
+        let casts = FakeCasts::new(OUTPUTS);
+        let backend = ScreenCastBackend::new(casts.clone());
+        server
+            .object_server()
+            .at(PORTAL_PATH, backend)
+            .await
+            .unwrap();
+        let session = selected_session(
+            &client,
+            "org.freedesktop.impl.portal.RemoteDesktop",
+            "luminous_remote",
+            APP_ID,
+        )
+        .await;
+        assert_eq!(session.session_type, SessionType::Remote);
+        assert_eq!(session.source_type, BitFlags::from(SourceType::Monitor));
+
+        let options: HashMap<&str, Value<'_>> = HashMap::new();
+        let (response, results) = call(
+            &client,
+            SCREENCAST,
+            "Start",
+            &(
+                &request_path("luminous_remote_start"),
+                &session.handle_path,
+                APP_ID,
+                "",
+                options,
+            ),
+        )
+        .await;
+        assert_eq!(response, 2);
+        let error = String::try_from(results["error"].clone()).unwrap();
+        assert!(error.contains("is a Remote session"), "{error}");
+        assert_eq!(casts.started(), 0);
+        assert!(CAST_SESSIONS.lock().await.is_empty());
+        remove_session(&session).await;
+    }
+
+    #[tokio::test]
+    async fn start_is_cancelled_with_the_picker() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        // Dismissed without a pick, then closed meanwhile
+        casts.then_pick(Some(&[])).then_pick(None);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let session = selected_session(&client, SCREENCAST, "luminous_cancelled", APP_ID).await;
+        for _ in 0..2 {
+            let (response, _) = start(&client, "luminous_cancelled", APP_ID, HashMap::new())
+                .await
+                .unwrap();
+            assert_eq!(response, 1, "the start is not cancelled");
+        }
+        assert!(casts.picked_all());
+        assert_eq!(casts.started(), 0);
+        assert!(CAST_SESSIONS.lock().await.is_empty());
+        remove_session(&session).await;
+    }
+
+    #[tokio::test]
+    async fn start_needs_the_output_asked_for() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let missing = selected_session(&client, SCREENCAST, "luminous_missing", APP_ID).await;
+        let options = HashMap::from([("output", Value::from("DP-9"))]);
+        let Err(e) = start(&client, "luminous_missing", APP_ID, options).await else {
+            panic!("a missing output is cast");
+        };
+        assert!(e.to_string().contains("eDP-1, HDMI-A-1"), "{e}");
+
+        // No picker is canned, it would fail the test if shown
+        let asked = selected_session(&client, SCREENCAST, "luminous_output", APP_ID).await;
+        let options = HashMap::from([("output", Value::from("eDP-1"))]);
+        let (response, results) = start(&client, "luminous_output", APP_ID, options)
+            .await
+            .unwrap();
+        assert_eq!(response, 0);
+        assert_eq!(stream_ids(&results.unwrap().streams), ["eDP-1"]);
+        assert_eq!(casts.started(), 1);
+        remove_session(&missing).await;
+        remove_session(&asked).await;
+    }
+
+    #[tokio::test]
+    async fn a_headless_start_casts_the_configured_output() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let headless = || HashMap::from([("headless", Value::Bool(true))]);
+        // No picker is canned, it would fail the test if shown
+        for (token, configured, cast) in [
+            ("luminous_primary", None, "eDP-1"),
+            ("luminous_configured", Some("HDMI-A-1"), "HDMI-A-1"),
+        ] {
+            SETTING_CONFIG.lock().await.headless_output = configured.map(str::to_string);
+            let session = selected_session(&client, SCREENCAST, token, APP_ID).await;
+            let (response, results) = start(&client, token, APP_ID, headless()).await.unwrap();
+            assert_eq!(response, 0, "{token} is not started");
+            assert_eq!(stream_ids(&results.unwrap().streams), [cast]);
+            remove_session(&session).await;
+        }
+
+        SETTING_CONFIG.lock().await.headless_output = Some("DP-9".to_string());
+        let session = selected_session(&client, SCREENCAST, "luminous_unplugged", APP_ID).await;
+        let Err(e) = start(&client, "luminous_unplugged", APP_ID, headless()).await else {
+            panic!("a missing headless output is cast");
+        };
+        assert!(e.to_string().contains("No output is called DP-9"), "{e}");
+        assert_eq!(casts.started(), 2);
+        remove_session(&session).await;
+    }
+
+    #[tokio::test]
+    async fn a_restore_token_restores_without_the_picker() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let token = granted_token(APP_ID, "HDMI-A-1").await;
+        let session = restoring_session(&client, "luminous_restored", &token).await;
+        let (response, results) = start(&client, "luminous_restored", APP_ID, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response, 0);
+        let results = results.unwrap();
+        assert_eq!(stream_ids(&results.streams), ["HDMI-A-1"]);
+        assert_eq!(results.persist_mode, PersistMode::Application as u32);
+        // The token is used up, and a new one stands for the same sources
+        assert!(peek_restore_data(&token, APP_ID).await.is_none());
+        let new_token = results.restore_token.expect("no new token is handed out");
+        assert_ne!(new_token, token);
+        let restored = peek_restore_data(&new_token, APP_ID).await.unwrap();
+        assert_eq!(restored.sources[0].output_name, "HDMI-A-1");
+        remove_session(&session).await;
+    }
+
+    #[tokio::test]
+    async fn unusable_restore_tokens_fall_back_to_the_picker() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let revoked = granted_token(APP_ID, "eDP-1").await;
+        assert!(revoke_restore_token(&revoked).await);
+        // Well formed, but its output is unplugged since
+        let stale = granted_token(APP_ID, "DP-9").await;
+        let unknown = uuid::Uuid::new_v4().to_string();
+        for (token, restore_token) in [
+            ("luminous_revoked", revoked),
+            ("luminous_stale", stale),
+            ("luminous_unknown", unknown),
+            ("luminous_garbage", "not a token".to_string()),
+        ] {
+            casts.then_pick(Some(&["eDP-1"]));
+            let session = restoring_session(&client, token, &restore_token).await;
+            let (response, results) = start(&client, token, APP_ID, HashMap::new()).await.unwrap();
+            assert_eq!(response, 0, "{token} is not started");
+            assert!(casts.picked_all(), "{token} is started without the picker");
+            let results = results.unwrap();
+            assert_eq!(stream_ids(&results.streams), ["eDP-1"]);
+            let new_token = results.restore_token.expect("no new token is handed out");
+            assert_ne!(new_token, restore_token);
+            remove_session(&session).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_restore_token_survives_a_failed_or_cancelled_start() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+
+        // Its output is gone, and the picker shown instead is closed
+        let stale = granted_token(APP_ID, "DP-9").await;
+        casts.then_pick(None);
+        let session = restoring_session(&client, "luminous_closed", &stale).await;
+        let (response, _) = start(&client, "luminous_closed", APP_ID, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response, 1);
+        assert!(peek_restore_data(&stale, APP_ID).await.is_some());
+        remove_session(&session).await;
+
+        let token = granted_token(APP_ID, "eDP-1").await;
+        casts.fail_casts();
+        let session = restoring_session(&client, "luminous_failed", &token).await;
+        let (response, _) = start(&client, "luminous_failed", APP_ID, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response, 2);
+        assert!(peek_restore_data(&token, APP_ID).await.is_some());
+        assert!(CAST_SESSIONS.lock().await.is_empty());
+        remove_session(&session).await;
+    }
+
+    #[tokio::test]
+    async fn a_restore_token_of_another_app_is_rejected() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let other = "org.luminous.Other";
+        let token = granted_token(other, "eDP-1").await;
+        casts.then_pick(Some(&["HDMI-A-1"]));
+        let session = restoring_session(&client, "luminous_borrowed", &token).await;
+        let (response, results) = start(&client, "luminous_borrowed", APP_ID, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response, 0);
+        assert!(
+            casts.picked_all(),
+            "the token of another app skips the picker"
+        );
+        assert_eq!(stream_ids(&results.unwrap().streams), ["HDMI-A-1"]);
+        // Left to the app it was handed to
+        assert!(peek_restore_data(&token, APP_ID).await.is_none());
+        assert!(peek_restore_data(&token, other).await.is_some());
+        remove_session(&session).await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_starts_cast_once() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        casts.then_pick(Some(&["eDP-1", "HDMI-A-1"]));
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let options = HashMap::from([
+            ("types", Value::U32(SourceType::Monitor as u32)),
+            ("multiple", Value::Bool(true)),
+            ("persist_mode", Value::U32(PersistMode::Application as u32)),
+        ]);
+        let session = session_with(&client, SCREENCAST, "luminous_twice", APP_ID, options).await;
+        let (first, second) = tokio::join!(
+            start(&client, "luminous_twice", APP_ID, HashMap::new()),
+            start(&client, "luminous_twice", APP_ID, HashMap::new()),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!((first.0, second.0), (0, 0));
+        let (first, second) = (first.1.unwrap(), second.1.unwrap());
+        let node_ids = |results: &StartReturnValue| -> Vec<u32> {
+            results.streams.iter().map(|stream| stream.0).collect()
+        };
+        assert_eq!(node_ids(&first).len(), 2);
+        assert_eq!(node_ids(&first), node_ids(&second));
+        assert_eq!(stream_ids(&first.streams), stream_ids(&second.streams));
+        // The one that waited is told what the other was granted
+        assert_eq!(first.persist_mode, PersistMode::Application as u32);
+        assert_eq!(second.persist_mode, first.persist_mode);
+        assert!(first.restore_token.is_some());
+        assert_eq!(second.restore_token, first.restore_token);
+        assert_eq!(casts.started(), 2);
+        assert_eq!(CAST_SESSIONS.lock().await.len(), 2);
+        remove_session(&session).await;
+    }
+
+    #[tokio::test]
+    async fn a_restored_session_casts_with_its_cursor_mode() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        // The sessions ask for no cursor mode, so they would hide it
+        let valid = granted_token(APP_ID, "HDMI-A-1").await;
+        let stale = granted_token(APP_ID, "DP-9").await;
+        casts.then_pick(Some(&["eDP-1"]));
+        for (token, restore_token) in [("luminous_valid", valid), ("luminous_stale", stale)] {
+            let session = restoring_session(&client, token, &restore_token).await;
+            let (response, results) = start(&client, token, APP_ID, HashMap::new()).await.unwrap();
+            assert_eq!(response, 0, "{token} is not started");
+            let new_token = results.unwrap().restore_token.unwrap();
+            let restored = peek_restore_data(&new_token, APP_ID).await.unwrap();
+            assert_eq!(restored.cursor_mode, CursorMode::Embedded, "{token}");
+            remove_session(&session).await;
+        }
+        assert!(casts.picked_all());
+        assert_eq!(
+            casts.cursor_modes(),
+            [CursorMode::Embedded, CursorMode::Embedded]
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_selects_keep_the_options_of_one_whole() {
+        let _globals = lock_globals().await;
+        let backend = ScreenCastBackend::new(FakeCasts::new(OUTPUTS));
+        let (_server, client) = serve(PORTAL_PATH, backend).await;
+        let shared = selected_session(&client, SCREENCAST, "luminous_shared", APP_ID).await;
+        let other = selected_session(&client, SCREENCAST, "luminous_other", APP_ID).await;
+        let select = |token: &'static str, cursor_mode: CursorMode, persist_mode: PersistMode| {
+            let client = client.clone();
+            async move {
+                let request = request_path(&format!("{token}_select"));
+                let options = HashMap::from([
+                    ("types", Value::U32(SourceType::Monitor as u32)),
+                    (
+                        "multiple",
+                        Value::Bool(persist_mode == PersistMode::Application),
+                    ),
+                    ("cursor_mode", Value::U32(cursor_mode as u32)),
+                    ("persist_mode", Value::U32(persist_mode as u32)),
+                ]);
+                let args = (&request, &session_path(token), APP_ID, options);
+                call(&client, SCREENCAST, "SelectSources", &args).await.0
+            }
+        };
+        let responses = tokio::join!(
+            select(
+                "luminous_shared",
+                CursorMode::Embedded,
+                PersistMode::Application
+            ),
+            select(
+                "luminous_shared",
+                CursorMode::Metadata,
+                PersistMode::Transient
+            ),
+            select(
+                "luminous_other",
+                CursorMode::Metadata,
+                PersistMode::Transient
+            ),
+        );
+        assert_eq!(responses, (0, 0, 0));
+
+        let options =
+            |session: &Session| (session.multiple, session.cursor_mode, session.persist_mode);
+        let shared = kept_session(&shared.handle_path).await;
+        assert!(
+            [
+                (true, CursorMode::Embedded, PersistMode::Application),
+                (false, CursorMode::Metadata, PersistMode::Transient),
+            ]
+            .contains(&options(&shared)),
+            "the options selected are mixed: {:?}",
+            options(&shared)
+        );
+        let other = kept_session(&other.handle_path).await;
+        assert_eq!(
+            options(&other),
+            (false, CursorMode::Metadata, PersistMode::Transient)
+        );
+        assert_eq!(SESSIONS.read().await.len(), 2);
+        remove_session(&shared).await;
+        remove_session(&other).await;
+    }
+
+    #[tokio::test]
+    async fn sessions_past_the_cap_are_rejected() {
+        let _globals = lock_globals().await;
+        SETTING_CONFIG.lock().await.max_sessions = 2;
+        let casts = FakeCasts::new(OUTPUTS);
+        casts
+            .then_pick(Some(&["eDP-1"]))
+            .then_pick(Some(&["HDMI-A-1"]));
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let first = selected_session(&client, SCREENCAST, "luminous_first", APP_ID).await;
+        let second = selected_session(&client, SCREENCAST, "luminous_second", APP_ID).await;
+
+        let request = request_path("luminous_third");
+        let options: HashMap<&str, Value<'_>> = HashMap::new();
+        let (response, results) = call(
+            &client,
+            SCREENCAST,
+            "CreateSession",
+            &(&request, &session_path("luminous_third"), APP_ID, options),
+        )
+        .await;
+        assert_eq!((response, results.len()), (2, 0));
+        assert_eq!(SESSIONS.read().await.len(), 2);
+        let options = HashMap::from([("types", Value::U32(SourceType::Monitor as u32))]);
+        let (response, _) = call(
+            &client,
+            SCREENCAST,
+            "SelectSources",
+            &(&request, &session_path("luminous_third"), APP_ID, options),
+        )
+        .await;
+        assert_eq!(response, 2, "the session rejected is kept");
+
+        // The sessions created before are left as they are
+        for (token, cast) in [("luminous_first", "eDP-1"), ("luminous_second", "HDMI-A-1")] {
+            let (response, results) = start(&client, token, APP_ID, HashMap::new()).await.unwrap();
+            assert_eq!(response, 0, "{token} is not started");
+            assert_eq!(stream_ids(&results.unwrap().streams), [cast]);
+        }
+        assert_eq!(casts.started(), 2);
+        remove_session(&first).await;
+        remove_session(&second).await;
+    }
+
+    #[tokio::test]
+    async fn selecting_no_source_type_it_supports_fails_the_request() {
+        let _globals = lock_globals().await;
+        let backend = ScreenCastBackend::new(FakeCasts::new(OUTPUTS));
+        let (_server, client) = serve(PORTAL_PATH, backend).await;
+        let session = selected_session(&client, SCREENCAST, "luminous_types", APP_ID).await;
+        let options = HashMap::from([("types", Value::U32(0))]);
+        let (response, results) = call(
+            &client,
+            SCREENCAST,
+            "SelectSources",
+            &(
+                &request_path("luminous_types"),
+                &session.handle_path,
+                APP_ID,
+                options,
+            ),
+        )
+        .await;
+        assert_eq!(response, 2);
+        let error = String::try_from(results["error"].clone()).unwrap();
+        assert!(error.contains("only Monitor | Window | Virtual"), "{error}");
+        // The types selected before are kept
+        let kept = kept_session(&session.handle_path).await;
+        assert_eq!(kept.source_type, BitFlags::from(SourceType::Monitor));
+        remove_session(&session).await;
+    }
+
+    #[tokio::test]
+    async fn the_members_of_version_4_are_there() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        casts.then_pick(Some(&["eDP-1"]));
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts)).await;
+        let reply = client
+            .call_method(
+                None::<&str>,
+                PORTAL_PATH,
+                Some("org.freedesktop.DBus.Properties"),
+                "Get",
+                &(SCREENCAST, "version"),
+            )
+            .await
+            .unwrap();
+        let version: OwnedValue = reply.body().deserialize().unwrap();
+        assert_eq!(u32::try_from(version).unwrap(), 4);
+
+        // The options of versions 2 and 4, under the keys of the spec
+        let options = HashMap::from([
+            ("types", Value::U32(SourceType::Monitor as u32)),
+            ("cursor_mode", Value::U32(CursorMode::Metadata as u32)),
+            ("persist_mode", Value::U32(PersistMode::Application as u32)),
+            ("restore_token", Value::from("not a token")),
+        ]);
+        let session = session_with(&client, SCREENCAST, "luminous_v4", APP_ID, options).await;
+        assert_eq!(session.cursor_mode, CursorMode::Metadata);
+        assert_eq!(session.persist_mode, PersistMode::Application);
+        assert_eq!(session.restore_token.as_deref(), Some("not a token"));
+
+        let options: HashMap<&str, Value<'_>> = HashMap::new();
+        let (response, results) = call(
+            &client,
+            SCREENCAST,
+            "Start",
+            &(
+                &request_path("luminous_v4_start"),
+                &session.handle_path,
+                APP_ID,
+                "",
+                options,
+            ),
+        )
+        .await;
+        assert_eq!(response, 0);
+        let mut keys: Vec<&str> = results.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["persist_mode", "restore_token", "streams"]);
+        let persist_mode = u32::try_from(results["persist_mode"].clone()).unwrap();
+        assert_eq!(persist_mode, PersistMode::Application as u32);
+        remove_session(&session).await;
+    }
+
+    #[tokio::test]
+    async fn the_last_selection_is_kept_and_offered_only_without_a_token() {
+        let _globals = lock_globals().await;
+        let casts = FakeCasts::new(OUTPUTS);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let last = || last_selection(APP_ID).map(|sources| sources[0].output_name.clone());
+        let persisted = || {
+            HashMap::from([
+                ("types", Value::U32(SourceType::Monitor as u32)),
+                ("persist_mode", Value::U32(PersistMode::Application as u32)),
+            ])
+        };
+
+        // Not to persist, so what is picked is not kept either
+        casts.then_pick(Some(&["eDP-1"]));
+        let session = selected_session(&client, SCREENCAST, "luminous_forgotten", APP_ID).await;
+        let (response, _) = start(&client, "luminous_forgotten", APP_ID, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response, 0);
+        assert_eq!(last(), None);
+        remove_session(&session).await;
+
+        casts.then_pick(Some(&["HDMI-A-1"]));
+        let session = session_with(&client, SCREENCAST, "luminous_kept", APP_ID, persisted()).await;
+        let (response, _) = start(&client, "luminous_kept", APP_ID, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response, 0);
+        assert_eq!(last().as_deref(), Some("HDMI-A-1"));
+        remove_session(&session).await;
+
+        // Without a token it is offered, and cast once the user agrees
+        casts.then_answer(true);
+        let session =
+            session_with(&client, SCREENCAST, "luminous_again", APP_ID, persisted()).await;
+        let (response, results) = start(&client, "luminous_again", APP_ID, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response, 0);
+        assert_eq!(stream_ids(&results.unwrap().streams), ["HDMI-A-1"]);
+        assert!(casts.picked_all());
+        remove_session(&session).await;
+
+        // A token is not asked about, whether it restores or the user picks anew
+        let valid = granted_token(APP_ID, "eDP-1").await;
+        let stale = granted_token(APP_ID, "DP-9").await;
+        casts.then_pick(Some(&["eDP-1"]));
+        for (token, restore_token) in [("luminous_valid", valid), ("luminous_stale", stale)] {
+            let session = restoring_session(&client, token, &restore_token).await;
+            let (response, results) = start(&client, token, APP_ID, HashMap::new()).await.unwrap();
+            assert_eq!(response, 0, "{token} is not started");
+            assert_eq!(stream_ids(&results.unwrap().streams), ["eDP-1"]);
+            remove_session(&session).await;
+        }
+        assert!(casts.picked_all());
+    }
+
+    #[tokio::test]
+    async fn a_modal_start_fails_rather_than_show_the_picker() {
+        let _globals = lock_globals().await;
+        // No pick is canned, it would fail the test if shown
+        let casts = FakeCasts::new(OUTPUTS);
+        let (_server, client) = serve(PORTAL_PATH, ScreenCastBackend::new(casts.clone())).await;
+        let modal = || HashMap::from([("modal", Value::Bool(true))]);
+        let session = selected_session(&client, SCREENCAST, "luminous_modal", APP_ID).await;
+        let (response, results) = call(
+            &client,
+            SCREENCAST,
+            "Start",
+            &(
+                &request_path("luminous_modal_start"),
+                &session.handle_path,
+                APP_ID,
+                "",
+                modal(),
+            ),
+        )
+        .await;
+        assert_eq!(response, 2);
+        let error = String::try_from(results["error"].clone()).unwrap();
+        assert!(error.contains("start without modal"), "{error}");
+        assert_eq!(casts.started(), 0);
+        remove_session(&session).await;
+
+        // Nothing is shown for a token, which restores all the same
+        let token = granted_token(APP_ID, "eDP-1").await;
+        let session = restoring_session(&client, "luminous_modal_restored", &token).await;
+        let (response, results) = start(&client, "luminous_modal_restored", APP_ID, modal())
+            .await
+            .unwrap();
+        assert_eq!(response, 0);
+        assert_eq!(stream_ids(&results.unwrap().streams), ["eDP-1"]);
+        remove_session(&session).await;
+    }
+
+    #[test]
+    fn outputs_are_listed_in_their_layout_and_their_mode() {
+        let output = ListedOutput {
+            name: "eDP-1".to_string(),
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080,
+            refresh: 59_940,
+            mode_width: 3840,
+            mode_height: 2160,
+        };
+        assert_eq!(
+            output.layout(),
+            ("eDP-1".to_string(), 0, 0, 1920, 1080, 59_940)
+        );
+        assert_eq!(
+            output.mode(),
+            OutputMode {
+                name: "eDP-1".to_string(),
+                width: 3840,
+                height: 2160,
+                refresh_hz: 59.94,
+            }
+        );
+        assert_eq!(refresh_hz(0), 0.);
+        assert_eq!(refresh_hz(60_000), 60.);
+        // ListOutputs keeps the signature it always had
+        assert_eq!(Vec::<OutputLayout>::SIGNATURE.to_string(), "a(siiiiu)");
+        assert_eq!(Vec::<OutputMode>::SIGNATURE.to_string(), "a(siid)");
+    }
+}
@@ -1,13 +1,25 @@
-use libwayshot::WayshotConnection;
+use image::RgbaImage;
+use image::buffer::ConvertBuffer;
+use image::codecs::jpeg::JpegEncoder;
+use libwayshot::{WayshotConnection, reexport::WlOutput};
 use libwaysip::Point;
 use screenshotdialog::ScreenInfo;
 use screenshotdialog::SlintSelection;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use wayland_client::protocol::wl_shm;
 use zbus::zvariant::{DeserializeDict, SerializeDict, Type, Value};
 use zbus::{fdo, interface, zvariant::ObjectPath};
 
 use crate::PortalResponse;
-use crate::utils::USER_RUNNING_DIR;
+use crate::session::CursorMode;
+use crate::utils::{
+    USER_RUNNING_DIR, connect_wayshot, pick_area_with_command, region_picker, screenshot_area,
+};
 
 use libwaysip::SelectionType;
 
@@ -26,9 +38,80 @@ struct Color {
 #[derive(DeserializeDict, SerializeDict, Type, Debug)]
 #[zvariant(signature = "dict")]
 pub struct ScreenshotOption {
-    interactive: bool,
+    interactive: Option<bool>,
     modal: Option<bool>,
     permission_store_checked: Option<bool>,
+    /// Not in the spec, whether the cursor is painted when the dialog does not tell; only
+    /// [`CursorMode::Embedded`] paints it
+    cursor_mode: Option<CursorMode>,
+    /// Not in the spec, the connector name of the one output to shoot, like `DP-1`. The
+    /// dialog is not shown then, even for an interactive shot
+    output: Option<String>,
+    /// Not in the spec, `png`, the default, `jpeg` or `webp`
+    format: Option<String>,
+    /// Not in the spec, the quality of a `jpeg` shot in `1..=100`
+    quality: Option<u32>,
+}
+
+/// What the file of each shot is called, before its uuid and its extension
+const SHOT_FILE_PREFIX: &str = "luminous-screenshot-";
+
+/// How long a shot is kept in the runtime dir, by then the app has read it long since
+const STALE_SHOT_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The quality of a JPEG shot when none is asked for
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+/// How a shot is encoded, see [`ScreenshotOption::format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShotFormat {
+    Png,
+    /// Has no alpha, transparent gaps between outputs turn black
+    Jpeg {
+        quality: u8,
+    },
+    /// Always lossless, the encoder has no lossy mode
+    WebP,
+}
+
+impl ShotFormat {
+    fn from_options(options: &ScreenshotOption) -> Result<Self, String> {
+        match options.format.as_deref().unwrap_or("png") {
+            "png" => Ok(Self::Png),
+            "jpeg" | "jpg" => {
+                let quality = options
+                    .quality
+                    .map_or(DEFAULT_JPEG_QUALITY, |quality| quality.clamp(1, 100) as u8);
+                Ok(Self::Jpeg { quality })
+            }
+            "webp" => Ok(Self::WebP),
+            format => Err(format!(
+                "Unknown format {format}, it must be png, jpeg or webp"
+            )),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg { .. } => "jpg",
+            Self::WebP => "webp",
+        }
+    }
+
+    fn save(self, image: &RgbaImage, path: &Path) -> anyhow::Result<()> {
+        match self {
+            Self::Png => image.save_with_format(path, image::ImageFormat::Png)?,
+            Self::Jpeg { quality } => {
+                let rgb: image::RgbImage = image.convert();
+                let mut encoder =
+                    JpegEncoder::new_with_quality(BufWriter::new(File::create(path)?), quality);
+                encoder.encode_image(&rgb)?;
+            }
+            Self::WebP => image.save_with_format(path, image::ImageFormat::WebP)?,
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -38,122 +121,294 @@ pub struct ScreenShotBackend;
 impl ScreenShotBackend {
     #[zbus(property, name = "version")]
     fn version(&self) -> u32 {
-        1
+        2
     }
-    fn screenshot(
-        &mut self,
+    async fn screenshot(
+        &self,
         handle: ObjectPath<'_>,
         app_id: String,
         _parent_window: String,
         options: ScreenshotOption,
     ) -> fdo::Result<PortalResponse<Screenshot>> {
         tracing::info!("Start shot: path :{}, appid: {}", handle.as_str(), app_id);
-        let wayshot_connection = WayshotConnection::new()
-            .map_err(|_| zbus::Error::Failure("Cannot update outputInfos".to_string()))?;
-
-        let image_buffer = if options.interactive {
-            let wayinfos = wayshot_connection.get_all_outputs();
-            let screen_infos = wayinfos
-                .iter()
-                .map(|screen| ScreenInfo {
-                    name: screen.name.clone().into(),
-                    description: screen.description.clone().into(),
-                })
-                .collect();
-            match screenshotdialog::selectgui(screen_infos) {
-                SlintSelection::Canceled => return Ok(PortalResponse::Cancelled),
-                SlintSelection::Slurp => {
-                    let info = match libwaysip::get_area(None, SelectionType::Area) {
-                        Ok(Some(info)) => info,
-                        Ok(None) => {
-                            return Err(zbus::Error::Failure("You cancel it".to_string()).into());
-                        }
-                        Err(e) => {
-                            return Err(zbus::Error::Failure(format!("wayland error, {e}")).into());
-                        }
-                    };
-
-                    let Point {
-                        x: x_coordinate,
-                        y: y_coordinate,
-                    } = info.left_top_point();
-                    let width = info.width();
-                    let height = info.height();
-
-                    wayshot_connection
-                        .screenshot(
-                            libwayshot::CaptureRegion {
-                                x_coordinate,
-                                y_coordinate,
-                                width,
-                                height,
-                            },
-                            false,
-                        )
-                        .map_err(|e| {
-                            zbus::Error::Failure(format!("Wayland screencopy failed, {e}"))
-                        })?
-                }
-                SlintSelection::GlobalScreen { showcursor } => wayshot_connection
-                    .screenshot_all(showcursor)
-                    .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?,
-                SlintSelection::Selection { index, showcursor } => wayshot_connection
-                    .screenshot_single_output(&wayinfos[index as usize], showcursor)
-                    .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?,
-            }
-        } else {
-            wayshot_connection
-                .screenshot_all(false)
-                .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?
+        // Checked first, the user would pick an area for nothing otherwise
+        let shot_format = ShotFormat::from_options(&options).map_err(zbus::Error::Failure)?;
+        let region_picker = region_picker().await;
+        let show_cursor = options
+            .cursor_mode
+            .is_some_and(|cursor_mode| cursor_mode.show_cursor());
+        let output = options.output.clone();
+        let interactive = options.interactive.unwrap_or(false);
+        // The dialogs, the pickers, the screencopy and the encoding all block, so they run off
+        // the executor
+        let saved = tokio::task::spawn_blocking(move || {
+            let shot = take_shot(
+                output.as_deref(),
+                interactive,
+                show_cursor,
+                region_picker.as_deref(),
+            )?;
+            let Some(image_buffer) = shot else {
+                return Ok(None);
+            };
+            remove_stale_shots(&USER_RUNNING_DIR, SystemTime::now());
+            // Each shot gets a file of its own, so concurrent requests do not overwrite each
+            // other
+            let savepath = USER_RUNNING_DIR.join(format!(
+                "{SHOT_FILE_PREFIX}{}.{}",
+                uuid::Uuid::new_v4(),
+                shot_format.extension()
+            ));
+            shot_format.save(&image_buffer, &savepath).map_err(|e| {
+                zbus::Error::Failure(format!("Cannot save to {}, e: {e}", savepath.display()))
+            })?;
+            Ok::<_, fdo::Error>(Some(savepath))
+        })
+        .await
+        .map_err(|e| zbus::Error::Failure(format!("the shot failed, error: {e}")))??;
+        let Some(savepath) = saved else {
+            return Ok(PortalResponse::Cancelled);
         };
-        let savepath = USER_RUNNING_DIR.join("wayshot.png");
-        image_buffer.save(&savepath).map_err(|e| {
-            zbus::Error::Failure(format!("Cannot save to {}, e: {e}", savepath.display()))
-        })?;
         tracing::info!("Shot Finished");
         Ok(PortalResponse::Success(Screenshot {
             uri: url::Url::from_file_path(savepath).unwrap(),
         }))
     }
 
-    fn pick_color(
-        &mut self,
+    async fn pick_color(
+        &self,
         _handle: ObjectPath<'_>,
         _app_id: String,
         _parent_window: String,
         _options: HashMap<String, Value<'_>>,
     ) -> fdo::Result<PortalResponse<Color>> {
-        let wayshot_connection = WayshotConnection::new()
-            .map_err(|_| zbus::Error::Failure("Cannot update outputInfos".to_string()))?;
-        let info = match libwaysip::get_area(None, SelectionType::Point) {
-            Ok(Some(info)) => info,
-            Ok(None) => return Err(zbus::Error::Failure("You cancel it".to_string()).into()),
-            Err(e) => return Err(zbus::Error::Failure(format!("wayland error, {e}")).into()),
+        // The picker and the screencopy block, so they run off the executor
+        let color = tokio::task::spawn_blocking(pick_color)
+            .await
+            .map_err(|e| zbus::Error::Failure(format!("the color pick failed, error: {e}")))??;
+        Ok(match color {
+            Some(color) => PortalResponse::Success(Color { color }),
+            None => PortalResponse::Cancelled,
+        })
+    }
+}
+
+/// Let the user pick a point, and read its color, see [`sample_pixel`]. `None` if the user
+/// cancels.
+fn pick_color() -> fdo::Result<Option<[f64; 3]>> {
+    let wayshot_connection = connect_wayshot()?;
+    let info = match libwaysip::get_area(None, SelectionType::Point) {
+        Ok(Some(info)) => info,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(zbus::Error::Failure(format!("wayland error, {e}")).into()),
+    };
+    let Point { x, y } = info.left_top_point();
+    let output_name = info.screen_info.output_info.name;
+    let Some(output) = wayshot_connection
+        .get_all_outputs()
+        .iter()
+        .find(|output| output.name == output_name)
+    else {
+        return Err(zbus::Error::Failure(format!("The output {output_name} is gone")).into());
+    };
+    let region = libwayshot::CaptureRegion {
+        x_coordinate: x - output.dimensions.x,
+        y_coordinate: y - output.dimensions.y,
+        width: 1,
+        height: 1,
+    };
+    let color = sample_pixel(&wayshot_connection, &output.wl_output, region)
+        .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?;
+    Ok(Some(color))
+}
+
+/// Take a shot of the output called `output`, or of what the user picks if `interactive`,
+/// or else of every output. `None` if the user cancels.
+///
+/// It blocks on the compositor, the dialog and the region pickers.
+fn take_shot(
+    output: Option<&str>,
+    interactive: bool,
+    show_cursor: bool,
+    region_picker: Option<&str>,
+) -> fdo::Result<Option<RgbaImage>> {
+    let wayshot_connection = connect_wayshot()?;
+    let image_buffer = if let Some(output_name) = output {
+        let outputs = wayshot_connection.get_all_outputs();
+        let Some(output) = outputs.iter().find(|output| output.name == output_name) else {
+            let known: Vec<&str> = outputs.iter().map(|output| output.name.as_str()).collect();
+            return Err(zbus::Error::Failure(format!(
+                "No output is called {output_name}, the outputs are: {}",
+                known.join(", ")
+            ))
+            .into());
         };
-        let Point {
-            x: x_coordinate,
-            y: y_coordinate,
-        } = info.left_top_point();
-
-        let image = wayshot_connection
-            .screenshot(
-                libwayshot::CaptureRegion {
-                    x_coordinate,
-                    y_coordinate,
-                    width: 1,
-                    height: 1,
-                },
-                false,
-            )
-            .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?;
-
-        let pixel = image.get_pixel(0, 0);
-        Ok(PortalResponse::Success(Color {
-            color: [
-                pixel.0[0] as f64 / 256.0,
-                pixel.0[1] as f64 / 256.0,
-                pixel.0[2] as f64 / 256.0,
-            ],
-        }))
+        wayshot_connection
+            .screenshot_single_output(output, show_cursor)
+            .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?
+    } else if interactive {
+        let wayinfos = wayshot_connection.get_all_outputs();
+        let screen_infos = wayinfos
+            .iter()
+            .map(|screen| ScreenInfo {
+                name: screen.name.clone().into(),
+                description: screen.description.clone().into(),
+            })
+            .collect();
+        match screenshotdialog::selectgui(screen_infos) {
+            SlintSelection::Canceled => return Ok(None),
+            SlintSelection::Slurp => {
+                let picked = region_picker.and_then(pick_area_with_command);
+                let (x_coordinate, y_coordinate, width, height) = match picked {
+                    Some(Ok(Some(area))) => (
+                        area.x_coordinate,
+                        area.y_coordinate,
+                        area.width,
+                        area.height,
+                    ),
+                    Some(Ok(None)) => return Ok(None),
+                    Some(Err(e)) => {
+                        return Err(zbus::Error::Failure(format!(
+                            "the region picker failed, error: {e}"
+                        ))
+                        .into());
+                    }
+                    None => {
+                        let info = match libwaysip::get_area(None, SelectionType::Area) {
+                            Ok(Some(info)) => info,
+                            Ok(None) => return Ok(None),
+                            Err(e) => {
+                                return Err(
+                                    zbus::Error::Failure(format!("wayland error, {e}")).into()
+                                );
+                            }
+                        };
+                        let Point { x, y } = info.left_top_point();
+                        (x, y, info.width(), info.height())
+                    }
+                };
+                if width <= 0 || height <= 0 {
+                    return Err(
+                        zbus::Error::Failure("The selected area is empty".to_string()).into(),
+                    );
+                }
+
+                screenshot_area(
+                    &wayshot_connection,
+                    libwayshot::CaptureRegion {
+                        x_coordinate,
+                        y_coordinate,
+                        width,
+                        height,
+                    },
+                    show_cursor,
+                )
+                .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?
+            }
+            SlintSelection::GlobalScreen { showcursor } => wayshot_connection
+                .screenshot_all(showcursor)
+                .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?,
+            SlintSelection::Selection { index, showcursor } => wayshot_connection
+                .screenshot_single_output(&wayinfos[index as usize], showcursor)
+                .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?,
+        }
+    } else {
+        wayshot_connection
+            .screenshot_all(show_cursor)
+            .map_err(|e| zbus::Error::Failure(format!("Wayland screencopy failed, {e}")))?
+    };
+    Ok(Some(image_buffer))
+}
+
+/// Remove the shots in `dir` older than [`STALE_SHOT_AGE`] at `now`, so that they do not pile
+/// up in the runtime dir while the portal runs.
+fn remove_stale_shots(dir: &Path, now: SystemTime) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let is_shot = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(SHOT_FILE_PREFIX));
+        let modified = entry.metadata().and_then(|metadata| metadata.modified());
+        let stale = modified.is_ok_and(|modified| {
+            now.duration_since(modified)
+                .is_ok_and(|age| age > STALE_SHOT_AGE)
+        });
+        if !(is_shot && stale) {
+            continue;
+        }
+        if let Err(e) = std::fs::remove_file(entry.path()) {
+            tracing::warn!(
+                "cannot remove the stale shot {:?}, error: {e}",
+                entry.path()
+            );
+        }
+    }
+}
+
+/// Read the first physical pixel of `region` on `output`, as rgb in `0..=1`.
+///
+/// On a scaled output one logical pixel covers several physical ones, the top left one is
+/// taken as is instead of blending them.
+fn sample_pixel(
+    connection: &WayshotConnection,
+    output: &WlOutput,
+    region: libwayshot::CaptureRegion,
+) -> anyhow::Result<[f64; 3]> {
+    let fd = rustix::fs::memfd_create(c"luminous-pick-color", rustix::fs::MemfdFlags::CLOEXEC)?;
+    // Big enough for one logical pixel at any sensible scale
+    rustix::fs::ftruncate(&fd, 64 * 64 * 4)?;
+    let frame_format = connection.capture_output_frame_shm_fd(0, output, &fd, Some(region))?;
+    let mut bytes = [0; 4];
+    File::from(fd).read_exact_at(&mut bytes, 0)?;
+
+    // wl_shm formats are little endian, so the byte order is reversed
+    let color = match frame_format.format {
+        wl_shm::Format::Argb8888 | wl_shm::Format::Xrgb8888 => {
+            [bytes[2], bytes[1], bytes[0]].map(|channel| channel as f64 / 255.)
+        }
+        wl_shm::Format::Abgr8888 | wl_shm::Format::Xbgr8888 => {
+            [bytes[0], bytes[1], bytes[2]].map(|channel| channel as f64 / 255.)
+        }
+        wl_shm::Format::Abgr2101010 | wl_shm::Format::Xbgr2101010 => {
+            let pixel = u32::from_le_bytes(bytes);
+            [0, 10, 20].map(|shift| ((pixel >> shift) & 0x3ff) as f64 / 1023.)
+        }
+        wl_shm::Format::Argb2101010 | wl_shm::Format::Xrgb2101010 => {
+            let pixel = u32::from_le_bytes(bytes);
+            [20, 10, 0].map(|shift| ((pixel >> shift) & 0x3ff) as f64 / 1023.)
+        }
+        format => anyhow::bail!("cannot read pixels of format {format:?}"),
+    };
+    Ok(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_stale_shots_are_removed() {
+        let dir = std::env::temp_dir().join(format!("luminous-shots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let now = SystemTime::now();
+        let file = |name: &str, age: Duration| {
+            let path = dir.join(name);
+            File::create(&path)
+                .unwrap()
+                .set_modified(now - age)
+                .unwrap();
+            path
+        };
+        let stale = file("luminous-screenshot-stale.png", STALE_SHOT_AGE * 2);
+        let fresh = file("luminous-screenshot-fresh.png", Duration::from_secs(60));
+        let other = file("other-stale.png", STALE_SHOT_AGE * 2);
+        remove_stale_shots(&dir, now);
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        assert!(other.exists(), "a file that is no shot is removed");
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
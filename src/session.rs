@@ -3,49 +3,175 @@ use zbus::{interface, object_server::SignalEmitter, zvariant::OwnedObjectPath};
 
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use zbus::zvariant::Type;
+use zbus::zvariant::{ObjectPath, Type, Value};
 
+use std::collections::HashMap;
 use std::sync::{Arc, LazyLock};
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
 
 use crate::{
-    remotedesktop::{SelectDevicesOptions, remove_remote_session},
-    screencast::{SelectSourcesOptions, remove_cast_session},
+    get_connection,
+    remotedesktop::{SelectDevicesOptions, is_remote_started, remove_remote_session},
+    screencast::{SelectSourcesOptions, is_cast_idle, remove_cast_session},
+    settings::SETTING_CONFIG,
 };
 
-pub static SESSIONS: LazyLock<Arc<Mutex<Vec<Session>>>> =
-    LazyLock::new(|| Arc::new(Mutex::new(Vec::new())));
+/// How often the sessions are checked for being idle.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-pub async fn append_session(session: Session) {
-    let mut sessions = SESSIONS.lock().await;
-    sessions.push(session)
+/// The sessions created and not closed yet, most calls only look them up so they share a
+/// read lock.
+pub static SESSIONS: LazyLock<Arc<RwLock<Vec<Session>>>> =
+    LazyLock::new(|| Arc::new(RwLock::new(Vec::new())));
+
+/// Add `session`, unless there are `max_sessions` already, against clients that create them
+/// without end. Returns whether it is added.
+pub async fn append_session(session: Session) -> bool {
+    let max_sessions = SETTING_CONFIG.lock().await.max_sessions;
+    let mut sessions = SESSIONS.write().await;
+    if sessions.len() >= max_sessions {
+        tracing::warn!(
+            "there are {max_sessions} sessions already, {} cannot create another one",
+            session.app_id
+        );
+        return false;
+    }
+    sessions.push(session);
+    true
 }
 
+/// Forget `session`, and stop whatever it still casts.
+///
+/// The casts are stopped even if the session is not registered anymore, so a thread is never
+/// left running behind a closed session. It is forgotten first, so a start going on does not
+/// add casts after they are stopped.
 pub async fn remove_session(session: &Session) {
-    let mut sessions = SESSIONS.lock().await;
-    let Some(index) = sessions
+    let path = session.handle_path.to_string();
+    SESSIONS
+        .write()
+        .await
+        .retain(|the_session| the_session.handle_path != session.handle_path);
+    remove_cast_session(&path).await;
+    remove_remote_session(&path).await;
+}
+
+/// The token `path` was made from, as the client passed it under `key` in the `options` of the
+/// call, or else the last element of `path`, where the frontend puts it.
+///
+/// A token that does not match `path` is not used, since the frontend tells requests and
+/// sessions apart by their path.
+pub fn handle_token(
+    options: &HashMap<String, Value<'_>>,
+    key: &str,
+    path: &ObjectPath<'_>,
+) -> String {
+    let derived = path
+        .as_str()
+        .rsplit('/')
+        .next()
+        .filter(|token| !token.is_empty());
+    let passed = options
+        .get(key)
+        .and_then(|value| <&str>::try_from(value).ok());
+    match (passed, derived) {
+        (Some(token), Some(derived)) if token != derived => {
+            tracing::warn!("the {key} {token} does not match {path}, use {derived} instead");
+            derived.to_string()
+        }
+        (_, Some(derived)) => derived.to_string(),
+        (Some(token), None) => token.to_string(),
+        (None, None) => path.to_string(),
+    }
+}
+
+/// Close the session at `path` from the portal side, like when all its casts have ended, and
+/// tell the client with the Closed signal.
+pub async fn close_session(path: &str, reason: &str) {
+    let session = SESSIONS
+        .read()
+        .await
         .iter()
-        .position(|the_session| the_session.handle_path == session.handle_path)
-    else {
+        .find(|session| session.handle_path.as_str() == path)
+        .cloned();
+    let Some(session) = session else {
         return;
     };
-    remove_cast_session(&session.handle_path.to_string()).await;
-    remove_remote_session(&session.handle_path.to_string()).await;
-    sessions.remove(index);
+    remove_session(&session).await;
+    let connection = get_connection().await;
+    let _ = connection
+        .object_server()
+        .remove::<Session, &str>(path)
+        .await;
+    emit_closed(&connection, path, reason).await;
+}
+
+/// Tell the client that the session at `path` is closed, and why.
+pub async fn emit_closed(connection: &zbus::Connection, path: &str, reason: &str) {
+    let result = match SignalEmitter::new(connection, path) {
+        Ok(emitter) => Session::closed(&emitter, reason).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => tracing::info!("session {path} is closed: {reason}"),
+        Err(e) => tracing::warn!("cannot tell that {path} is closed, error: {e}"),
+    }
+}
+
+/// Close the sessions idle for longer than the `idle_timeout` of the config, for clients
+/// that forget to close them. Runs as long as the portal does.
+///
+/// A session is idle while it is not started, or while no consumer is connected to any of
+/// its casts. A started remote desktop session never is, it may only be sending input.
+pub async fn close_idle_sessions() {
+    let mut idle_since: HashMap<String, Instant> = HashMap::new();
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Read every time, the config is reloaded when it changes
+        let Some(timeout) = SETTING_CONFIG.lock().await.idle_timeout else {
+            idle_since.clear();
+            continue;
+        };
+        let timeout = Duration::from_secs(timeout);
+        let paths: Vec<String> = SESSIONS
+            .read()
+            .await
+            .iter()
+            .map(|session| session.handle_path.to_string())
+            .collect();
+        idle_since.retain(|path, _| paths.contains(path));
+        let mut expired = Vec::new();
+        for path in paths {
+            if !is_cast_idle(&path).await || is_remote_started(&path).await {
+                idle_since.remove(&path);
+                continue;
+            }
+            let since = *idle_since.entry(path.clone()).or_insert_with(Instant::now);
+            if since.elapsed() >= timeout {
+                expired.push(path);
+            }
+        }
+        for path in expired {
+            tracing::info!("{path} is idle for {timeout:?}, close it");
+            idle_since.remove(&path);
+            close_session(&path, "The session was idle for too long").await;
+        }
+    }
 }
 
 #[bitflags]
-#[derive(Serialize, Default, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Type)]
+#[derive(Serialize_repr, Deserialize_repr, Default, PartialEq, Eq, Copy, Clone, Debug, Type)]
 #[repr(u32)]
 /// A bit flag for the available sources to record.
 pub enum SourceType {
     #[default]
     /// A monitor.
-    Monitor,
-    /// A specific window
-    Window,
-    /// Virtual
-    Virtual,
+    Monitor = 1,
+    /// A specific window.
+    Window = 2,
+    /// A virtual screen, not backed by a physical output.
+    Virtual = 4,
 }
 
 #[bitflags]
@@ -80,8 +206,9 @@ pub enum DeviceType {
 }
 
 impl CursorMode {
+    /// Whether the cursor is painted into the captured frames.
     pub fn show_cursor(&self) -> bool {
-        !matches!(self, CursorMode::Hidden)
+        matches!(self, CursorMode::Embedded)
     }
 }
 
@@ -109,26 +236,57 @@ pub enum PersistMode {
 pub struct Session {
     pub session_type: SessionType,
     pub handle_path: OwnedObjectPath,
+    /// The token `handle_path` is made from, handed back by `create_session`, see
+    /// [`handle_token`]
+    pub handle_token: String,
+    /// The application that created the session, the only one that can start it
+    pub app_id: String,
     pub source_type: BitFlags<SourceType>,
     pub multiple: bool,
     pub cursor_mode: CursorMode,
     pub persist_mode: PersistMode,
+    pub restore_token: Option<String>,
 
     pub device_type: BitFlags<DeviceType>,
+    /// The unique bus name of the client that created the session, the only one that can
+    /// control its casts over [`crate::casts::CastsBackend`], `None` when it came with no name
+    pub sender: Option<String>,
 }
 
 impl Session {
-    pub fn new<P: Into<OwnedObjectPath>>(path: P, session_type: SessionType) -> Self {
+    pub fn new<P: Into<OwnedObjectPath>>(
+        path: P,
+        handle_token: String,
+        session_type: SessionType,
+        app_id: String,
+    ) -> Self {
         Self {
             session_type,
             handle_path: path.into(),
+            handle_token,
+            app_id,
             source_type: SourceType::Monitor.into(),
             multiple: false,
             cursor_mode: CursorMode::Hidden,
             persist_mode: PersistMode::DoNot,
+            restore_token: None,
             device_type: DeviceType::Keyboard.into(),
+            sender: None,
+        }
+    }
+
+    /// Check that the session is one of the `valid` types, or tell how it does not match.
+    pub fn check_type(&self, valid: &[SessionType]) -> Result<(), String> {
+        if valid.contains(&self.session_type) {
+            return Ok(());
         }
+        Err(format!(
+            "{} is a {:?} session, only {valid:?} sessions can do this",
+            self.handle_path.as_str(),
+            self.session_type
+        ))
     }
+
     pub fn set_screencast_options(&mut self, options: SelectSourcesOptions) {
         if let Some(types) = options.types {
             self.source_type = types;
@@ -140,6 +298,7 @@ impl Session {
         if let Some(persist_mode) = options.persist_mode {
             self.persist_mode = persist_mode;
         }
+        self.restore_token = options.restore_token;
     }
 
     pub fn set_remote_options(&mut self, options: SelectDevicesOptions) {
@@ -149,6 +308,10 @@ impl Session {
         if let Some(persist_mode) = options.persist_mode {
             self.persist_mode = persist_mode;
         }
+        // The token may have come with the sources already, selected first
+        if let Some(restore_token) = options.restore_token {
+            self.restore_token = Some(restore_token);
+        }
     }
 }
 
@@ -159,10 +322,11 @@ impl Session {
         #[zbus(signal_emitter)] cxts: SignalEmitter<'_>,
         #[zbus(object_server)] server: &zbus::ObjectServer,
     ) -> zbus::fdo::Result<()> {
+        // Stop the casts first, they must not outlive the session even if it is gone already
+        remove_session(self).await;
         server
             .remove::<Self, &OwnedObjectPath>(&self.handle_path)
             .await?;
-        remove_session(self).await;
         Self::closed(&cxts, "Closed").await?;
         Ok(())
     }
@@ -175,3 +339,227 @@ impl Session {
     #[zbus(signal)]
     async fn closed(signal_ctxt: &SignalEmitter<'_>, message: &str) -> zbus::Result<()>;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::pipewirethread::ScreencastThread;
+    use crate::screencast::{CAST_SESSIONS, StreamProperties, remove_cast_stream};
+    use crate::testing::{lock_globals, serve};
+
+    fn session(session_type: SessionType) -> Session {
+        Session::new(
+            ObjectPath::from_static_str_unchecked("/org/luminous/test/session"),
+            "session".to_string(),
+            session_type,
+            "org.luminous.Test".to_string(),
+        )
+    }
+
+    #[test]
+    fn check_type_tells_the_mismatch() {
+        let remote = session(SessionType::Remote);
+        assert_eq!(
+            remote.check_type(&[SessionType::ScreenCast, SessionType::Remote]),
+            Ok(())
+        );
+        assert_eq!(
+            remote.check_type(&[SessionType::ScreenCast]),
+            Err(
+                "/org/luminous/test/session is a Remote session, only [ScreenCast] sessions can do this"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn selecting_devices_keeps_the_restore_token() {
+        let mut remote = session(SessionType::Remote);
+        remote.set_screencast_options(SelectSourcesOptions {
+            restore_token: Some("token".to_string()),
+            ..Default::default()
+        });
+        remote.set_remote_options(SelectDevicesOptions::default());
+        assert_eq!(remote.restore_token.as_deref(), Some("token"));
+    }
+
+    #[test]
+    fn handle_token_is_the_one_passed() {
+        let path = ObjectPath::from_static_str_unchecked(
+            "/org/freedesktop/portal/desktop/session/1_1/luminous",
+        );
+        let options =
+            HashMap::from([("session_handle_token".to_string(), Value::from("luminous"))]);
+        assert_eq!(
+            handle_token(&options, "session_handle_token", &path),
+            "luminous"
+        );
+        // The path is what the frontend finds the session by
+        let options = HashMap::from([("session_handle_token".to_string(), Value::from("other"))]);
+        assert_eq!(
+            handle_token(&options, "session_handle_token", &path),
+            "luminous"
+        );
+    }
+
+    #[test]
+    fn handle_token_is_derived_when_absent() {
+        let path = ObjectPath::from_static_str_unchecked(
+            "/org/freedesktop/portal/desktop/request/1_1/luminous",
+        );
+        assert_eq!(
+            handle_token(&HashMap::new(), "handle_token", &path),
+            "luminous"
+        );
+        let options = HashMap::from([("handle_token".to_string(), Value::from("luminous"))]);
+        let root = ObjectPath::from_static_str_unchecked("/");
+        assert_eq!(handle_token(&options, "handle_token", &root), "luminous");
+        assert_eq!(handle_token(&HashMap::new(), "handle_token", &root), "/");
+    }
+
+    #[tokio::test]
+    async fn removing_a_session_stops_its_casts() {
+        let _globals = lock_globals().await;
+        let path = "/org/luminous/test/removed";
+        let stopped = Arc::new(AtomicBool::new(false));
+        CAST_SESSIONS.lock().await.push((
+            path.to_string(),
+            ScreencastThread::idle(0, stopped.clone()),
+            StreamProperties::default(),
+        ));
+        // Never registered, or gone already, its casts are stopped all the same
+        let removed = Session::new(
+            ObjectPath::from_static_str_unchecked(path),
+            "removed".to_string(),
+            SessionType::ScreenCast,
+            "org.luminous.Test".to_string(),
+        );
+        remove_session(&removed).await;
+        assert!(stopped.load(Ordering::Relaxed), "the cast is not stopped");
+        assert!(!CAST_SESSIONS.lock().await.iter().any(|cast| cast.0 == path));
+    }
+
+    #[tokio::test]
+    async fn stopping_a_cast_twice_does_nothing_more() {
+        let _globals = lock_globals().await;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let cast = ScreencastThread::idle(0, stopped.clone());
+        cast.stop();
+        cast.stop();
+        assert!(stopped.load(Ordering::Relaxed), "the cast is not stopped");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !cast.is_finished() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the cast thread is not finished");
+
+        let path = "/org/luminous/test/stopped_twice";
+        let other = "/org/luminous/test/left";
+        let left = Arc::new(AtomicBool::new(false));
+        CAST_SESSIONS.lock().await.extend([
+            (path.to_string(), cast, StreamProperties::default()),
+            (
+                other.to_string(),
+                ScreencastThread::idle(1, left.clone()),
+                StreamProperties::default(),
+            ),
+        ]);
+        let stopped_twice = Session::new(
+            ObjectPath::from_static_str_unchecked(path),
+            "stopped_twice".to_string(),
+            SessionType::ScreenCast,
+            "org.luminous.Test".to_string(),
+        );
+        remove_session(&stopped_twice).await;
+        remove_session(&stopped_twice).await;
+        // Like the cast ending on its own after it was stopped
+        remove_cast_stream(path, 0).await;
+        let casts = CAST_SESSIONS.lock().await;
+        assert_eq!(casts.len(), 1);
+        assert_eq!(casts[0].0, other);
+        assert!(
+            !left.load(Ordering::Relaxed),
+            "another session's cast is stopped"
+        );
+    }
+
+    #[tokio::test]
+    async fn close_forgets_the_session_and_stops_its_casts() {
+        let _globals = lock_globals().await;
+        let path = "/org/freedesktop/portal/desktop/session/1_1/luminous_closed";
+        let closed = Session::new(
+            ObjectPath::from_static_str_unchecked(path),
+            "luminous_closed".to_string(),
+            SessionType::ScreenCast,
+            "org.luminous.Test".to_string(),
+        );
+        assert!(append_session(closed.clone()).await);
+        let stopped = Arc::new(AtomicBool::new(false));
+        CAST_SESSIONS.lock().await.push((
+            path.to_string(),
+            ScreencastThread::idle(0, stopped.clone()),
+            StreamProperties::default(),
+        ));
+        let (_server, client) = serve(path, closed).await;
+        client
+            .call_method(
+                None::<&str>,
+                path,
+                Some("org.freedesktop.impl.portal.Session"),
+                "Close",
+                &(),
+            )
+            .await
+            .unwrap();
+        assert!(stopped.load(Ordering::Relaxed), "the cast is not stopped");
+        assert!(!CAST_SESSIONS.lock().await.iter().any(|cast| cast.0 == path));
+        assert!(
+            !SESSIONS
+                .read()
+                .await
+                .iter()
+                .any(|session| session.handle_path.as_str() == path)
+        );
+    }
+
+    #[tokio::test]
+    async fn the_client_is_told_the_session_is_closed() {
+        let path = "/org/freedesktop/portal/desktop/session/1_1/luminous_ended";
+        let ended = Session::new(
+            ObjectPath::from_static_str_unchecked(path),
+            "luminous_ended".to_string(),
+            SessionType::ScreenCast,
+            "org.luminous.Test".to_string(),
+        );
+        let (server, client) = serve(path, ended).await;
+        let mut messages = zbus::MessageStream::from(&client);
+        emit_closed(&server, path, "The cast of the session has ended").await;
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let message = messages
+                    .try_next()
+                    .await
+                    .unwrap()
+                    .expect("no more messages");
+                if message
+                    .header()
+                    .member()
+                    .is_some_and(|member| member == "Closed")
+                {
+                    return message;
+                }
+            }
+        })
+        .await
+        .expect("Closed is not emitted");
+        assert_eq!(closed.header().path().unwrap().as_str(), path);
+        let reason: String = closed.body().deserialize().unwrap();
+        assert_eq!(reason, "The cast of the session has ended");
+    }
+}
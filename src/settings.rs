@@ -2,7 +2,8 @@ mod config;
 use tokio::sync::Mutex;
 use zbus::{fdo, interface, object_server::SignalEmitter};
 
-use zbus::zvariant::{DeserializeDict, OwnedValue, SerializeDict, Type, Value};
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::{OwnedValue, Type, Value};
 
 const DEFAULT_COLOR: u32 = 0;
 const DARK_COLOR: u32 = 1;
@@ -16,12 +17,13 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::LazyLock;
 
-pub use self::config::SettingsConfig;
+pub use self::config::{HiddenRegion, SettingsConfig};
 
 pub static SETTING_CONFIG: LazyLock<Arc<Mutex<SettingsConfig>>> =
     LazyLock::new(|| Arc::new(Mutex::new(SettingsConfig::config_from_file())));
 
-#[derive(DeserializeDict, SerializeDict, Clone, Copy, PartialEq, Type, OwnedValue, Value)]
+/// Sent as `(ddd)`, each channel in `0..=1`.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Type, OwnedValue, Value)]
 pub struct AccentColor {
     red: f64,
     green: f64,
@@ -38,6 +40,18 @@ impl AccentColor {
     }
 }
 
+/// Whether `namespace` is asked for, no namespace asks for all of them, and a trailing `*`
+/// matches any suffix.
+fn namespaces_match(namespaces: &[&str], namespace: &str) -> bool {
+    namespaces.is_empty()
+        || namespaces
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => namespace.starts_with(prefix),
+                None => *pattern == namespace,
+            })
+}
+
 #[derive(Debug)]
 pub struct SettingsBackend;
 
@@ -68,8 +82,8 @@ impl SettingsBackend {
         &self,
         namespaces: Vec<&str>,
     ) -> fdo::Result<HashMap<String, HashMap<String, OwnedValue>>> {
-        if !namespaces.contains(&APPEARANCE) {
-            return Err(zbus::fdo::Error::Failed("No such namespace".to_string()));
+        if !namespaces_match(&namespaces, APPEARANCE) {
+            return Ok(HashMap::new());
         }
         let mut output_setting = HashMap::<String, OwnedValue>::new();
         let config = SETTING_CONFIG.lock().await;
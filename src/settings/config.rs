@@ -1,15 +1,104 @@
 use serde::Deserialize;
 use std::io::Read;
+
+use crate::pipewirethread::MAX_FRAMERATE;
+use crate::session::{CursorMode, PersistMode};
+
 const DEFAULT_COLOR_NAME: &str = "default";
 const DARK_COLOR_NAME: &str = "dark";
 const LIGHT_COLOR_NAME: &str = "light";
 
 const DEFAULT_ACCENT_COLLOR: &str = "#ffffff";
 
+const LOGICAL_SIZE_NAME: &str = "logical";
+const PHYSICAL_SIZE_NAME: &str = "physical";
+
+const BLACK_HIDE_STYLE_NAME: &str = "black";
+const BLUR_HIDE_STYLE_NAME: &str = "blur";
+
+const DRAG_PICKER_NAME: &str = "drag";
+const LIST_PICKER_NAME: &str = "list";
+
+const PERSIST_MODE_NAMES: [(&str, PersistMode); 3] = [
+    ("none", PersistMode::DoNot),
+    ("transient", PersistMode::Application),
+    ("persistent", PersistMode::ExplicitlyRevoked),
+];
+const DEFAULT_PERSIST_MODE_NAME: &str = "persistent";
+
+const DEFAULT_MAX_SESSIONS: usize = 64;
+
+const PIXEL_FORMAT_NAMES: [&str; 4] = ["BGRx", "BGRA", "RGBx", "RGBA"];
+
+const CURSOR_MODE_NAMES: [(&str, CursorMode); 3] = [
+    ("hidden", CursorMode::Hidden),
+    ("embedded", CursorMode::Embedded),
+    ("metadata", CursorMode::Metadata),
+];
+
+/// An area of an output that casts never show, in logical pixels relative to the output.
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct HiddenRegion {
+    /// The connector name of the output, like `DP-1`
+    pub output: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
 #[derive(Deserialize, PartialEq, Eq, Debug)]
+#[serde(default)]
 pub struct SettingsConfig {
     pub color_scheme: String,
     pub accent_color: String,
+    /// The cursor mode of the casts whose client does not choose one
+    pub default_cursor_mode: Option<String>,
+    /// Whether casts report their size in `logical` pixels, like the spec wants, or in the
+    /// `physical` pixels of their frames. Frames are always captured in physical pixels.
+    pub stream_size: String,
+    /// Whether screens are picked with a `drag` over them, or from a `list` of the outputs
+    pub picker: String,
+    /// Send no frame while the screen does not change, a consumer still gets one when it
+    /// connects
+    pub skip_unchanged_frames: bool,
+    /// A command that lets the user pick an area and prints it as `x,y wxh`, like `slurp`,
+    /// used instead of the built-in picker
+    pub region_picker: Option<String>,
+    /// The connector name of the output cast by headless starts, the primary output if unset
+    pub headless_output: Option<String>,
+    /// A command that draws the image whose path is put last as the background and keeps
+    /// running, like `swaybg -m fill -i`, for the wallpaper portal
+    pub wallpaper_command: Option<String>,
+    /// Close the sessions not started, or with no consumer connected to their casts, after
+    /// that many seconds. Sessions are never closed for being idle if unset.
+    pub idle_timeout: Option<u64>,
+    /// The size of the cursor sent as metadata, in percent of the size the compositor draws
+    /// it at. The compositor paints an embedded cursor itself, at its own size.
+    pub cursor_scale: Option<u32>,
+    /// The longest casts may be remembered: `none`, `transient` while the app runs, or
+    /// `persistent` until revoked. Apps asking for longer get this.
+    pub max_persist_mode: String,
+    /// Areas filled in every frame of the casts of their output, or of the desktop, before
+    /// they are sent, like a panel showing notifications
+    pub hidden_regions: Vec<HiddenRegion>,
+    /// Whether the hidden regions are filled with `black`, or `blur` to blur them instead
+    pub hide_style: String,
+    /// Show a desktop notification naming the app and what it casts once a cast starts
+    pub notify_on_cast: bool,
+    /// How many sessions may be open at once, of all the apps together
+    pub max_sessions: usize,
+    /// The frames per second of the casts whose client does not choose, in
+    /// `1..=MAX_FRAMERATE`. The refresh rate of the output is used if unset.
+    ///
+    /// The `framerate` option of a start wins over it, and it wins over the refresh rate.
+    pub framerate: Option<u32>,
+    /// The format offered first to consumers, `BGRx`, `BGRA`, `RGBx` or `RGBA`, when the frames
+    /// can be sent in it. The format the frames are copied in comes first if unset.
+    ///
+    /// The format a consumer picks among those offered wins over it. There is no key to turn
+    /// DMA-BUF on or off, casts only share memory until they can hand out dmabufs.
+    pub pixel_format: Option<String>,
 }
 
 impl SettingsConfig {
@@ -18,7 +107,7 @@ impl SettingsConfig {
             DEFAULT_COLOR_NAME => super::DEFAULT_COLOR,
             DARK_COLOR_NAME => super::DARK_COLOR,
             LIGHT_COLOR_NAME => super::LIGHT_COLOR,
-            _ => unreachable!(),
+            _ => super::DEFAULT_COLOR,
         }
     }
     pub fn get_accent_color(&self) -> [f64; 3] {
@@ -30,11 +119,51 @@ impl SettingsConfig {
                     .to_rgba8(),
             );
         [
-            color[0] as f64 / 256.0,
-            color[1] as f64 / 256.0,
-            color[2] as f64 / 256.0,
+            color[0] as f64 / 255.0,
+            color[1] as f64 / 255.0,
+            color[2] as f64 / 255.0,
         ]
     }
+    pub fn reports_physical_size(&self) -> bool {
+        self.stream_size == PHYSICAL_SIZE_NAME
+    }
+    pub fn uses_list_picker(&self) -> bool {
+        self.picker == LIST_PICKER_NAME
+    }
+    /// The [`SettingsConfig::cursor_scale`] as a factor, 1 if unset.
+    pub fn get_cursor_scale(&self) -> f64 {
+        self.cursor_scale
+            .filter(|percent| *percent != 0)
+            .map_or(1., |percent| percent as f64 / 100.)
+    }
+    /// The [`SettingsConfig::max_persist_mode`], persistent if it is unknown.
+    pub fn get_max_persist_mode(&self) -> PersistMode {
+        PERSIST_MODE_NAMES
+            .iter()
+            .find(|(mode_name, _)| *mode_name == self.max_persist_mode)
+            .map_or(PersistMode::ExplicitlyRevoked, |(_, mode)| *mode)
+    }
+    /// The [`SettingsConfig::framerate`], clamped to the framerates casts can have.
+    pub fn get_framerate(&self) -> Option<u32> {
+        self.framerate
+            .map(|framerate| framerate.clamp(1, MAX_FRAMERATE))
+    }
+    /// The [`SettingsConfig::pixel_format`], if it is a known one.
+    pub fn get_pixel_format(&self) -> Option<&str> {
+        self.pixel_format
+            .as_deref()
+            .filter(|name| PIXEL_FORMAT_NAMES.contains(name))
+    }
+    pub fn blurs_hidden_regions(&self) -> bool {
+        self.hide_style == BLUR_HIDE_STYLE_NAME
+    }
+    pub fn get_default_cursor_mode(&self) -> Option<CursorMode> {
+        let name = self.default_cursor_mode.as_deref()?;
+        CURSOR_MODE_NAMES
+            .iter()
+            .find(|(mode_name, _)| *mode_name == name)
+            .map(|(_, mode)| *mode)
+    }
 }
 
 impl Default for SettingsConfig {
@@ -42,6 +171,22 @@ impl Default for SettingsConfig {
         SettingsConfig {
             color_scheme: DEFAULT_COLOR_NAME.to_string(),
             accent_color: DEFAULT_ACCENT_COLLOR.to_string(),
+            default_cursor_mode: None,
+            stream_size: LOGICAL_SIZE_NAME.to_string(),
+            picker: DRAG_PICKER_NAME.to_string(),
+            skip_unchanged_frames: false,
+            region_picker: None,
+            headless_output: None,
+            wallpaper_command: None,
+            idle_timeout: None,
+            cursor_scale: None,
+            max_persist_mode: DEFAULT_PERSIST_MODE_NAME.to_string(),
+            hidden_regions: Vec::new(),
+            hide_style: BLACK_HIDE_STYLE_NAME.to_string(),
+            notify_on_cast: true,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            framerate: None,
+            pixel_format: None,
         }
     }
 }
@@ -62,6 +207,77 @@ impl SettingsConfig {
         if file.read_to_string(&mut buf).is_err() {
             return Self::default();
         };
-        toml::from_str(&buf).unwrap_or(Self::default())
+        let config: Self = match toml::from_str(&buf) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::warn!("cannot parse the config, error: {e}");
+                return Self::default();
+            }
+        };
+        if ![DEFAULT_COLOR_NAME, DARK_COLOR_NAME, LIGHT_COLOR_NAME]
+            .contains(&config.color_scheme.as_str())
+        {
+            tracing::warn!(
+                "unknown color_scheme {}, it must be {DEFAULT_COLOR_NAME}, {DARK_COLOR_NAME} or \
+                 {LIGHT_COLOR_NAME}",
+                config.color_scheme
+            );
+        }
+        if csscolorparser::parse(&config.accent_color).is_err() {
+            tracing::warn!(
+                "cannot parse the accent_color {}, {DEFAULT_ACCENT_COLLOR} is used instead",
+                config.accent_color
+            );
+        }
+        if config.default_cursor_mode.is_some() && config.get_default_cursor_mode().is_none() {
+            tracing::warn!(
+                "unknown default_cursor_mode {:?}, it must be hidden, embedded or metadata",
+                config.default_cursor_mode
+            );
+        }
+        if !PERSIST_MODE_NAMES
+            .iter()
+            .any(|(mode_name, _)| *mode_name == config.max_persist_mode)
+        {
+            tracing::warn!(
+                "unknown max_persist_mode {}, it must be none, transient or persistent",
+                config.max_persist_mode
+            );
+        }
+        if ![LOGICAL_SIZE_NAME, PHYSICAL_SIZE_NAME].contains(&config.stream_size.as_str()) {
+            tracing::warn!(
+                "unknown stream_size {}, it must be {LOGICAL_SIZE_NAME} or {PHYSICAL_SIZE_NAME}",
+                config.stream_size
+            );
+        }
+        if let Some(framerate) = config.framerate
+            && !(1..=MAX_FRAMERATE).contains(&framerate)
+        {
+            tracing::warn!(
+                "the framerate {framerate} is out of 1..={MAX_FRAMERATE}, {} is used instead",
+                framerate.clamp(1, MAX_FRAMERATE)
+            );
+        }
+        if config.pixel_format.is_some() && config.get_pixel_format().is_none() {
+            tracing::warn!(
+                "unknown pixel_format {:?}, it must be one of {}",
+                config.pixel_format,
+                PIXEL_FORMAT_NAMES.join(", ")
+            );
+        }
+        if ![BLACK_HIDE_STYLE_NAME, BLUR_HIDE_STYLE_NAME].contains(&config.hide_style.as_str()) {
+            tracing::warn!(
+                "unknown hide_style {}, it must be {BLACK_HIDE_STYLE_NAME} or \
+                 {BLUR_HIDE_STYLE_NAME}",
+                config.hide_style
+            );
+        }
+        if ![DRAG_PICKER_NAME, LIST_PICKER_NAME].contains(&config.picker.as_str()) {
+            tracing::warn!(
+                "unknown picker {}, it must be {DRAG_PICKER_NAME} or {LIST_PICKER_NAME}",
+                config.picker
+            );
+        }
+        config
     }
 }
@@ -0,0 +1,289 @@
+//! What the tests share: outputs and casts without a compositor or PipeWire, a D-Bus
+//! connection without a bus, a lock on the global state, and a count of the allocations.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use libwayshot::output::{OutputInfo, OutputPositioning, WlOutputMode};
+use wayland_client::Proxy;
+use wayland_client::protocol::wl_output::{Transform, WlOutput};
+use zbus::object_server::Interface;
+use zbus::zvariant::OwnedObjectPath;
+
+use crate::pipewirethread::ScreencastThread;
+use crate::remotedesktop::REMOTE_SESSIONS;
+use crate::restore::{LAST_SELECTIONS, RESTORE_TOKENS, RestoreSource};
+use crate::screencast::{
+    CAST_SESSIONS, CastBackend, PickerOptions, SelectedSource, restore_source,
+};
+use crate::session::{CursorMode, SESSIONS, SourceType};
+use crate::settings::{SETTING_CONFIG, SettingsConfig};
+use crate::utils::CaptureBackend;
+
+/// The system allocator, counting the allocations of each thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Not counted once the thread is torn down
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// How many times the current thread allocated or grew memory so far, the other tests running
+/// meanwhile do not count.
+pub fn allocations() -> u64 {
+    ALLOCATIONS.with(Cell::get)
+}
+
+/// Held by the tests that use the sessions, the casts, the restore tokens or the config, which
+/// are global, so that they do not run into each other.
+static GLOBALS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Take the global state for a test, emptied, with the config at its defaults except that no
+/// notification is sent.
+pub async fn lock_globals() -> tokio::sync::MutexGuard<'static, ()> {
+    let guard = GLOBALS.lock().await;
+    *SETTING_CONFIG.lock().await = SettingsConfig {
+        notify_on_cast: false,
+        ..Default::default()
+    };
+    SESSIONS.write().await.clear();
+    CAST_SESSIONS.lock().await.clear();
+    REMOTE_SESSIONS.lock().await.clear();
+    RESTORE_TOKENS.lock().await.clear();
+    LAST_SELECTIONS.lock().unwrap().clear();
+    guard
+}
+
+/// A Wayland connection no compositor listens at, the fake outputs are objects of it. Both
+/// ends stay open for as long as the tests run.
+static DEAD_WAYLAND: LazyLock<(wayland_client::Connection, UnixStream)> = LazyLock::new(|| {
+    let (client, server) = UnixStream::pair().expect("cannot make a socket pair");
+    let connection =
+        wayland_client::Connection::from_socket(client).expect("cannot use the socket pair");
+    (connection, server)
+});
+
+/// Canned outputs standing in for a compositor.
+pub struct FakeOutputs(Vec<OutputInfo>);
+
+impl FakeOutputs {
+    /// The `outputs` as `(name, x, y, width, height)`, in logical pixels, each in a mode of
+    /// twice its logical size.
+    pub fn new(outputs: &[(&str, i32, i32, i32, i32)]) -> Self {
+        let backend = DEAD_WAYLAND.0.backend().downgrade();
+        Self(
+            outputs
+                .iter()
+                .map(|&(name, x, y, width, height)| OutputInfo {
+                    wl_output: WlOutput::inert(backend.clone()),
+                    name: name.to_string(),
+                    description: format!("fake output {name}"),
+                    transform: Transform::Normal,
+                    dimensions: OutputPositioning {
+                        x,
+                        y,
+                        width,
+                        height,
+                    },
+                    mode: WlOutputMode {
+                        width: width * 2,
+                        height: height * 2,
+                    },
+                })
+                .collect(),
+        )
+    }
+}
+
+impl CaptureBackend for FakeOutputs {
+    fn outputs(&self) -> &[OutputInfo] {
+        &self.0
+    }
+}
+
+/// Serve `iface` at `path` on a peer to peer connection, and return it along with the client
+/// end calling it.
+pub async fn serve<I: Interface>(path: &str, iface: I) -> (zbus::Connection, zbus::Connection) {
+    let (server, client) = tokio::net::UnixStream::pair().expect("cannot make a socket pair");
+    let server = zbus::connection::Builder::unix_stream(server)
+        .server(zbus::Guid::generate())
+        .and_then(|builder| builder.p2p().serve_at(path, iface))
+        .expect("cannot serve the interface")
+        .build();
+    let client = zbus::connection::Builder::unix_stream(client).p2p().build();
+    tokio::try_join!(server, client).expect("cannot connect the peers")
+}
+
+/// The node id of the first fake cast, the next ones count up from it.
+const FIRST_NODE_ID: u32 = 100;
+
+/// Canned outputs and picks standing in for the compositor, and idle threads for the casts.
+///
+/// Clones share the picks and the casts, so a test keeps one to check what the portal did.
+#[derive(Clone)]
+pub struct FakeCasts(Arc<FakeCastsState>);
+
+struct FakeCastsState {
+    outputs: Vec<(&'static str, i32, i32, i32, i32)>,
+    /// What each pick gives in turn, `None` for a request closed meanwhile
+    picks: Mutex<VecDeque<Option<Vec<&'static str>>>>,
+    /// What the user answers in turn when asked to cast the last selection again
+    answers: Mutex<VecDeque<bool>>,
+    /// Fail every cast, like without PipeWire
+    failing: AtomicBool,
+    next_node_id: AtomicU32,
+    /// Whether each cast started is stopped, and its cursor mode, in the order they were started
+    casts: Mutex<Vec<(Arc<AtomicBool>, CursorMode)>>,
+}
+
+impl FakeCasts {
+    /// The `outputs` as [`FakeOutputs::new`] takes them, with no pick to give yet.
+    pub fn new(outputs: &[(&'static str, i32, i32, i32, i32)]) -> Self {
+        Self(Arc::new(FakeCastsState {
+            outputs: outputs.to_vec(),
+            picks: Mutex::default(),
+            answers: Mutex::default(),
+            failing: AtomicBool::new(false),
+            next_node_id: AtomicU32::new(FIRST_NODE_ID),
+            casts: Mutex::default(),
+        }))
+    }
+
+    /// Have the next pick give the whole outputs called `names`, or `None` for the request
+    /// being closed meanwhile. A pick nothing is canned for fails the test.
+    pub fn then_pick(&self, names: Option<&[&'static str]>) -> &Self {
+        self.0
+            .picks
+            .lock()
+            .unwrap()
+            .push_back(names.map(<[&str]>::to_vec));
+        self
+    }
+
+    /// Have the user answer `answer` when next asked to cast the last selection again. Asking
+    /// with nothing canned fails the test.
+    pub fn then_answer(&self, answer: bool) -> &Self {
+        self.0.answers.lock().unwrap().push_back(answer);
+        self
+    }
+
+    /// Whether the picks and the answers canned are all given.
+    pub fn picked_all(&self) -> bool {
+        self.0.picks.lock().unwrap().is_empty() && self.0.answers.lock().unwrap().is_empty()
+    }
+
+    /// Fail the casts started from now on.
+    pub fn fail_casts(&self) {
+        self.0.failing.store(true, Ordering::Relaxed);
+    }
+
+    /// How many casts were started.
+    pub fn started(&self) -> usize {
+        self.0.casts.lock().unwrap().len()
+    }
+
+    /// How many of the casts started are stopped.
+    pub fn stopped(&self) -> usize {
+        let casts = self.0.casts.lock().unwrap();
+        casts
+            .iter()
+            .filter(|(stopped, _)| stopped.load(Ordering::Relaxed))
+            .count()
+    }
+
+    /// The cursor mode of each cast started, in the order they were started.
+    pub fn cursor_modes(&self) -> Vec<CursorMode> {
+        let casts = self.0.casts.lock().unwrap();
+        casts.iter().map(|&(_, cursor_mode)| cursor_mode).collect()
+    }
+}
+
+impl CastBackend for FakeCasts {
+    type Outputs = FakeOutputs;
+
+    fn connect(&self) -> zbus::fdo::Result<FakeOutputs> {
+        Ok(FakeOutputs::new(&self.0.outputs))
+    }
+
+    fn output_under_cursor(&self, outputs: &FakeOutputs) -> anyhow::Result<Option<String>> {
+        Ok(outputs.outputs().first().map(|output| output.name.clone()))
+    }
+
+    async fn pick(
+        &self,
+        _request_path: OwnedObjectPath,
+        _options: PickerOptions,
+    ) -> zbus::fdo::Result<Option<Vec<SelectedSource>>> {
+        let pick = self.0.picks.lock().unwrap().pop_front();
+        let Some(names) = pick.expect("the picker is shown, nothing is canned for it") else {
+            return Ok(None);
+        };
+        let outputs = FakeOutputs::new(&self.0.outputs);
+        let sources = names
+            .iter()
+            .map(|name| {
+                let restore = RestoreSource {
+                    output_name: name.to_string(),
+                    source_type: SourceType::Monitor,
+                    region: None,
+                };
+                restore_source(&outputs, &restore).expect("no fake output has the name picked")
+            })
+            .collect();
+        Ok(Some(sources))
+    }
+
+    async fn confirm_last_selection(&self, _app_id: String, _sources: Vec<SelectedSource>) -> bool {
+        let answer = self.0.answers.lock().unwrap().pop_front();
+        answer.expect("the last selection is asked about, nothing is canned for it")
+    }
+
+    async fn start_cast(
+        &self,
+        _source: &SelectedSource,
+        cursor_mode: CursorMode,
+        _framerate: Option<u32>,
+        _max_size: (Option<u32>, Option<u32>),
+        _on_ended: impl FnOnce(u32) + Send + 'static,
+    ) -> Result<ScreencastThread, String> {
+        // Like connecting to PipeWire, so that concurrent starts interleave here
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if self.0.failing.load(Ordering::Relaxed) {
+            return Err("cannot start pipewire stream, error: PipeWire not available".to_string());
+        }
+        let node_id = self.0.next_node_id.fetch_add(1, Ordering::Relaxed);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let cast = (stopped.clone(), cursor_mode);
+        self.0.casts.lock().unwrap().push(cast);
+        Ok(ScreencastThread::idle(node_id, stopped))
+    }
+}
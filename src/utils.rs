@@ -1,8 +1,211 @@
 use std::path::PathBuf;
 
-use std::sync::LazyLock;
+use std::sync::{LazyLock, Mutex};
+
+use libwayshot::CaptureRegion;
+use libwayshot::output::OutputInfo;
+
+use crate::settings::SETTING_CONFIG;
+
+/// Names a command to pick areas with instead of the built-in picker, it wins over the
+/// `region_picker` of the config.
+pub const REGION_PICKER_ENV: &str = "LUMINOUS_REGION_PICKER";
 
 pub static USER_RUNNING_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     let cache_dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or("/tmp".to_string());
     PathBuf::from(cache_dir)
 });
+
+pub static USER_STATE_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    if let Ok(state_dir) = std::env::var("XDG_STATE_HOME") {
+        return PathBuf::from(state_dir);
+    }
+    let home = std::env::var("HOME").unwrap_or("/tmp".to_string());
+    PathBuf::from(home).join(".local").join("state")
+});
+
+/// The connection to the compositor, shared by the requests and the casts so that each of
+/// them does not open a socket of its own. It is opened again once it fails.
+static WAYLAND_CONNECTION: LazyLock<Mutex<Option<wayland_client::Connection>>> =
+    LazyLock::new(|| Mutex::new(None));
+
+/// Connect to the compositor, failing only the request at hand if that is not possible.
+///
+/// The connection is shared, but the globals and outputs are fetched again each time, so an
+/// output plugged in meanwhile is known. That takes roundtrips, so the casts starting at once
+/// call this off the executor, and the lock is not held meanwhile so they do not wait on each
+/// other.
+pub fn connect_wayshot() -> zbus::fdo::Result<libwayshot::WayshotConnection> {
+    let cached = WAYLAND_CONNECTION
+        .lock()
+        .unwrap()
+        .clone()
+        .filter(|conn| conn.protocol_error().is_none() && conn.flush().is_ok());
+    if let Some(conn) = cached {
+        match libwayshot::WayshotConnection::from_connection(conn) {
+            Ok(connection) => return Ok(connection),
+            Err(e) => tracing::warn!("the connection to the compositor failed, open it again: {e}"),
+        }
+    }
+    // Two calls may open one each if it failed, the casts on the other keep theirs open
+    let connection = libwayshot::WayshotConnection::new().map_err(connection_failure)?;
+    *WAYLAND_CONNECTION.lock().unwrap() = Some(connection.conn.clone());
+    Ok(connection)
+}
+
+/// Open a connection of its own for a picker, which is taken down by shutting its socket
+/// down, see [`crate::request::append_picker`]. Doing so to the shared connection would end
+/// every cast along with it.
+pub fn connect_picker() -> zbus::fdo::Result<libwayshot::WayshotConnection> {
+    libwayshot::WayshotConnection::new().map_err(connection_failure)
+}
+
+fn connection_failure(e: impl std::fmt::Display) -> zbus::fdo::Error {
+    let display = std::env::var("WAYLAND_DISPLAY").unwrap_or("unset".to_string());
+    let desktop = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or("unknown".to_string());
+    tracing::error!("cannot connect to the compositor {desktop} on {display}, error: {e}");
+    zbus::Error::Failure(format!(
+        "Cannot connect to the compositor {desktop} (WAYLAND_DISPLAY={display}), it must be a \
+         wlroots based compositor, error: {e}"
+    ))
+    .into()
+}
+
+/// What the outputs are known from, [`libwayshot::WayshotConnection`] for the compositor.
+///
+/// The logic that only looks at the output layout takes it, so a fake list of outputs can
+/// stand in for a compositor.
+pub trait CaptureBackend {
+    fn outputs(&self) -> &[OutputInfo];
+}
+
+impl CaptureBackend for libwayshot::WayshotConnection {
+    fn outputs(&self) -> &[OutputInfo] {
+        self.get_all_outputs()
+    }
+}
+
+/// The box around all the outputs, as `(x, y, width, height)` in logical pixels.
+pub fn output_layout(backend: &impl CaptureBackend) -> (i32, i32, u32, u32) {
+    let outputs = backend.outputs();
+    let left = outputs.iter().map(|output| output.dimensions.x).min();
+    let top = outputs.iter().map(|output| output.dimensions.y).min();
+    let right = outputs
+        .iter()
+        .map(|output| output.dimensions.x + output.dimensions.width)
+        .max();
+    let bottom = outputs
+        .iter()
+        .map(|output| output.dimensions.y + output.dimensions.height)
+        .max();
+    let (Some(left), Some(top), Some(right), Some(bottom)) = (left, top, right, bottom) else {
+        return (0, 0, 0, 0);
+    };
+    (left, top, (right - left) as u32, (bottom - top) as u32)
+}
+
+/// Capture `region`, in logical coordinates, piecing together the part of each output it
+/// spans. Whatever is not on any output stays transparent.
+pub fn screenshot_area(
+    connection: &libwayshot::WayshotConnection,
+    region: libwayshot::CaptureRegion,
+    show_cursor: bool,
+) -> anyhow::Result<image::RgbaImage> {
+    let mut image = image::RgbaImage::new(region.width as u32, region.height as u32);
+    for output in connection.get_all_outputs() {
+        let dimensions = &output.dimensions;
+        let left = region.x_coordinate.max(dimensions.x);
+        let top = region.y_coordinate.max(dimensions.y);
+        let right = (region.x_coordinate + region.width).min(dimensions.x + dimensions.width);
+        let bottom = (region.y_coordinate + region.height).min(dimensions.y + dimensions.height);
+        if right <= left || bottom <= top {
+            continue;
+        }
+        let part = connection.screenshot(
+            libwayshot::CaptureRegion {
+                x_coordinate: left,
+                y_coordinate: top,
+                width: right - left,
+                height: bottom - top,
+            },
+            show_cursor,
+        )?;
+        image::imageops::overlay(
+            &mut image,
+            &part,
+            (left - region.x_coordinate) as i64,
+            (top - region.y_coordinate) as i64,
+        );
+    }
+    Ok(image)
+}
+
+/// The command areas are picked with, if the built-in picker is not to be used.
+pub async fn region_picker() -> Option<String> {
+    if let Some(command) = std::env::var(REGION_PICKER_ENV)
+        .ok()
+        .filter(|command| !command.trim().is_empty())
+    {
+        return Some(command);
+    }
+    SETTING_CONFIG.lock().await.region_picker.clone()
+}
+
+/// Let the user pick an area with `command`, which prints it as `x,y wxh` in logical
+/// coordinates, like `slurp` does. Exiting with a failure means the user cancelled.
+///
+/// Returns `None` when the command is not found, so the built-in picker can be used instead.
+pub fn pick_area_with_command(command: &str) -> Option<anyhow::Result<Option<CaptureRegion>>> {
+    let mut words = command.split_whitespace();
+    let program = words.next()?;
+    let output = match std::process::Command::new(program).args(words).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::warn!("cannot find the region picker {program}, use the built-in one");
+            return None;
+        }
+        Err(e) => return Some(Err(e.into())),
+    };
+    if !output.status.success() {
+        return Some(Ok(None));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Some(
+        parse_area(stdout.trim())
+            .map(Some)
+            .ok_or_else(|| anyhow::anyhow!("cannot read the area {stdout:?} picked by {program}")),
+    )
+}
+
+/// Read an area written as `x,y wxh`.
+fn parse_area(area: &str) -> Option<CaptureRegion> {
+    let (position, size) = area.split_once(' ')?;
+    let (x, y) = position.split_once(',')?;
+    let (width, height) = size.split_once('x')?;
+    Some(CaptureRegion {
+        x_coordinate: x.trim().parse().ok()?,
+        y_coordinate: y.trim().parse().ok()?,
+        width: width.trim().parse().ok()?,
+        height: height.trim().parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_failure_fails_the_request() {
+        let zbus::fdo::Error::ZBus(zbus::Error::Failure(message)) =
+            connection_failure("Connection refused")
+        else {
+            panic!("the failure is not told to the frontend");
+        };
+        let display = std::env::var("WAYLAND_DISPLAY").unwrap_or("unset".to_string());
+        assert!(
+            message.contains(&format!("(WAYLAND_DISPLAY={display})")),
+            "{message}"
+        );
+        assert!(message.ends_with("error: Connection refused"), "{message}");
+    }
+}
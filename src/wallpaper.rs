@@ -0,0 +1,160 @@
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::{LazyLock, Mutex};
+
+use zbus::zvariant::{DeserializeDict, ObjectPath, SerializeDict, Type};
+use zbus::{fdo, interface};
+
+use crate::settings::SETTING_CONFIG;
+use crate::{PORTAL_RESPONSE_CANCELLED, PORTAL_RESPONSE_SUCCESS};
+
+#[derive(DeserializeDict, SerializeDict, Type, Debug, Default)]
+#[zvariant(signature = "dict")]
+pub struct WallpaperOptions {
+    /// Whether the user is shown the image and asked before it is set
+    #[zvariant(rename = "show-preview")]
+    show_preview: Option<bool>,
+    /// `background`, `lockscreen` or `both`, the default
+    #[zvariant(rename = "set-on")]
+    set_on: Option<String>,
+}
+
+/// Where the wallpaper goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WallpaperTarget {
+    Background,
+    Lockscreen,
+    Both,
+}
+
+impl WallpaperTarget {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "background" => Some(Self::Background),
+            "lockscreen" => Some(Self::Lockscreen),
+            "both" => Some(Self::Both),
+            _ => None,
+        }
+    }
+}
+
+/// How the wallpaper is set, wlroots compositors have no common way to do it.
+pub trait WallpaperSetter {
+    fn set_background(&self, path: &Path) -> anyhow::Result<()>;
+
+    /// Whether [`WallpaperSetter::set_lockscreen`] can be called.
+    fn supports_lockscreen(&self) -> bool {
+        false
+    }
+
+    fn set_lockscreen(&self, _path: &Path) -> anyhow::Result<()> {
+        anyhow::bail!("the lockscreen cannot be given a wallpaper")
+    }
+}
+
+/// The process drawing the background set last, stopped once another one is set.
+static BACKGROUND: LazyLock<Mutex<Option<Child>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Sets the background with the `wallpaper_command` of the config, like `swaybg -m fill -i`,
+/// which is given the path of the image last and kept running.
+pub struct CommandWallpaper {
+    command: String,
+}
+
+impl WallpaperSetter for CommandWallpaper {
+    fn set_background(&self, path: &Path) -> anyhow::Result<()> {
+        let mut words = self.command.split_whitespace();
+        let program = words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("the wallpaper_command is empty"))?;
+        let child = Command::new(program).args(words).arg(path).spawn()?;
+        // Stopped only now, so there is no moment without a background
+        if let Some(mut previous) = BACKGROUND.lock().unwrap().replace(child) {
+            let _ = previous.kill();
+            let _ = previous.wait();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct WallpaperBackend;
+
+#[interface(name = "org.freedesktop.impl.portal.Wallpaper")]
+impl WallpaperBackend {
+    #[zbus(property, name = "version")]
+    fn version(&self) -> u32 {
+        1
+    }
+
+    #[zbus(name = "SetWallpaperURI")]
+    async fn set_wallpaper_uri(
+        &self,
+        _handle: ObjectPath<'_>,
+        app_id: String,
+        _parent_window: String,
+        uri: String,
+        options: WallpaperOptions,
+    ) -> fdo::Result<u32> {
+        tracing::info!("{app_id} sets the wallpaper to {uri}");
+        let set_on = options.set_on.as_deref().unwrap_or("both");
+        let Some(target) = WallpaperTarget::from_name(set_on) else {
+            return Err(zbus::Error::Failure(format!(
+                "Unknown set-on {set_on}, it must be background, lockscreen or both"
+            ))
+            .into());
+        };
+        let path = url::Url::parse(&uri)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| {
+                zbus::Error::Failure(format!("{uri} is not a local file, it cannot be set"))
+            })?;
+        let Some(command) = SETTING_CONFIG.lock().await.wallpaper_command.clone() else {
+            tracing::warn!("no wallpaper_command is configured, the wallpaper cannot be set");
+            return Err(zbus::Error::Failure(
+                "Setting the wallpaper is not supported without a wallpaper_command".to_string(),
+            )
+            .into());
+        };
+        let setter = CommandWallpaper { command };
+        // Checked before the user is asked, who would confirm for nothing otherwise
+        if target == WallpaperTarget::Lockscreen && !setter.supports_lockscreen() {
+            return Err(zbus::Error::Failure(
+                "Setting the lockscreen wallpaper is not supported, set-on background only"
+                    .to_string(),
+            )
+            .into());
+        }
+        if options.show_preview.unwrap_or(false) {
+            let title = "Set the wallpaper".to_string();
+            let information = format!("{app_id} wants to set {} as the wallpaper", path.display());
+            let confirmed =
+                tokio::task::spawn_blocking(move || accessdialog::confirmgui(title, information))
+                    .await
+                    .unwrap_or_default();
+            if !confirmed {
+                return Ok(PORTAL_RESPONSE_CANCELLED);
+            }
+        }
+        let result = match target {
+            WallpaperTarget::Background => setter.set_background(&path),
+            WallpaperTarget::Lockscreen => setter.set_lockscreen(&path),
+            // Both is what clients ask for by default, so the background alone will do
+            WallpaperTarget::Both if !setter.supports_lockscreen() => {
+                tracing::info!(
+                    "the lockscreen cannot be given a wallpaper, only set the background"
+                );
+                setter.set_background(&path)
+            }
+            WallpaperTarget::Both => setter
+                .set_background(&path)
+                .and_then(|()| setter.set_lockscreen(&path)),
+        };
+        result.map_err(|e| {
+            tracing::error!("cannot set the wallpaper to {}: {e}", path.display());
+            zbus::Error::Failure(format!("Cannot set the wallpaper, error: {e}"))
+        })?;
+        Ok(PORTAL_RESPONSE_SUCCESS)
+    }
+}